@@ -0,0 +1,131 @@
+//! JPEG helpers for what image's built-in codec doesn't cover: writing progressive JPEGs
+//! with a choice of chroma subsampling (through the `jpeg-encoder` crate, behind the
+//! feature of the same name), reading the headers that identify CMYK sources, and
+//! decoding CMYK that isn't stored the Adobe way.
+
+use std::io::{self, Read};
+#[cfg(feature = "jpeg-encoder")]
+use std::io::Write;
+
+use image::RgbImage;
+use zune_jpeg::errors::DecodeErrors;
+use zune_jpeg::zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+use zune_jpeg::JpegDecoder;
+
+/// How much the Cb and Cr planes are downsampled relative to luma
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// Full-resolution chroma
+    Yuv444,
+    /// Chroma halved horizontally
+    Yuv422,
+    /// Chroma halved in both directions, what most encoders and decoders expect
+    #[default]
+    Yuv420,
+}
+
+#[cfg(feature = "jpeg-encoder")]
+impl ChromaSubsampling {
+    fn sampling_factor(self) -> jpeg_encoder::SamplingFactor {
+        match self {
+            ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Encode `img` as a JFIF JPEG with `quality` in 1..=100. Progressive output starts with
+/// a DC-only scan of every component and refines the AC coefficients in later scans.
+/// `img` must be at most 65535 pixels on each side
+#[cfg(feature = "jpeg-encoder")]
+pub(crate) fn encode<W: Write>(
+    img: &RgbImage,
+    quality: u8,
+    progressive: bool,
+    subsampling: ChromaSubsampling,
+    mut out: W,
+) -> Result<(), jpeg_encoder::EncodingError> {
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality);
+    encoder.set_sampling_factor(subsampling.sampling_factor());
+    encoder.set_progressive(progressive);
+    encoder.encode(img.as_raw(), img.width() as u16, img.height() as u16, jpeg_encoder::ColorType::Rgb)?;
+    Ok(out.flush()?)
+}
+
+/// How a four-component JPEG stores its inks, going by the Adobe APP14 marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CmykEncoding {
+    /// Adobe marker with no color transform: CMYK with every sample inverted
+    /// (0 = full ink), as Photoshop writes it
+    AdobeInverted,
+    /// Adobe marker with transform 2: inverted CMY stored as YCbCr, plus inverted K
+    Ycck,
+    /// No Adobe marker: CMYK samples stored as-is (255 = full ink)
+    Plain,
+}
+
+/// How the frame of a JPEG stream encodes CMYK, or `None` if it has fewer than four
+/// components. Reads only the headers; non-JPEG input gives `Ok(None)`
+pub(crate) fn cmyk_encoding<R: Read>(mut reader: R) -> io::Result<Option<CmykEncoding>> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    if bytes != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+    let mut adobe_transform = None;
+    loop {
+        reader.read_exact(&mut bytes[..1])?;
+        if bytes[0] != 0xFF {
+            return Ok(None);
+        }
+        let mut marker = 0xFF;
+        while marker == 0xFF {
+            reader.read_exact(&mut bytes[..1])?;
+            marker = bytes[0];
+        }
+        match marker {
+            // Start of scan or end of image: no frame header seen
+            0xDA | 0xD9 => return Ok(None),
+            // Standalone markers without a length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        reader.read_exact(&mut bytes)?;
+        let length = u16::from_be_bytes(bytes).saturating_sub(2) as usize;
+        let mut segment = vec![0u8; length];
+        reader.read_exact(&mut segment)?;
+        // APP14: "Adobe", version (2), flags0 (2), flags1 (2), color transform (1)
+        if marker == 0xEE && segment.starts_with(b"Adobe") {
+            adobe_transform = segment.get(11).copied();
+        }
+        let is_frame = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+        if is_frame {
+            // precision (1), height (2), width (2), component count (1)
+            if segment.get(5) != Some(&4) {
+                return Ok(None);
+            }
+            return Ok(Some(match adobe_transform {
+                Some(2) => CmykEncoding::Ycck,
+                Some(_) => CmykEncoding::AdobeInverted,
+                None => CmykEncoding::Plain,
+            }));
+        }
+    }
+}
+
+/// Decode a `CmykEncoding::Plain` JPEG to RGB. Decoders (including image's) assume the
+/// Adobe convention for every CMYK JPEG, which turns uninverted inks into a negative, so
+/// this reads the raw samples and converts them with R = (255 - C) * (255 - K) / 255
+pub(crate) fn decode_plain_cmyk(data: &[u8]) -> Result<RgbImage, DecodeErrors> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    let samples = decoder.decode()?;
+    let (width, height) = decoder
+        .dimensions()
+        .ok_or(DecodeErrors::FormatStatic("JPEG headers were not decoded"))?;
+    let ink = |value: u8, k: u8| (((255 - value as u32) * (255 - k as u32) + 127) / 255) as u8;
+    let pixels = samples.chunks_exact(4).flat_map(|cmyk| [0, 1, 2].map(|c| ink(cmyk[c], cmyk[3]))).collect();
+    RgbImage::from_raw(width as u32, height as u32, pixels)
+        .ok_or(DecodeErrors::FormatStatic("Decoded CMYK data doesn't match the image size"))
+}
@@ -1,45 +1,1136 @@
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Rgba};
+mod jpeg;
+mod recipe;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(test)]
+mod tests;
+
+pub use jpeg::ChromaSubsampling;
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use ab_glyph::{point, Font, OutlinedGlyph, ScaleFont};
+use image::{
+    codecs::{
+        bmp::{BmpDecoder, BmpEncoder},
+        farbfeld::FarbfeldDecoder,
+        gif::GifDecoder,
+        png::{CompressionType, FilterType, PngDecoder, PngEncoder},
+        webp::WebPDecoder,
+        tga::TgaEncoder,
+        tiff::TiffEncoder,
+    },
+    AnimationDecoder, ColorType, DynamicImage, GenericImageView, ImageBuffer, ImageDecoderRect, ImageFormat, ImageReader,
+    Luma, Rgba, RgbaImage, SubImage,
+};
+use serde::{Deserialize, Serialize};
 use imageproc::{
-    geometric_transformations::{rotate_about_center, Interpolation},
+    drawing::{
+        draw_antialiased_line_segment_mut, draw_antialiased_polygon_mut, draw_filled_circle_mut,
+        draw_hollow_circle_mut,
+    },
+    pixelops::interpolate,
+    point::Point,
+    geometric_transformations::{rotate_about_center, warp, Interpolation, Projection},
     filter::{gaussian_blur_f32},
+    gradients::sobel_gradients,
 };
+
+/// Blur sigma used by `clarity` to separate local detail from broad tonal structure
+const CLARITY_SIGMA: f32 = 10.0;
+
+/// Fraction of the image height over which `tilt_shift` ramps from sharp to fully blurred
+const TILT_SHIFT_FALLOFF: f32 = 0.2;
+
+/// Space between a caption's background box and its text, in pixels
+const CAPTION_PADDING: u32 = 4;
+
+/// Blur sigma `blur_fast` aims to run at after downscaling; larger sigmas shrink the image
+/// by the integer factor that brings them closest to this
+const BLUR_FAST_WORKING_SIGMA: f32 = 3.0;
+
+/// Largest relative red/blue gain applied by `adjust_temperature` at an amount of ±1.0
+const TEMPERATURE_SCALE: f32 = 0.2;
+
+/// How many times the mean of green and blue a pixel's red must exceed for
+/// `remove_red_eye` to treat it as red-eye; skin tones stay well below this
+const RED_EYE_RATIO: f32 = 1.8;
+
+/// Width in pixels of the divider `side_by_side` draws between the two images
+const COMPARISON_DIVIDER_WIDTH: u32 = 4;
+
+/// Font size in pixels of `side_by_side`'s labels
+const COMPARISON_LABEL_SCALE: f32 = 24.0;
+
+/// Side length of the square tiles `transpose` copies at a time
+const TRANSPOSE_TILE: usize = 32;
+
+/// Classic sepia tone as a color matrix, used by `sepia`
+const SEPIA_MATRIX: [[f32; 3]; 3] = [
+    [0.393, 0.769, 0.189],
+    [0.349, 0.686, 0.168],
+    [0.272, 0.534, 0.131],
+];
+
+/// Output rows `resize_streaming` produces per strip; only the source rows under a strip
+/// are converted and held in memory at once
+const STREAMING_STRIP_ROWS: u32 = 16;
+
+/// Speed/size trade-off for `save_png_optimized`. Every level is lossless
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    /// Minimal deflate effort and no row filtering
+    Fast,
+    /// Balanced deflate level with adaptive row filters
+    #[default]
+    Default,
+    /// Strongest deflate level with adaptive row filters
+    Best,
+}
+
+/// Cylindrical color model for saturation adjustments. HSL lightness is the midpoint of
+/// the brightest and darkest channels, so full saturation sits at lightness 0.5; HSV value
+/// is the brightest channel alone, so pure colors have full value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorModel {
+    /// Hue, saturation, lightness
+    #[default]
+    Hsl,
+    /// Hue, saturation, value
+    Hsv,
+}
+
+/// Backdrop color of a chroma-keyed shot, for `despill`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyColor {
+    /// Green screen
+    #[default]
+    Green,
+    /// Blue screen
+    Blue,
+}
+
+/// Resampling filter for resizes that let the caller choose, from fastest and blockiest
+/// to slowest and sharpest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    /// Nearest neighbor
+    Nearest,
+    /// Linear
+    #[default]
+    Triangle,
+    /// Cubic
+    CatmullRom,
+    /// Gaussian, soft
+    Gaussian,
+    /// Lanczos with window 3
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// How far the kernel reaches, in source pixels at unit scale. Nearest neighbor
+    /// has no kernel: it picks a single source pixel instead of weighting several
+    fn support(self) -> f32 {
+        match self {
+            ResizeFilter::Nearest => 0.0,
+            ResizeFilter::Triangle => 1.0,
+            ResizeFilter::CatmullRom => 2.0,
+            ResizeFilter::Gaussian | ResizeFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// The kernel's unnormalized weight at distance `x` from the sample point
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            ResizeFilter::Nearest => if x < 0.5 { 1.0 } else { 0.0 },
+            ResizeFilter::Triangle => (1.0 - x).max(0.0),
+            ResizeFilter::CatmullRom if x < 1.0 => 1.5 * x * x * x - 2.5 * x * x + 1.0,
+            ResizeFilter::CatmullRom if x < 2.0 => -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0,
+            ResizeFilter::CatmullRom => 0.0,
+            ResizeFilter::Gaussian => (-2.0 * x * x).exp(),
+            ResizeFilter::Lanczos3 if x < 3.0 => sinc(x) * sinc(x / 3.0),
+            ResizeFilter::Lanczos3 => 0.0,
+        }
+    }
+}
+
+/// How `rotate_with` samples the source between pixel centers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotateInterpolation {
+    /// Nearest pixel; keeps hard edges and the exact palette, for pixel art
+    Nearest,
+    /// Linear blend of the four nearest pixels, as `rotate` uses
+    #[default]
+    Bilinear,
+    /// Cubic blend of the sixteen nearest pixels; sharper than bilinear
+    Bicubic,
+}
+
+impl RotateInterpolation {
+    fn interpolation(self) -> Interpolation {
+        match self {
+            RotateInterpolation::Nearest => Interpolation::Nearest,
+            RotateInterpolation::Bilinear => Interpolation::Bilinear,
+            RotateInterpolation::Bicubic => Interpolation::Bicubic,
+        }
+    }
+}
+
+/// Named looks for `apply_preset`, each a fixed chain of tone and color operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Warm, slightly faded color with a soft vignette
+    WarmFilm,
+    /// Cool, contrasty and slightly desaturated
+    CoolCinematic,
+    /// Muted color, low contrast and a strong vignette
+    Vintage,
+}
+
+/// How filters sample pixels that fall outside the image bounds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeMode {
+    /// Repeat the nearest edge pixel
+    Clamp,
+    /// Mirror the image across its edges (the edge pixel itself is repeated once)
+    Reflect,
+    /// Wrap around to the opposite edge, for tileable textures
+    Wrap,
+    /// Treat everything outside the image as a fixed color
+    Constant(#[serde(with = "recipe::rgba")] Rgba<u8>),
+}
+
+impl EdgeMode {
+    /// Map a possibly out-of-range coordinate into `0..len`, or `None` for `Constant`
+    fn resolve(&self, coord: i64, len: u32) -> Option<u32> {
+        let len = len as i64;
+        if (0..len).contains(&coord) {
+            return Some(coord as u32);
+        }
+        let resolved = match self {
+            EdgeMode::Clamp => coord.clamp(0, len - 1),
+            EdgeMode::Reflect => {
+                let m = coord.rem_euclid(2 * len);
+                if m < len { m } else { 2 * len - 1 - m }
+            }
+            EdgeMode::Wrap => coord.rem_euclid(len),
+            EdgeMode::Constant(_) => return None,
+        };
+        Some(resolved as u32)
+    }
+
+    /// Fetch the pixel at (x, y), applying this edge mode outside the image
+    fn sample(&self, img: &RgbaImage, x: i64, y: i64) -> Rgba<u8> {
+        match (self.resolve(x, img.width()), self.resolve(y, img.height())) {
+            (Some(x), Some(y)) => *img.get_pixel(x, y),
+            _ => match self {
+                EdgeMode::Constant(color) => *color,
+                _ => unreachable!("only constant edges resolve outside the image"),
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ImageError {
     LoadError(String),
-    OperationError(String),
+    /// An operation failed; `op` names it (e.g. "crop") so errors from a chain of calls
+    /// show which step went wrong
+    OperationError { op: String, detail: String },
+    /// `op` only makes sense for some color types (e.g. alpha operations need an alpha
+    /// channel) and the image's `color_type` isn't one of them
+    UnsupportedColorType { op: String, color_type: ColorType },
+}
+
+impl ImageError {
+    /// Build an `OperationError` for the operation named `op`
+    fn operation(op: &str, detail: impl Into<String>) -> Self {
+        ImageError::OperationError {
+            op: op.to_string(),
+            detail: detail.into(),
+        }
+    }
+
+    /// Build an `UnsupportedColorType` for the operation named `op`
+    fn unsupported_color_type(op: &str, color_type: ColorType) -> Self {
+        ImageError::UnsupportedColorType {
+            op: op.to_string(),
+            color_type,
+        }
+    }
+}
+
+/// A single mutating operation and its parameters, as recorded in an `OperationLog`.
+/// Serialized as an object whose "op" field is the method name, e.g.
+/// `{"op":"blur","sigma":1.5}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    Rotate { angle: f32 },
+    AdjustBrightness { factor: f32 },
+    Blur { sigma: f32 },
+    Grayscale,
+    Invert,
+    AdjustContrast { factor: f32 },
+    Clarity { amount: f32 },
+    Colorize { hue: f32, saturation: f32 },
+    ResizeArea { width: u32, height: u32 },
+    AutoWhiteBalance,
+    SeamCarve { new_width: u32, new_height: u32 },
+    SharpenEdgeAware { sigma: f32, amount: f32, threshold: u8 },
+    TiltShift { focus_center_y: u32, focus_height: u32, sigma: f32 },
+    RemoveRedEye { x: u32, y: u32, width: u32, height: u32 },
+    Normalize,
+    Despeckle { min_size: u32 },
+    AdjustTemperature { amount: f32 },
+    AdjustSaturation { factor: f32, model: ColorModel },
+    Vignette { strength: f32 },
+    AdjustChannels { r_factor: f32, g_factor: f32, b_factor: f32 },
+    Posterize { levels: u8 },
+    Cartoonize { levels: u8, edge_threshold: u8 },
+    AdjustValue { factor: f32 },
+    AdjustHue { degrees: f32 },
+    AdjustHueRange { target_hue: f32, range: f32, hue_shift: f32, sat_factor: f32 },
+    BlurFast { sigma: f32 },
+    Despill { key: KeyColor, amount: f32 },
+    ResizeStreaming { width: u32, height: u32 },
+    Sepia,
+    RgbShift { r_dx: i32, r_dy: i32, g_dx: i32, g_dy: i32, b_dx: i32, b_dy: i32 },
+    ResizeToMultiple { multiple: u32, filter: ResizeFilter },
+    PadToMultiple { multiple: u32 },
+    SelectiveDesaturate { keep_hue: f32, hue_range: f32 },
+    Rotate90,
+    Rotate270,
+    CorrectVignette { strength: f32 },
+    RotateWith { angle: f32, interpolation: RotateInterpolation },
+    RotateHueMatrix { degrees: f32 },
+    SoftClipHighlights { knee: f32 },
+    AdjustGammaRgb { r_gamma: f32, g_gamma: f32, b_gamma: f32 },
+    Kaleidoscope { segments: u32 },
+    ShadowsHighlights { shadow_lift: f32, highlight_recover: f32 },
+    Resize { width: u32, height: u32, filter: ResizeFilter },
+    ConvertTo {
+        #[serde(with = "recipe::color_type")]
+        target: ColorType,
+    },
+    RotateWithFill {
+        angle: f32,
+        #[serde(with = "recipe::rgba")]
+        fill: Rgba<u8>,
+    },
+    RotateAbout {
+        angle: f32,
+        pivot: (f32, f32),
+        #[serde(with = "recipe::rgba")]
+        fill: Rgba<u8>,
+    },
+    ColorMatrix { matrix: [[f32; 3]; 3], offset: [f32; 3] },
+    Convolve { kernel: Vec<f32>, kernel_width: u32, edge_mode: EdgeMode },
+    BoxBlur { radius: u32, edge_mode: EdgeMode },
+    MedianFilter { radius: u32, edge_mode: EdgeMode },
+    SplitTone {
+        #[serde(with = "recipe::rgba")]
+        shadow_color: Rgba<u8>,
+        #[serde(with = "recipe::rgba")]
+        highlight_color: Rgba<u8>,
+        balance: f32,
+    },
+    /// The LUT is recorded by path and read again on replay
+    ApplyLutCube { path: String },
+    GradientMap {
+        #[serde(with = "recipe::gradient_stops")]
+        stops: Vec<(f32, Rgba<u8>)>,
+    },
+    FloodFill {
+        x: u32,
+        y: u32,
+        #[serde(with = "recipe::rgba")]
+        fill: Rgba<u8>,
+        tolerance: u8,
+    },
+    DrawCircle {
+        center: (i32, i32),
+        radius: i32,
+        #[serde(with = "recipe::rgba")]
+        color: Rgba<u8>,
+        filled: bool,
+    },
+    DrawLineAa {
+        start: (i32, i32),
+        end: (i32, i32),
+        #[serde(with = "recipe::rgba")]
+        color: Rgba<u8>,
+    },
+    DrawCircleAa {
+        center: (i32, i32),
+        radius: u32,
+        #[serde(with = "recipe::rgba")]
+        color: Rgba<u8>,
+        filled: bool,
+    },
+    DrawGrid {
+        spacing: u32,
+        #[serde(with = "recipe::rgba")]
+        color: Rgba<u8>,
+    },
+    DrawThirds {
+        #[serde(with = "recipe::rgba")]
+        color: Rgba<u8>,
+    },
+}
+
+impl Operation {
+    /// Name used for this operation in serialized recipes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Crop { .. } => "crop",
+            Operation::Rotate { .. } => "rotate",
+            Operation::AdjustBrightness { .. } => "adjust_brightness",
+            Operation::Blur { .. } => "blur",
+            Operation::Grayscale => "grayscale",
+            Operation::Invert => "invert",
+            Operation::AdjustContrast { .. } => "adjust_contrast",
+            Operation::Clarity { .. } => "clarity",
+            Operation::Colorize { .. } => "colorize",
+            Operation::ResizeArea { .. } => "resize_area",
+            Operation::AutoWhiteBalance => "auto_white_balance",
+            Operation::SeamCarve { .. } => "seam_carve",
+            Operation::SharpenEdgeAware { .. } => "sharpen_edge_aware",
+            Operation::TiltShift { .. } => "tilt_shift",
+            Operation::RemoveRedEye { .. } => "remove_red_eye",
+            Operation::Normalize => "normalize",
+            Operation::Despeckle { .. } => "despeckle",
+            Operation::AdjustTemperature { .. } => "adjust_temperature",
+            Operation::AdjustSaturation { .. } => "adjust_saturation",
+            Operation::Vignette { .. } => "vignette",
+            Operation::AdjustChannels { .. } => "adjust_channels",
+            Operation::Posterize { .. } => "posterize",
+            Operation::Cartoonize { .. } => "cartoonize",
+            Operation::AdjustValue { .. } => "adjust_value",
+            Operation::AdjustHue { .. } => "adjust_hue",
+            Operation::AdjustHueRange { .. } => "adjust_hue_range",
+            Operation::BlurFast { .. } => "blur_fast",
+            Operation::Despill { .. } => "despill",
+            Operation::ResizeStreaming { .. } => "resize_streaming",
+            Operation::Sepia => "sepia",
+            Operation::RgbShift { .. } => "rgb_shift",
+            Operation::ResizeToMultiple { .. } => "resize_to_multiple",
+            Operation::PadToMultiple { .. } => "pad_to_multiple",
+            Operation::SelectiveDesaturate { .. } => "selective_desaturate",
+            Operation::Rotate90 => "rotate90",
+            Operation::Rotate270 => "rotate270",
+            Operation::CorrectVignette { .. } => "correct_vignette",
+            Operation::RotateWith { .. } => "rotate_with",
+            Operation::RotateHueMatrix { .. } => "rotate_hue_matrix",
+            Operation::SoftClipHighlights { .. } => "soft_clip_highlights",
+            Operation::AdjustGammaRgb { .. } => "adjust_gamma_rgb",
+            Operation::Kaleidoscope { .. } => "kaleidoscope",
+            Operation::ShadowsHighlights { .. } => "shadows_highlights",
+            Operation::Resize { .. } => "resize",
+            Operation::ConvertTo { .. } => "convert_to",
+            Operation::RotateWithFill { .. } => "rotate_with_fill",
+            Operation::RotateAbout { .. } => "rotate_about",
+            Operation::ColorMatrix { .. } => "color_matrix",
+            Operation::Convolve { .. } => "convolve",
+            Operation::BoxBlur { .. } => "box_blur",
+            Operation::MedianFilter { .. } => "median_filter",
+            Operation::SplitTone { .. } => "split_tone",
+            Operation::ApplyLutCube { .. } => "apply_lut_cube",
+            Operation::GradientMap { .. } => "gradient_map",
+            Operation::FloodFill { .. } => "flood_fill",
+            Operation::DrawCircle { .. } => "draw_circle",
+            Operation::DrawLineAa { .. } => "draw_line_aa",
+            Operation::DrawCircleAa { .. } => "draw_circle_aa",
+            Operation::DrawGrid { .. } => "draw_grid",
+            Operation::DrawThirds { .. } => "draw_thirds",
+        }
+    }
+
+    fn apply(&self, processor: &mut ImageProcessor) -> Result<(), ImageError> {
+        match *self {
+            Operation::Crop { x, y, width, height } => processor.crop(x, y, width, height)?,
+            Operation::Rotate { angle } => processor.rotate(angle)?,
+            Operation::AdjustBrightness { factor } => processor.adjust_brightness(factor)?,
+            Operation::Blur { sigma } => processor.blur(sigma)?,
+            Operation::Grayscale => processor.grayscale()?,
+            Operation::Invert => processor.invert()?,
+            Operation::AdjustContrast { factor } => processor.adjust_contrast(factor)?,
+            Operation::Clarity { amount } => processor.clarity(amount)?,
+            Operation::Colorize { hue, saturation } => processor.colorize(hue, saturation)?,
+            Operation::ResizeArea { width, height } => processor.resize_area(width, height)?,
+            Operation::AutoWhiteBalance => processor.auto_white_balance()?,
+            Operation::SeamCarve { new_width, new_height } => processor.seam_carve(new_width, new_height)?,
+            Operation::SharpenEdgeAware { sigma, amount, threshold } => processor.sharpen_edge_aware(sigma, amount, threshold)?,
+            Operation::TiltShift { focus_center_y, focus_height, sigma } => processor.tilt_shift(focus_center_y, focus_height, sigma)?,
+            Operation::RemoveRedEye { x, y, width, height } => processor.remove_red_eye(x, y, width, height)?,
+            Operation::Normalize => processor.normalize()?,
+            Operation::Despeckle { min_size } => processor.despeckle(min_size)?,
+            Operation::AdjustTemperature { amount } => processor.adjust_temperature(amount)?,
+            Operation::AdjustSaturation { factor, model } => processor.adjust_saturation(factor, model)?,
+            Operation::Vignette { strength } => processor.vignette(strength)?,
+            Operation::AdjustChannels { r_factor, g_factor, b_factor } => processor.adjust_channels(r_factor, g_factor, b_factor)?,
+            Operation::Posterize { levels } => processor.posterize(levels)?,
+            Operation::Cartoonize { levels, edge_threshold } => processor.cartoonize(levels, edge_threshold)?,
+            Operation::AdjustValue { factor } => processor.adjust_value(factor)?,
+            Operation::AdjustHue { degrees } => processor.adjust_hue(degrees)?,
+            Operation::AdjustHueRange { target_hue, range, hue_shift, sat_factor } => processor.adjust_hue_range(target_hue, range, hue_shift, sat_factor)?,
+            Operation::BlurFast { sigma } => processor.blur_fast(sigma)?,
+            Operation::Despill { key, amount } => processor.despill(key, amount)?,
+            Operation::ResizeStreaming { width, height } => processor.resize_streaming(width, height)?,
+            Operation::Sepia => processor.sepia()?,
+            Operation::RgbShift { r_dx, r_dy, g_dx, g_dy, b_dx, b_dy } => processor.rgb_shift((r_dx, r_dy), (g_dx, g_dy), (b_dx, b_dy))?,
+            Operation::ResizeToMultiple { multiple, filter } => processor.resize_to_multiple(multiple, filter)?,
+            Operation::PadToMultiple { multiple } => processor.pad_to_multiple(multiple)?,
+            Operation::SelectiveDesaturate { keep_hue, hue_range } => processor.selective_desaturate(keep_hue, hue_range)?,
+            Operation::Rotate90 => processor.rotate90()?,
+            Operation::Rotate270 => processor.rotate270()?,
+            Operation::CorrectVignette { strength } => processor.correct_vignette(strength)?,
+            Operation::RotateWith { angle, interpolation } => processor.rotate_with(angle, interpolation)?,
+            Operation::RotateHueMatrix { degrees } => processor.rotate_hue_matrix(degrees)?,
+            Operation::SoftClipHighlights { knee } => processor.soft_clip_highlights(knee)?,
+            Operation::AdjustGammaRgb { r_gamma, g_gamma, b_gamma } => processor.adjust_gamma_rgb(r_gamma, g_gamma, b_gamma)?,
+            Operation::Kaleidoscope { segments } => processor.kaleidoscope(segments)?,
+            Operation::ShadowsHighlights { shadow_lift, highlight_recover } => processor.shadows_highlights(shadow_lift, highlight_recover)?,
+            Operation::Resize { width, height, filter } => processor.resize(width, height, filter)?,
+            Operation::ConvertTo { target } => processor.convert_to(target)?,
+            Operation::RotateWithFill { angle, fill } => processor.rotate_with_fill(angle, fill)?,
+            Operation::RotateAbout { angle, pivot, fill } => processor.rotate_about(angle, pivot, fill)?,
+            Operation::ColorMatrix { matrix, offset } => processor.color_matrix(matrix, offset)?,
+            Operation::Convolve { ref kernel, kernel_width, edge_mode } => processor.convolve(kernel, kernel_width, edge_mode)?,
+            Operation::BoxBlur { radius, edge_mode } => processor.box_blur(radius, edge_mode)?,
+            Operation::MedianFilter { radius, edge_mode } => processor.median_filter(radius, edge_mode)?,
+            Operation::SplitTone { shadow_color, highlight_color, balance } => processor.split_tone(shadow_color, highlight_color, balance)?,
+            Operation::ApplyLutCube { ref path } => processor.apply_lut_cube(path)?,
+            Operation::GradientMap { ref stops } => processor.gradient_map(stops)?,
+            Operation::FloodFill { x, y, fill, tolerance } => processor.flood_fill(x, y, fill, tolerance)?,
+            Operation::DrawCircle { center, radius, color, filled } => processor.draw_circle(center, radius, color, filled)?,
+            Operation::DrawLineAa { start, end, color } => processor.draw_line_aa(start, end, color)?,
+            Operation::DrawCircleAa { center, radius, color, filled } => processor.draw_circle_aa(center, radius, color, filled)?,
+            Operation::DrawGrid { spacing, color } => processor.draw_grid(spacing, color)?,
+            Operation::DrawThirds { color } => processor.draw_thirds(color)?,
+        };
+        Ok(())
+    }
+}
+
+/// Ordered record of the operations applied to an image, serializable as a JSON recipe
+///
+/// Operations whose inputs a recipe can't carry (other images, masks, fonts, closures)
+/// still run, but mark the log so `to_json` fails rather than writing a recipe that
+/// replays to a different image.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperationLog {
+    operations: Vec<Operation>,
+    /// Name of the first operation that couldn't be recorded, if any
+    unrecordable: Option<String>,
+}
+
+impl OperationLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, op: Operation) {
+        self.operations.push(op);
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Note that an operation ran that can't be written into a recipe
+    pub fn record_unrecordable(&mut self, name: &str) {
+        self.unrecordable.get_or_insert_with(|| name.to_string());
+    }
+
+    /// Serialize as a JSON array, e.g. `[{"op":"blur","sigma":1.5},{"op":"grayscale"}]`.
+    /// Fails if an unrecordable operation ran or a parameter is NaN or infinite, since
+    /// JSON can't represent either
+    pub fn to_json(&self) -> Result<String, ImageError> {
+        if let Some(name) = &self.unrecordable {
+            return Err(ImageError::operation("recipe_json", format!(
+                "'{}' takes inputs a recipe can't hold, so this log can't be replayed",
+                name
+            )));
+        }
+        for op in &self.operations {
+            check_finite("recipe_json", op)?;
+        }
+        serde_json::to_string(&self.operations).map_err(|e| ImageError::operation("recipe_json", e.to_string()))
+    }
+
+    /// Parse a recipe previously produced by `to_json`. Unknown operations or fields,
+    /// missing parameters and values of the wrong type or out of range are errors
+    pub fn from_json(json: &str) -> Result<Self, ImageError> {
+        let operations: Vec<Operation> = serde_json::from_str(json)
+            .map_err(|e| ImageError::operation("apply_recipe", format!("Invalid recipe: {}", e)))?;
+        for op in &operations {
+            check_finite("apply_recipe", op)?;
+        }
+        Ok(OperationLog { operations, unrecordable: None })
+    }
+}
+
+/// In-memory LRU cache of rendered images keyed by source hash and operation recipe
+pub struct ThumbnailCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<(u64, String), (DynamicImage, u64)>,
+}
+
+impl ThumbnailCache {
+    /// Create a cache holding at most `capacity` images; 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        ThumbnailCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Return the cached image for this source and recipe, or run `compute` and cache its
+    /// result, evicting the least recently used entry when full
+    pub fn get_or_compute(
+        &mut self,
+        source_hash: u64,
+        recipe: &OperationLog,
+        compute: impl FnOnce() -> DynamicImage,
+    ) -> DynamicImage {
+        let Ok(recipe) = recipe.to_json() else {
+            return compute();
+        };
+        self.tick += 1;
+        let key = (source_hash, recipe);
+        if let Some((image, last_used)) = self.entries.get_mut(&key) {
+            *last_used = self.tick;
+            return image.clone();
+        }
+
+        let image = compute();
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+            self.entries.insert(key, (image.clone(), self.tick));
+        }
+        image
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// A parsed Adobe `.cube` 3D LUT
+struct CubeLut {
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    /// Output colors with red varying fastest, then green, then blue
+    table: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    fn parse(text: &str) -> Result<Self, ImageError> {
+        let invalid = |message: String| ImageError::LoadError(format!("Invalid .cube LUT: {}", message));
+        let parse_triple = |fields: &[&str]| -> Result<[f32; 3], ImageError> {
+            if fields.len() != 3 {
+                return Err(invalid(format!("expected 3 values, got {}", fields.len())));
+            }
+            let mut triple = [0.0; 3];
+            for (value, field) in triple.iter_mut().zip(fields) {
+                *value = field
+                    .parse()
+                    .map_err(|_| invalid(format!("'{}' is not a number", field)))?;
+            }
+            Ok(triple)
+        };
+
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[0] {
+                "TITLE" => {}
+                "LUT_1D_SIZE" => return Err(invalid("1D LUTs are not supported".to_string())),
+                "LUT_3D_SIZE" => {
+                    let n: usize = fields
+                        .get(1)
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| invalid("LUT_3D_SIZE needs a number".to_string()))?;
+                    if !(2..=256).contains(&n) {
+                        return Err(invalid(format!("LUT_3D_SIZE {} is outside 2..=256", n)));
+                    }
+                    size = Some(n);
+                }
+                "DOMAIN_MIN" => domain_min = parse_triple(&fields[1..])?,
+                "DOMAIN_MAX" => domain_max = parse_triple(&fields[1..])?,
+                "LUT_3D_INPUT_RANGE" => {
+                    let range: Vec<f32> = fields[1..].iter().filter_map(|v| v.parse().ok()).collect();
+                    if fields.len() != 3 || range.len() != 2 {
+                        return Err(invalid("LUT_3D_INPUT_RANGE needs two numbers".to_string()));
+                    }
+                    domain_min = [range[0]; 3];
+                    domain_max = [range[1]; 3];
+                }
+                keyword if keyword.chars().next().is_some_and(|c| c.is_ascii_alphabetic()) => {
+                    return Err(invalid(format!("unknown keyword '{}'", keyword)));
+                }
+                _ => table.push(parse_triple(&fields)?),
+            }
+        }
+
+        let size = size.ok_or_else(|| invalid("missing LUT_3D_SIZE".to_string()))?;
+        if table.len() != size * size * size {
+            return Err(invalid(format!(
+                "expected {} entries for size {}, found {}",
+                size * size * size,
+                size,
+                table.len()
+            )));
+        }
+        if (0..3).any(|c| domain_min[c] >= domain_max[c]) {
+            return Err(invalid("DOMAIN_MIN must be below DOMAIN_MAX".to_string()));
+        }
+        Ok(CubeLut { size, domain_min, domain_max, table })
+    }
+
+    /// Trilinearly interpolate the LUT at `rgb` (each channel 0.0..=1.0)
+    fn lookup(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let n = self.size;
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for c in 0..3 {
+            let t = (rgb[c] - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+            let pos = t.clamp(0.0, 1.0) * (n - 1) as f32;
+            base[c] = (pos.floor() as usize).min(n - 2);
+            frac[c] = pos - base[c] as f32;
+        }
+
+        let mut out = [0.0f32; 3];
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut weight = 1.0;
+            for c in 0..3 {
+                weight *= if offset[c] == 1 { frac[c] } else { 1.0 - frac[c] };
+            }
+            let (r, g, b) = (base[0] + offset[0], base[1] + offset[1], base[2] + offset[2]);
+            let entry = self.table[r + g * n + b * n * n];
+            for c in 0..3 {
+                out[c] += weight * entry[c];
+            }
+        }
+        out
+    }
 }
 
 pub struct ImageProcessor {
     image: DynamicImage,
+    log: OperationLog,
+    /// Per-operation wall-clock durations, `None` unless enabled with `with_timing`
+    timings: Option<Vec<(String, Duration)>>,
+    /// Whether `new` loaded this from a CMYK or YCCK JPEG
+    cmyk_source: bool,
+}
+
+/// Non-owning, read-only view into a rectangle of an `ImageProcessor`'s image,
+/// created by `ImageProcessor::crop_view`. Pixels are only copied by `to_processor`
+pub struct SubImageProcessor<'a> {
+    view: SubImage<&'a DynamicImage>,
+}
+
+impl SubImageProcessor<'_> {
+    pub fn width(&self) -> u32 {
+        self.view.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.view.height()
+    }
+
+    /// Read a pixel relative to the view's top-left corner, or `None` if out of bounds
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<Rgba<u8>> {
+        if x < self.view.width() && y < self.view.height() {
+            Some(self.view.get_pixel(x, y))
+        } else {
+            None
+        }
+    }
+
+    /// Copy the viewed region into a new, independently mutable processor
+    pub fn to_processor(&self) -> ImageProcessor {
+        ImageProcessor::from_dynamic_image(DynamicImage::ImageRgba8(self.view.to_image()))
+    }
 }
 
 impl ImageProcessor {
-    /// Create a new ImageProcessor from a file path
+    /// Create a new ImageProcessor from a file path. CMYK and YCCK JPEGs are converted to
+    /// RGB: Adobe-marked ones (inverted CMYK or YCCK) by image's decoder, and unmarked CMYK,
+    /// which that decoder would show as a negative, by `jpeg::decode_plain_cmyk`. See
+    /// `is_cmyk_source`
     pub fn new(path: &str) -> Result<Self, ImageError> {
-        image::open(path)
-            .map(|img| ImageProcessor { image: img })
-            .map_err(|e| ImageError::LoadError(e.to_string()))
+        let load_error = |e: image::ImageError| ImageError::LoadError(e.to_string());
+        let reader = ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| ImageError::LoadError(e.to_string()))?;
+
+        let cmyk = match reader.format() {
+            Some(ImageFormat::Jpeg) => File::open(path)
+                .and_then(|file| jpeg::cmyk_encoding(BufReader::new(file)))
+                .unwrap_or(None),
+            _ => None,
+        };
+        let image = match cmyk {
+            Some(jpeg::CmykEncoding::Plain) => {
+                let data = fs::read(path).map_err(|e| ImageError::LoadError(e.to_string()))?;
+                let rgb = jpeg::decode_plain_cmyk(&data).map_err(|e| ImageError::LoadError(e.to_string()))?;
+                DynamicImage::ImageRgb8(rgb)
+            }
+            _ => reader.decode().map_err(load_error)?,
+        };
+        let mut processor = ImageProcessor::from_dynamic_image(image);
+        processor.cmyk_source = cmyk.is_some();
+        Ok(processor)
+    }
+
+    /// Load only the `width` x `height` rectangle at (`x`, `y`) of an image file. BMP and
+    /// farbfeld files are read through the decoder's rectangle API; other formats are
+    /// decoded in full and then cropped
+    pub fn open_region(path: &str, x: u32, y: u32, width: u32, height: u32) -> Result<Self, ImageError> {
+        let load_error = |e: image::ImageError| ImageError::LoadError(e.to_string());
+        let reader = ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| ImageError::LoadError(e.to_string()))?;
+
+        let image = match reader.format() {
+            Some(ImageFormat::Bmp) => {
+                read_region(BmpDecoder::new(reader.into_inner()).map_err(load_error)?, x, y, width, height)?
+            }
+            Some(ImageFormat::Farbfeld) => {
+                read_region(FarbfeldDecoder::new(reader.into_inner()).map_err(load_error)?, x, y, width, height)?
+            }
+            _ => {
+                let image = Self::new(path)?.image;
+                check_region("open_region", image.dimensions(), x, y, width, height)?;
+                image.crop_imm(x, y, width, height)
+            }
+        };
+        Ok(ImageProcessor::from_dynamic_image(image))
+    }
+
+    /// Decode every frame of an animated GIF, APNG or WebP as a full-canvas RGBA image.
+    /// Other files, including still PNGs and WebPs, give a single frame
+    pub fn open_frames(path: &str) -> Result<Vec<DynamicImage>, ImageError> {
+        let load_error = |e: image::ImageError| ImageError::LoadError(e.to_string());
+        let reader = ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| ImageError::LoadError(e.to_string()))?;
+
+        let frames = match reader.format() {
+            Some(ImageFormat::Gif) => GifDecoder::new(reader.into_inner()).map_err(load_error)?.into_frames(),
+            Some(ImageFormat::Png) => {
+                let decoder = PngDecoder::new(reader.into_inner()).map_err(load_error)?;
+                if !decoder.is_apng().map_err(load_error)? {
+                    return DynamicImage::from_decoder(decoder).map(|image| vec![image]).map_err(load_error);
+                }
+                decoder.apng().map_err(load_error)?.into_frames()
+            }
+            Some(ImageFormat::WebP) => {
+                let decoder = WebPDecoder::new(reader.into_inner()).map_err(load_error)?;
+                if !decoder.has_animation() {
+                    return DynamicImage::from_decoder(decoder).map(|image| vec![image]).map_err(load_error);
+                }
+                decoder.into_frames()
+            }
+            _ => return reader.decode().map(|image| vec![image]).map_err(load_error),
+        };
+        frames
+            .map(|frame| frame.map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())).map_err(load_error))
+            .collect()
+    }
+
+    /// Number of frames `open_frames` would return. Frames have to be decoded to be
+    /// counted, so this costs as much as `open_frames` but doesn't keep them
+    pub fn frame_count(path: &str) -> Result<usize, ImageError> {
+        Ok(Self::open_frames(path)?.len())
     }
 
     /// Create from an existing DynamicImage
     pub fn from_dynamic_image(image: DynamicImage) -> Self {
-        ImageProcessor { image }
+        ImageProcessor {
+            image,
+            log: OperationLog::new(),
+            timings: None,
+            cmyk_source: false,
+        }
     }
 
-    /// Crop the image given coordinates
-    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<&mut Self, ImageError> {
-        if x + width > self.image.width() || y + height > self.image.height() {
-            return Err(ImageError::OperationError(
-                "Crop dimensions exceed image bounds".to_string(),
+    /// Record how long each mutating operation takes, readable through `timings`
+    pub fn with_timing(mut self) -> Self {
+        self.timings = Some(Vec::new());
+        self
+    }
+
+    /// Operation names and durations in the order they ran; empty unless `with_timing` was used
+    pub fn timings(&self) -> &[(String, Duration)] {
+        self.timings.as_deref().unwrap_or(&[])
+    }
+
+    /// Crop to the bounding box of the largest 8-connected region of non-background pixels.
+    /// A pixel is background if every channel is within `tolerance` of `background`, so
+    /// isolated specks elsewhere in the image don't widen the crop
+    pub fn crop_to_content(&mut self, background: Rgba<u8>, tolerance: u8) -> Result<&mut Self, ImageError> {
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let mask: Vec<bool> = img
+            .pixels()
+            .map(|p| (0..4).any(|c| p[c].abs_diff(background[c]) > tolerance))
+            .collect();
+
+        let (_, regions) = label_regions(&mask, width, height);
+        let largest = regions.iter().max_by_key(|r| r.size).ok_or_else(|| {
+            ImageError::operation("crop_to_content", "Image has no content distinct from the background")
+        })?;
+        self.crop(
+            largest.min_x,
+            largest.min_y,
+            largest.max_x - largest.min_x + 1,
+            largest.max_y - largest.min_y + 1,
+        )
+    }
+
+    /// Crop to the bounding box of pixels whose alpha is above `alpha_threshold`, e.g. to
+    /// trim transparent padding from sprites. The crop is logged with its offsets like any
+    /// other `crop`. Errors if no pixel is above the threshold, or with
+    /// `UnsupportedColorType` if the image has no alpha channel to trim by
+    pub fn crop_to_alpha(&mut self, alpha_threshold: u8) -> Result<&mut Self, ImageError> {
+        let color = self.image.color();
+        if !color.has_alpha() {
+            return Err(ImageError::unsupported_color_type("crop_to_alpha", color));
+        }
+
+        let img = self.image.to_rgba8();
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if pixel[3] > alpha_threshold {
+                bounds = Some(match bounds {
+                    Some((min_x, min_y, max_x, max_y)) => (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+        let (min_x, min_y, max_x, max_y) = bounds.ok_or_else(|| {
+            ImageError::operation("crop_to_alpha", format!("No pixel has alpha above {}", alpha_threshold))
+        })?;
+        self.crop(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)
+    }
+
+    /// Convert the underlying image to `target` and keep it in that format.
+    ///
+    /// Only `crop`, `crop_view` and the save methods preserve the current format; every
+    /// other operation works on RGBA8 and leaves the image as RGBA8 afterwards, so convert
+    /// last (or just before saving) when a specific format matters
+    pub fn convert_to(&mut self, target: ColorType) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = match target {
+            ColorType::L8 => DynamicImage::ImageLuma8(self.image.to_luma8()),
+            ColorType::La8 => DynamicImage::ImageLumaA8(self.image.to_luma_alpha8()),
+            ColorType::Rgb8 => DynamicImage::ImageRgb8(self.image.to_rgb8()),
+            ColorType::Rgba8 => DynamicImage::ImageRgba8(self.image.to_rgba8()),
+            ColorType::L16 => DynamicImage::ImageLuma16(self.image.to_luma16()),
+            ColorType::La16 => DynamicImage::ImageLumaA16(self.image.to_luma_alpha16()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(self.image.to_rgb16()),
+            ColorType::Rgba16 => DynamicImage::ImageRgba16(self.image.to_rgba16()),
+            ColorType::Rgb32F => DynamicImage::ImageRgb32F(self.image.to_rgb32f()),
+            ColorType::Rgba32F => DynamicImage::ImageRgba32F(self.image.to_rgba32f()),
+            other => {
+                return Err(ImageError::operation("convert_to", format!(
+                    "Cannot convert to unsupported color type {:?}",
+                    other
+                )))
+            }
+        };
+        self.record(started, Operation::ConvertTo { target });
+        Ok(self)
+    }
+
+    /// Borrow a read-only view of a rectangle of the image without copying any pixels.
+    /// The processor stays immutably borrowed for as long as the view is alive, so it
+    /// can't be mutated underneath it; call `to_processor` on the view to get an owned copy
+    pub fn crop_view(&self, x: u32, y: u32, width: u32, height: u32) -> Result<SubImageProcessor<'_>, ImageError> {
+        check_region("crop_view", self.image.dimensions(), x, y, width, height)?;
+        Ok(SubImageProcessor {
+            view: self.image.view(x, y, width, height),
+        })
+    }
+
+    /// Cut the image into a grid of `tile_width` x `tile_height` tiles, row by row, each
+    /// paired with its (x, y) pixel origin. Tiles on the right and bottom edges are smaller
+    /// when the image doesn't divide evenly; a zero tile size yields no tiles
+    pub fn split_tiles(&self, tile_width: u32, tile_height: u32) -> Vec<(u32, u32, DynamicImage)> {
+        if tile_width == 0 || tile_height == 0 {
+            return Vec::new();
+        }
+
+        let (width, height) = (self.image.width(), self.image.height());
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(tile_height as usize) {
+            for x in (0..width).step_by(tile_width as usize) {
+                let tile = self.image.crop_imm(x, y, tile_width.min(width - x), tile_height.min(height - y));
+                tiles.push((x, y, tile));
+            }
+        }
+        tiles
+    }
+
+    /// Build a full mip chain: level 0 is the image itself, and each following level halves
+    /// both dimensions (never below 1) with area averaging, ending at 1x1
+    pub fn generate_mipmaps(&self) -> Vec<DynamicImage> {
+        let mut levels = vec![self.image.clone()];
+        let (mut width, mut height) = self.dimensions();
+        while width > 1 || height > 1 {
+            (width, height) = ((width / 2).max(1), (height / 2).max(1));
+            let mut level = ImageProcessor::from_dynamic_image(levels[levels.len() - 1].clone());
+            if level.resize_area(width, height).is_err() {
+                break;
+            }
+            levels.push(level.image);
+        }
+        levels
+    }
+
+    /// Stitch tiles (as produced by `split_tiles`) back together, placing each at its (x, y)
+    /// origin on a blank `total_width` x `total_height` canvas in the first tile's color type.
+    /// Fails if any tile extends past the canvas
+    pub fn from_tiles(
+        tiles: &[(u32, u32, DynamicImage)],
+        total_width: u32,
+        total_height: u32,
+    ) -> Result<Self, ImageError> {
+        let color = tiles.first().map_or(ColorType::Rgba8, |(_, _, tile)| tile.color());
+        let mut canvas = DynamicImage::new(total_width, total_height, color);
+        for (x, y, tile) in tiles {
+            if *x as u64 + tile.width() as u64 > total_width as u64
+                || *y as u64 + tile.height() as u64 > total_height as u64
+            {
+                return Err(ImageError::operation("from_tiles", format!(
+                    "Tile at ({}, {}) exceeds the {}x{} canvas",
+                    x, y, total_width, total_height
+                )));
+            }
+            image::imageops::replace(&mut canvas, tile, *x as i64, *y as i64);
+        }
+        Ok(ImageProcessor::from_dynamic_image(canvas))
+    }
+
+    /// Render a `width` x `height` placeholder from a BlurHash string such as one made by
+    /// `blurhash`. Both dimensions must be non-zero
+    pub fn from_blurhash(hash: &str, width: u32, height: u32) -> Result<Self, ImageError> {
+        if width == 0 || height == 0 {
+            return Err(ImageError::operation(
+                "from_blurhash",
+                "Placeholder dimensions must be non-zero",
             ));
         }
+        let invalid = |detail: &str| ImageError::operation("from_blurhash", format!("Invalid BlurHash '{}': {}", hash, detail));
+        if !hash.is_ascii() || hash.len() < 6 {
+            return Err(invalid("too short"));
+        }
+        let size_flag = decode_base83(&hash[..1]).ok_or_else(|| invalid("bad character"))?;
+        let (x_components, y_components) = (size_flag % 9 + 1, size_flag / 9 + 1);
+        if hash.len() != 4 + 2 * (x_components * y_components) as usize {
+            return Err(invalid("length doesn't match its component count"));
+        }
+        let quantised_max = decode_base83(&hash[1..2]).ok_or_else(|| invalid("bad character"))?;
+        let maximum = (quantised_max + 1) as f32 / 166.0;
+
+        let dc = decode_base83(&hash[2..6]).ok_or_else(|| invalid("bad character"))?;
+        let mut colors = vec![[dc >> 16, (dc >> 8) & 255, dc & 255].map(|v| srgb_to_linear(v as u8))];
+        for i in 1..(x_components * y_components) as usize {
+            let value = decode_base83(&hash[4 + 2 * i..6 + 2 * i]).ok_or_else(|| invalid("bad character"))?;
+            colors.push([value / (19 * 19), (value / 19) % 19, value % 19].map(|q| sign_pow((q as f32 - 9.0) / 9.0, 2.0) * maximum));
+        }
+
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let mut sum = [0.0f32; 3];
+            for j in 0..y_components {
+                for i in 0..x_components {
+                    let basis = (std::f32::consts::PI * x as f32 * i as f32 / width as f32).cos()
+                        * (std::f32::consts::PI * y as f32 * j as f32 / height as f32).cos();
+                    let color = colors[(j * x_components + i) as usize];
+                    for c in 0..3 {
+                        sum[c] += color[c] * basis;
+                    }
+                }
+            }
+            Rgba([linear_to_srgb(sum[0]), linear_to_srgb(sum[1]), linear_to_srgb(sum[2]), 255])
+        });
+        Ok(ImageProcessor::from_dynamic_image(DynamicImage::ImageRgba8(image)))
+    }
+
+    /// Crop the image given coordinates
+    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        check_region("crop", self.image.dimensions(), x, y, width, height)?;
 
         self.image = self.image.crop(x, y, width, height);
+        self.record(started, Operation::Crop { x, y, width, height });
         Ok(self)
     }
 
+    /// Non-mutating `crop`: returns a new processor holding just the region, whose log is
+    /// this processor's log plus the crop. `self` is left untouched
+    pub fn cropped(&self, x: u32, y: u32, width: u32, height: u32) -> Result<ImageProcessor, ImageError> {
+        check_region("cropped", self.image.dimensions(), x, y, width, height)?;
+
+        let mut log = self.log.clone();
+        log.record(Operation::Crop { x, y, width, height });
+        Ok(ImageProcessor {
+            image: self.image.crop_imm(x, y, width, height),
+            log,
+            timings: self.timings.as_ref().map(|_| Vec::new()),
+            cmyk_source: self.cmyk_source,
+        })
+    }
+
     /// Rotate the image by the specified angle in degrees
     pub fn rotate(&mut self, angle: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
         // Convert angle to radians
         let radians = angle.to_radians();
         
@@ -53,112 +1144,3806 @@ impl ImageProcessor {
             )
         );
         
+        self.record(started, Operation::Rotate { angle });
         Ok(self)
     }
 
-
-    pub fn adjust_brightness(&mut self, factor: f32) -> Result<&mut Self, ImageError> {
-        let mut img = self.image.to_rgba8();
-        for pixel in img.pixels_mut() {
-            pixel[0] = (pixel[0] as f32 * factor).min(255.0) as u8;
-            pixel[1] = (pixel[1] as f32 * factor).min(255.0) as u8;
-            pixel[2] = (pixel[2] as f32 * factor).min(255.0) as u8;
-        }
-        self.image = DynamicImage::ImageRgba8(img);
+    /// Like `rotate`, but with a choice of `interpolation`
+    pub fn rotate_with(&mut self, angle: f32, interpolation: RotateInterpolation) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = DynamicImage::ImageRgba8(rotate_about_center(
+            &self.image.to_rgba8(),
+            angle.to_radians(),
+            interpolation.interpolation(),
+            Rgba([0, 0, 0, 0]),
+        ));
+        self.record(started, Operation::RotateWith { angle, interpolation });
         Ok(self)
     }
 
-    pub fn blur(&mut self, sigma: f32) -> Result<&mut Self, ImageError> {
-        let img = self.image.to_rgba8();
-        let blurred = gaussian_blur_f32(&img, sigma);
-        self.image = DynamicImage::ImageRgba8(blurred);
+    /// Like `rotate`, but fills the corners the rotation exposes with `fill` instead of
+    /// transparent black, e.g. white for images headed to JPEG
+    pub fn rotate_with_fill(&mut self, angle: f32, fill: Rgba<u8>) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = DynamicImage::ImageRgba8(rotate_about_center(
+            &self.image.to_rgba8(),
+            angle.to_radians(),
+            Interpolation::Bilinear,
+            fill,
+        ));
+        self.record(started, Operation::RotateWithFill { angle, fill });
         Ok(self)
     }
 
-    pub fn grayscale(&mut self) -> Result<&mut Self, ImageError> {
-        self.image = DynamicImage::ImageRgba8(self.image.grayscale().to_rgba8());
+    /// Rotate a quarter turn clockwise, losslessly
+    pub fn rotate90(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = DynamicImage::ImageRgba8(rotate90_pixels(&self.image.to_rgba8()));
+        self.record(started, Operation::Rotate90);
         Ok(self)
     }
 
-     /// Invert the colors of the image
-     pub fn invert(&mut self) -> Result<&mut Self, ImageError> {
-        let mut img = self.image.to_rgba8();
-        for pixel in img.pixels_mut() {
-            pixel[0] = 255 - pixel[0];
-            pixel[1] = 255 - pixel[1];
-            pixel[2] = 255 - pixel[2];
-        }
-        self.image = DynamicImage::ImageRgba8(img);
+    /// Rotate a quarter turn counter-clockwise, losslessly
+    pub fn rotate270(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = DynamicImage::ImageRgba8(rotate270_pixels(&self.image.to_rgba8()));
+        self.record(started, Operation::Rotate270);
         Ok(self)
     }
 
-     /// Factor > 1.0 increases contrast, < 1.0 decreases it
-    pub fn adjust_contrast(&mut self, factor: f32) -> Result<&mut Self, ImageError> {
-        let mut img = self.image.to_rgba8();
-        for pixel in img.pixels_mut() {
-            for c in 0..3 {
-                let scaled = (((pixel[c] as f32 / 255.0) - 0.5) * factor + 0.5) * 255.0;
-                pixel[c] = scaled.max(0.0).min(255.0) as u8;
-            }
+
+    /// Rotate so the line from `p1` to `p2` becomes horizontal. The order of the points
+    /// doesn't matter, so the correction is always under 90 degrees. Recorded as a `rotate`;
+    /// corners are left transparent, so follow with a crop (or use the angle with
+    /// `rotate_crop_inside`) to remove them
+    pub fn straighten(&mut self, p1: (f32, f32), p2: (f32, f32)) -> Result<&mut Self, ImageError> {
+        let (mut dx, mut dy) = (p2.0 - p1.0, p2.1 - p1.1);
+        if dx == 0.0 && dy == 0.0 {
+            return Err(ImageError::operation(
+                "straighten",
+                "Straighten needs two distinct points",
+            ));
         }
-        self.image = DynamicImage::ImageRgba8(img);
+        if dx < 0.0 {
+            (dx, dy) = (-dx, -dy);
+        }
+        self.rotate(-dy.atan2(dx).to_degrees())
+    }
+
+    /// Rotate by `angle` degrees (clockwise, like `rotate`) around `pivot` in pixel
+    /// coordinates. The canvas keeps its size: content rotated past the edges is clipped and
+    /// areas left uncovered are filled with `fill`
+    pub fn rotate_about(&mut self, angle: f32, pivot: (f32, f32), fill: Rgba<u8>) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (px, py) = pivot;
+        let projection = Projection::translate(px, py)
+            * Projection::rotate(angle.to_radians())
+            * Projection::translate(-px, -py);
+        self.image = DynamicImage::ImageRgba8(warp(&self.image.to_rgba8(), &projection, Interpolation::Bilinear, fill));
+        self.record(started, Operation::RotateAbout { angle, pivot, fill });
         Ok(self)
     }
 
-    /// Overlay another image at specified coordinates
-    pub fn overlay_image(
-        &mut self,
-        overlay: &DynamicImage,
-        x: u32,
-        y: u32,
-    ) -> Result<&mut Self, ImageError> {
-        if x + overlay.width() > self.image.width() || y + overlay.height() > self.image.height() {
-            return Err(ImageError::OperationError(
-                "Overlay image exceeds base image bounds".to_string(),
+    /// Rotate by `angle` degrees, then crop to the largest axis-aligned rectangle that lies
+    /// entirely inside the rotated image, so no transparent corners remain. Multiples of
+    /// 90 degrees turn the whole image losslessly instead, swapping width and height for
+    /// quarter turns
+    pub fn rotate_crop_inside(&mut self, angle: f32) -> Result<&mut Self, ImageError> {
+        let (width, height) = self.image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(ImageError::operation(
+                "rotate_crop_inside",
+                "Cannot rotate an empty image",
             ));
         }
 
-        // Convert both images to RGBA
-        let mut base: ImageBuffer<Rgba<u8>, Vec<u8>> = self.image.to_rgba8();
+        let quarter_turns = angle as f64 / 90.0;
+        if (quarter_turns - quarter_turns.round()).abs() < 1e-6 {
+            return match (quarter_turns.round() as i64).rem_euclid(4) {
+                0 => Ok(self),
+                1 => self.rotate90(),
+                2 => self.rotate90()?.rotate90(),
+                _ => self.rotate270(),
+            };
+        }
+
+        // Bilinear sampling only gives opaque pixels where the source point has a full
+        // 2x2 neighbourhood, i.e. in the (width - 1) x (height - 1) box between the outer
+        // pixel centers. `rotate` pivots on (width / 2, height / 2), so that box's center
+        // lands half a pixel up and left of the pivot, turned by the angle
+        let radians = (angle as f64).to_radians();
+        let (sin_a, cos_a) = (radians.sin(), radians.cos());
+        let (valid_width, valid_height) = (width as f64 - 1.0, height as f64 - 1.0);
+        let (inner_width, inner_height) = inscribed_rect(valid_width, valid_height, sin_a.abs(), cos_a.abs());
+        let center_x = width as f64 / 2.0 - 0.5 * cos_a + 0.5 * sin_a;
+        let center_y = height as f64 / 2.0 - 0.5 * sin_a - 0.5 * cos_a;
+
+        // Keep the pixels whose positions fall inside the rectangle, shrunk slightly so
+        // none sit exactly on its edge
+        let pixel_span = |center: f64, extent: f64, len: u32| {
+            let half = extent / 2.0 - 1e-3;
+            let first = (center - half).ceil().max(0.0);
+            let last = (center + half).floor().min(len as f64 - 1.0);
+            (first as u32, (last - first + 1.0).max(0.0) as u32)
+        };
+        let (x, crop_width) = pixel_span(center_x, inner_width, width);
+        let (y, crop_height) = pixel_span(center_y, inner_height, height);
+        if crop_width == 0 || crop_height == 0 {
+            return Err(ImageError::operation("rotate_crop_inside", format!(
+                "A {}x{} image has no opaque pixels left after rotating by {} degrees",
+                width, height, angle
+            )));
+        }
+
+        self.rotate(angle)?;
+        self.crop(x, y, crop_width, crop_height)
+    }
+
+    /// Resize to exactly `width` x `height` with `filter`, which is scaled up when shrinking
+    /// so every source pixel contributes. Colors are filtered with premultiplied alpha like
+    /// `resize_area`
+    pub fn resize(&mut self, width: u32, height: u32, filter: ResizeFilter) -> Result<&mut Self, ImageError> {
+        self.resize_with_progress(width, height, filter, &mut |_| {})
+    }
+
+    /// Same as `resize`, reporting the completed fraction (0.0..=1.0) to `progress` after
+    /// each row of both passes
+    pub fn resize_with_progress(
+        &mut self,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+        progress: &mut dyn FnMut(f32),
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if width == 0 || height == 0 {
+            return Err(ImageError::operation(
+                "resize",
+                "Resize dimensions must be non-zero",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let x_weights = filter_weights(img.width(), width, filter);
+        let y_weights = filter_weights(img.height(), height, filter);
+        let resized = resample(&img, &x_weights, &y_weights, progress);
+        self.image = DynamicImage::ImageRgba8(resized);
+        self.record(started, Operation::Resize { width, height, filter });
+        Ok(self)
+    }
+
+    /// Resize to exactly `width` x `height`. When shrinking, each output pixel is the
+    /// coverage-weighted average of every source pixel under it (true area resampling),
+    /// which avoids the aliasing of kernel filters on large downscales. Any upscaling
+    /// falls back to a Triangle filter. Colors are filtered with premultiplied alpha, so
+    /// fully transparent pixels can't bleed dark halos into anti-aliased edges
+    pub fn resize_area(&mut self, width: u32, height: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if width == 0 || height == 0 {
+            return Err(ImageError::operation(
+                "resize_area",
+                "Resize dimensions must be non-zero",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let (src_width, src_height) = img.dimensions();
+        let (x_weights, y_weights) = if width > src_width || height > src_height {
+            let filter = ResizeFilter::Triangle;
+            (filter_weights(src_width, width, filter), filter_weights(src_height, height, filter))
+        } else {
+            (area_weights(src_width, width), area_weights(src_height, height))
+        };
+        let resized = resample(&img, &x_weights, &y_weights, &mut |_| {});
+
+        self.image = DynamicImage::ImageRgba8(resized);
+        self.record(started, Operation::ResizeArea { width, height });
+        Ok(self)
+    }
+
+    /// Same area resampling as `resize_area`, but done in strips of output rows so only
+    /// the source rows under the current strip are converted to RGBA and buffered. Peak
+    /// memory beyond the source and output stays proportional to the width, not the
+    /// height, which matters for very large downscales. Only shrinking is supported
+    pub fn resize_streaming(&mut self, width: u32, height: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (src_width, src_height) = self.image.dimensions();
+        if width == 0 || height == 0 || width > src_width || height > src_height {
+            return Err(ImageError::operation("resize_streaming", format!(
+                "Streaming resize can only shrink: cannot go from {}x{} to {}x{}",
+                src_width, src_height, width, height
+            )));
+        }
+
+        let x_weights = area_weights(src_width, width);
+        let y_weights = area_weights(src_height, height);
+        let mut resized = RgbaImage::new(width, height);
+        for strip_start in (0..height).step_by(STREAMING_STRIP_ROWS as usize) {
+            let strip_end = (strip_start + STREAMING_STRIP_ROWS).min(height);
+            let strip_weights = &y_weights[strip_start as usize..strip_end as usize];
+            let first_row = strip_weights[0][0].0;
+            let last_row = strip_weights.iter().flatten().map(|&(sy, _)| sy).max().unwrap_or(first_row);
+            let rows = last_row - first_row + 1;
+            let source = image::imageops::crop_imm(&self.image, 0, first_row, src_width, rows).to_image();
+
+            let mut horizontal = vec![[0.0f32; 4]; (width * rows) as usize];
+            for y in 0..rows {
+                for (x, weights) in x_weights.iter().enumerate() {
+                    let mut sum = [0.0f32; 4];
+                    for &(sx, weight) in weights {
+                        let pixel = premultiply(source.get_pixel(sx, y));
+                        for c in 0..4 {
+                            sum[c] += weight * pixel[c];
+                        }
+                    }
+                    horizontal[(y * width) as usize + x] = sum;
+                }
+            }
+
+            for (y, weights) in (strip_start..strip_end).zip(strip_weights) {
+                for x in 0..width {
+                    let mut sum = [0.0f32; 4];
+                    for &(sy, weight) in weights {
+                        let row = horizontal[((sy - first_row) * width + x) as usize];
+                        for c in 0..4 {
+                            sum[c] += weight * row[c];
+                        }
+                    }
+                    resized.put_pixel(x, y, unpremultiply(sum));
+                }
+            }
+        }
+
+        self.image = DynamicImage::ImageRgba8(resized);
+        self.record(started, Operation::ResizeStreaming { width, height });
+        Ok(self)
+    }
+
+    /// Resize to the multiple of `multiple` nearest each current dimension (but at least
+    /// `multiple`), as ML models with fixed strides expect. Filters with premultiplied alpha
+    /// like `resize_area`. The aspect ratio may shift slightly; use `pad_to_multiple` to
+    /// keep every pixel instead
+    pub fn resize_to_multiple(&mut self, multiple: u32, filter: ResizeFilter) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if multiple == 0 {
+            return Err(ImageError::operation(
+                "resize_to_multiple",
+                "Multiple must be non-zero",
+            ));
+        }
+
+        let snap = |len: u32| ((len as f64 / multiple as f64).round() as u32).max(1) * multiple;
+        let (width, height) = (snap(self.image.width()), snap(self.image.height()));
+        let img = self.image.to_rgba8();
+        let x_weights = filter_weights(img.width(), width, filter);
+        let y_weights = filter_weights(img.height(), height, filter);
+        let resized = resample(&img, &x_weights, &y_weights, &mut |_| {});
+        self.image = DynamicImage::ImageRgba8(resized);
+        self.record(started, Operation::ResizeToMultiple { multiple, filter });
+        Ok(self)
+    }
+
+    /// Grow the canvas on the right and bottom to the next multiple of `multiple`,
+    /// filling with transparent pixels. Existing pixels keep their coordinates
+    pub fn pad_to_multiple(&mut self, multiple: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if multiple == 0 {
+            return Err(ImageError::operation(
+                "pad_to_multiple",
+                "Multiple must be non-zero",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let width = img.width().div_ceil(multiple) * multiple;
+        let height = img.height().div_ceil(multiple) * multiple;
+        let mut padded = RgbaImage::new(width, height);
+        image::imageops::replace(&mut padded, &img, 0, 0);
+        self.image = DynamicImage::ImageRgba8(padded);
+        self.record(started, Operation::PadToMultiple { multiple });
+        Ok(self)
+    }
+
+    /// Content-aware shrink: repeatedly removes the lowest-energy (gradient magnitude)
+    /// vertical and then horizontal seams until the image is `new_width` x `new_height`.
+    /// Only shrinking is supported
+    pub fn seam_carve(&mut self, new_width: u32, new_height: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (width, height) = (self.image.width(), self.image.height());
+        if new_width == 0 || new_height == 0 || new_width > width || new_height > height {
+            return Err(ImageError::operation("seam_carve", format!(
+                "Seam carving can only shrink: cannot go from {}x{} to {}x{}",
+                width, height, new_width, new_height
+            )));
+        }
+
+        let img = remove_vertical_seams(self.image.to_rgba8(), width - new_width);
+        // Horizontal seams are vertical seams of the rotated image
+        let rotated = rotate90_pixels(&img);
+        let img = rotate270_pixels(&remove_vertical_seams(rotated, height - new_height));
+
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::SeamCarve { new_width, new_height });
+        Ok(self)
+    }
+
+    pub fn adjust_brightness(&mut self, factor: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        for pixel in self.rgba8_mut("adjust_brightness")?.pixels_mut() {
+            pixel[0] = (pixel[0] as f32 * factor).min(255.0) as u8;
+            pixel[1] = (pixel[1] as f32 * factor).min(255.0) as u8;
+            pixel[2] = (pixel[2] as f32 * factor).min(255.0) as u8;
+        }
+        self.record(started, Operation::AdjustBrightness { factor });
+        Ok(self)
+    }
+
+    /// Multiply red, green and blue by their own factors, clamping to 0..=255.
+    /// All factors at 1.0 leave the image unchanged
+    pub fn adjust_channels(&mut self, r_factor: f32, g_factor: f32, b_factor: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let factors = [r_factor, g_factor, b_factor];
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as f32 * factors[c]).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustChannels { r_factor, g_factor, b_factor });
+        Ok(self)
+    }
+
+    /// Per-channel gamma for correcting color casts: each channel becomes
+    /// 255 * (value / 255)^(1 / gamma), so a gamma above 1.0 lifts that channel's midtones
+    /// and below 1.0 deepens them, while black and white stay put. All gammas at 1.0 leave
+    /// the image unchanged
+    pub fn adjust_gamma_rgb(&mut self, r_gamma: f32, g_gamma: f32, b_gamma: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let gammas = [r_gamma, g_gamma, b_gamma];
+        if gammas.iter().any(|&gamma| gamma <= 0.0 || !gamma.is_finite()) {
+            return Err(ImageError::operation(
+                "adjust_gamma_rgb",
+                "Gamma values must be positive",
+            ));
+        }
+
+        let luts = gammas.map(|gamma| -> [u8; 256] {
+            std::array::from_fn(|v| ((v as f32 / 255.0).powf(1.0 / gamma) * 255.0).round() as u8)
+        });
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = luts[c][pixel[c] as usize];
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustGammaRgb { r_gamma, g_gamma, b_gamma });
+        Ok(self)
+    }
+
+    /// Replace each pixel's RGB with `matrix * rgb + offset`, clamped to 0..=255. Rows of
+    /// the matrix produce red, green and blue; alpha is untouched. Covers channel mixing,
+    /// sepia and similar linear color effects
+    pub fn color_matrix(&mut self, matrix: [[f32; 3]; 3], offset: [f32; 3]) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        apply_color_matrix(&mut img, &matrix, &offset);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::ColorMatrix { matrix, offset });
+        Ok(self)
+    }
+
+    /// Warm brown monochrome tone, applied as a color matrix
+    pub fn sepia(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        apply_color_matrix(&mut img, &SEPIA_MATRIX, &[0.0; 3]);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Sepia);
+        Ok(self)
+    }
+
+    /// Fast approximation of `adjust_hue`: rotates every color by `degrees` around the gray
+    /// axis of the RGB cube with a single 3x3 matrix, instead of converting each pixel
+    /// through HSL. Exact at multiples of 120 degrees; in between, strongly saturated colors
+    /// come out duller than with `adjust_hue`. Grays are unchanged
+    pub fn rotate_hue_matrix(&mut self, degrees: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (along, across) = ((1.0 - cos) / 3.0, sin / 3f32.sqrt());
+        let matrix = [
+            [cos + along, along - across, along + across],
+            [along + across, cos + along, along - across],
+            [along - across, along + across, cos + along],
+        ];
+        let mut img = self.image.to_rgba8();
+        apply_color_matrix(&mut img, &matrix, &[0.0; 3]);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::RotateHueMatrix { degrees });
+        Ok(self)
+    }
+
+    /// Gaussian blur with standard deviation `sigma`, which must be positive and finite. Edges are
+    /// clamped
+    pub fn blur(&mut self, sigma: f32) -> Result<&mut Self, ImageError> {
+        self.blur_with_progress(sigma, &mut |_| {})
+    }
+
+    /// Approximate `blur` for large sigmas: the image is downscaled so the blur runs at a
+    /// sigma of about 3, then scaled back up, which is much faster and visually very close.
+    /// Small sigmas blur at full resolution exactly like `blur`
+    pub fn blur_fast(&mut self, sigma: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(sigma.is_finite() && sigma > 0.0) {
+            return Err(ImageError::operation(
+                "blur_fast",
+                "Blur sigma must be positive and finite",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let factor = (sigma / BLUR_FAST_WORKING_SIGMA).floor().max(1.0);
+        let blurred = if factor > 1.0 {
+            let small_width = ((width as f32 / factor).round() as u32).max(1);
+            let small_height = ((height as f32 / factor).round() as u32).max(1);
+            let filter = image::imageops::FilterType::Triangle;
+            let small = image::imageops::resize(&img, small_width, small_height, filter);
+            let small = gaussian_blur(&small, sigma / factor);
+            image::imageops::resize(&small, width, height, filter)
+        } else {
+            gaussian_blur(&img, sigma)
+        };
+        self.image = DynamicImage::ImageRgba8(blurred);
+        self.record(started, Operation::BlurFast { sigma });
+        Ok(self)
+    }
+
+    /// Gaussian blur sized by pixel radius instead of sigma (sigma = radius / 3)
+    pub fn blur_radius(&mut self, radius: f32) -> Result<&mut Self, ImageError> {
+        if radius <= 0.0 {
+            return Err(ImageError::operation(
+                "blur_radius",
+                "Blur radius must be positive",
+            ));
+        }
+        self.blur(radius / 3.0)
+    }
+
+    /// Tilt-shift "miniature" effect: rows within the `focus_height` band centered on
+    /// `focus_center_y` stay sharp, and the blur (up to `sigma`) fades in smoothly over a
+    /// fifth of the image height above and below the band
+    pub fn tilt_shift(&mut self, focus_center_y: u32, focus_height: u32, sigma: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if sigma <= 0.0 {
+            return Err(ImageError::operation(
+                "tilt_shift",
+                "Blur sigma must be positive",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let blurred = gaussian_blur(&img, sigma);
+        let half_band = focus_height as f32 / 2.0;
+        let falloff = (img.height() as f32 * TILT_SHIFT_FALLOFF).max(1.0);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let distance = ((y as f32 - focus_center_y as f32).abs() - half_band).max(0.0);
+            let t = (distance / falloff).min(1.0);
+            let weight = t * t * (3.0 - 2.0 * t);
+            if weight > 0.0 {
+                let blurred_pixel = blurred.get_pixel(x, y);
+                for c in 0..4 {
+                    let value = pixel[c] as f32 * (1.0 - weight) + blurred_pixel[c] as f32 * weight;
+                    pixel[c] = value.round() as u8;
+                }
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::TiltShift { focus_center_y, focus_height, sigma });
+        Ok(self)
+    }
+
+    /// Same as `blur`, reporting the completed fraction (0.0..=1.0) to `progress` after
+    /// each row of both passes
+    pub fn blur_with_progress(
+        &mut self,
+        sigma: f32,
+        progress: &mut dyn FnMut(f32),
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(sigma.is_finite() && sigma > 0.0) {
+            return Err(ImageError::operation(
+                "blur",
+                "Blur sigma must be positive and finite",
+            ));
+        }
+
+        let kernel = gaussian_kernel(sigma);
+        let blurred = separable_filter(&self.image.to_rgba8(), &kernel, EdgeMode::Clamp, progress);
+        self.image = DynamicImage::ImageRgba8(blurred);
+        self.record(started, Operation::Blur { sigma });
+        Ok(self)
+    }
+
+    /// Convolve the image with a `kernel_width` x `kernel.len() / kernel_width` kernel given
+    /// in row-major order. Both kernel dimensions must be odd
+    pub fn convolve(
+        &mut self,
+        kernel: &[f32],
+        kernel_width: u32,
+        edge_mode: EdgeMode,
+    ) -> Result<&mut Self, ImageError> {
+        self.convolve_with_progress(kernel, kernel_width, edge_mode, &mut |_| {})
+    }
+
+    /// Same as `convolve`, reporting the completed fraction (0.0..=1.0) to `progress` after each row
+    pub fn convolve_with_progress(
+        &mut self,
+        kernel: &[f32],
+        kernel_width: u32,
+        edge_mode: EdgeMode,
+        progress: &mut dyn FnMut(f32),
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if kernel_width == 0 || kernel.is_empty() || !kernel.len().is_multiple_of(kernel_width as usize) {
+            return Err(ImageError::operation(
+                "convolve",
+                "Kernel length must be a non-zero multiple of its width",
+            ));
+        }
+        let kernel_height = (kernel.len() / kernel_width as usize) as u32;
+        if kernel_width.is_multiple_of(2) || kernel_height.is_multiple_of(2) {
+            return Err(ImageError::operation(
+                "convolve",
+                "Kernel dimensions must be odd",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let (half_w, half_h) = (kernel_width as i64 / 2, kernel_height as i64 / 2);
+        let mut convolved = RgbaImage::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0f32; 4];
+                for ky in 0..kernel_height as i64 {
+                    for kx in 0..kernel_width as i64 {
+                        let weight = kernel[(ky * kernel_width as i64 + kx) as usize];
+                        let pixel = edge_mode.sample(&img, x as i64 + kx - half_w, y as i64 + ky - half_h);
+                        for c in 0..4 {
+                            sum[c] += weight * pixel[c] as f32;
+                        }
+                    }
+                }
+                convolved.put_pixel(x, y, Rgba(sum.map(|v| v.round().clamp(0.0, 255.0) as u8)));
+            }
+            progress((y + 1) as f32 / height as f32);
+        }
+        self.image = DynamicImage::ImageRgba8(convolved);
+        self.record(started, Operation::Convolve { kernel: kernel.to_vec(), kernel_width, edge_mode });
+        Ok(self)
+    }
+
+    /// Blur by averaging each pixel with its neighbours within `radius`
+    pub fn box_blur(&mut self, radius: u32, edge_mode: EdgeMode) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let count = 2 * radius as usize + 1;
+        let kernel = vec![1.0 / count as f32; count];
+        let blurred = separable_filter(&self.image.to_rgba8(), &kernel, edge_mode, &mut |_| {});
+        self.image = DynamicImage::ImageRgba8(blurred);
+        self.record(started, Operation::BoxBlur { radius, edge_mode });
+        Ok(self)
+    }
+
+    /// Replace each channel with its median over the (2 * radius + 1) square window,
+    /// removing salt-and-pepper noise while keeping edges
+    pub fn median_filter(&mut self, radius: u32, edge_mode: EdgeMode) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let img = self.image.to_rgba8();
+        let r = radius as i64;
+        let window = ((2 * r + 1) * (2 * r + 1)) as usize;
+        let mut values: [Vec<u8>; 4] = Default::default();
+
+        let filtered = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+            for channel in values.iter_mut() {
+                channel.clear();
+            }
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let pixel = edge_mode.sample(&img, x as i64 + dx, y as i64 + dy);
+                    for c in 0..4 {
+                        values[c].push(pixel[c]);
+                    }
+                }
+            }
+            let mut out = [0u8; 4];
+            for c in 0..4 {
+                let (_, median, _) = values[c].select_nth_unstable(window / 2);
+                out[c] = *median;
+            }
+            Rgba(out)
+        });
+        self.image = DynamicImage::ImageRgba8(filtered);
+        self.record(started, Operation::MedianFilter { radius, edge_mode });
+        Ok(self)
+    }
+
+    pub fn grayscale(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        self.image = DynamicImage::ImageRgba8(self.image.grayscale().to_rgba8());
+        self.record(started, Operation::Grayscale);
+        Ok(self)
+    }
+
+     /// Invert the colors of the image
+     pub fn invert(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        for pixel in self.rgba8_mut("invert")?.pixels_mut() {
+            pixel[0] = 255 - pixel[0];
+            pixel[1] = 255 - pixel[1];
+            pixel[2] = 255 - pixel[2];
+        }
+        self.record(started, Operation::Invert);
+        Ok(self)
+    }
+
+     /// Factor > 1.0 increases contrast, < 1.0 decreases it
+    pub fn adjust_contrast(&mut self, factor: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        for pixel in self.rgba8_mut("adjust_contrast")?.pixels_mut() {
+            for c in 0..3 {
+                let scaled = (((pixel[c] as f32 / 255.0) - 0.5) * factor + 0.5) * 255.0;
+                pixel[c] = scaled.clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.record(started, Operation::AdjustContrast { factor });
+        Ok(self)
+    }
+
+    /// Roll highlights off smoothly instead of clipping them: channel values above
+    /// `knee * 255` are compressed along a tanh shoulder that leaves the knee with slope 1,
+    /// so brightness keeps rising but never slams into white. Values at or below the knee
+    /// are untouched. `knee` must be in 0.0..1.0
+    pub fn soft_clip_highlights(&mut self, knee: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(0.0..1.0).contains(&knee) {
+            return Err(ImageError::operation(
+                "soft_clip_highlights",
+                "Knee must be at least 0.0 and below 1.0",
+            ));
+        }
+
+        let headroom = 1.0 - knee;
+        let curve: [u8; 256] = std::array::from_fn(|v| {
+            let v = v as f32 / 255.0;
+            if v <= knee {
+                return (v * 255.0).round() as u8;
+            }
+            let compressed = knee + headroom * ((v - knee) / headroom).tanh();
+            (compressed * 255.0).round() as u8
+        });
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = curve[pixel[c] as usize];
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::SoftClipHighlights { knee });
+        Ok(self)
+    }
+
+    /// Lift shadows and recover highlights. A luminance mask that is 1.0 at black and
+    /// fades to 0.0 by mid-gray pulls dark pixels `shadow_lift` of the way toward
+    /// mid-gray; the mirrored mask pulls bright pixels `highlight_recover` of the way
+    /// down. Both amounts are 0.0..=1.0. Each pixel's RGB is scaled by its luminance
+    /// change, so hue and saturation are kept and mid-gray is left alone
+    pub fn shadows_highlights(&mut self, shadow_lift: f32, highlight_recover: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(0.0..=1.0).contains(&shadow_lift) || !(0.0..=1.0).contains(&highlight_recover) {
+            return Err(ImageError::operation(
+                "shadows_highlights",
+                "Shadow lift and highlight recovery must be between 0.0 and 1.0",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let luma = luminance(pixel) / 255.0;
+            let shadow = (1.0 - luma / 0.5).max(0.0);
+            let highlight = ((luma - 0.5) / 0.5).max(0.0);
+            // Smoothstep the masks so the adjustment eases out toward the midtones
+            let shadow = shadow * shadow * (3.0 - 2.0 * shadow);
+            let highlight = highlight * highlight * (3.0 - 2.0 * highlight);
+            let target = luma + shadow_lift * shadow * (0.5 - luma) - highlight_recover * highlight * (luma - 0.5);
+            for c in 0..3 {
+                let value = if luma > 0.0 { pixel[c] as f32 * target / luma } else { target * 255.0 };
+                pixel[c] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::ShadowsHighlights { shadow_lift, highlight_recover });
+        Ok(self)
+    }
+
+    /// Boost local midtone contrast ("clarity") without affecting colors.
+    /// Applies an unsharp mask with a large blur radius to luminance only; 0.0 is a no-op
+    pub fn clarity(&mut self, amount: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if amount != 0.0 {
+            let mut img = self.image.to_rgba8();
+            let (width, height) = img.dimensions();
+
+            let luma: ImageBuffer<Luma<f32>, Vec<f32>> = ImageBuffer::from_fn(width, height, |x, y| {
+                Luma([luminance(img.get_pixel(x, y)) / 255.0])
+            });
+            let blurred = gaussian_blur_f32(&luma, CLARITY_SIGMA);
+
+            for (x, y, pixel) in img.enumerate_pixels_mut() {
+                let l = luma.get_pixel(x, y)[0];
+                let detail = l - blurred.get_pixel(x, y)[0];
+                // Weight towards midtones so shadows and highlights don't clip
+                let midtone_weight = 1.0 - (2.0 * l - 1.0).powi(2);
+                let delta = amount * detail * midtone_weight * 255.0;
+                for c in 0..3 {
+                    pixel[c] = (pixel[c] as f32 + delta).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            self.image = DynamicImage::ImageRgba8(img);
+        }
+        self.record(started, Operation::Clarity { amount });
+        Ok(self)
+    }
+
+    /// Unsharp-mask sharpening applied only where the local luminance gradient (Sobel
+    /// magnitude / 4, roughly the intensity step across an edge) exceeds `threshold`,
+    /// so flat and noisy regions are left untouched
+    pub fn sharpen_edge_aware(&mut self, sigma: f32, amount: f32, threshold: u8) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if sigma <= 0.0 {
+            return Err(ImageError::operation(
+                "sharpen_edge_aware",
+                "Sharpen sigma must be positive",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let blurred = gaussian_blur(&img, sigma);
+        let gradients = sobel_gradients(&self.image.to_luma8());
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if gradients.get_pixel(x, y)[0] / 4 > threshold as u16 {
+                *pixel = unsharp_pixel(pixel, blurred.get_pixel(x, y), amount);
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::SharpenEdgeAware { sigma, amount, threshold });
+        Ok(self)
+    }
+
+    /// Reduce each color channel to `levels` evenly spaced values (at least 2)
+    pub fn posterize(&mut self, levels: u8) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if levels < 2 {
+            return Err(ImageError::operation(
+                "posterize",
+                "Posterize needs at least 2 levels",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = posterize_channel(pixel[c], levels);
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Posterize { levels });
+        Ok(self)
+    }
+
+    /// Comic-style effect: posterize to `levels`, then draw black outlines wherever the
+    /// original luminance gradient (Sobel magnitude / 4, as in `sharpen_edge_aware`)
+    /// exceeds `edge_threshold`
+    pub fn cartoonize(&mut self, levels: u8, edge_threshold: u8) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if levels < 2 {
+            return Err(ImageError::operation(
+                "cartoonize",
+                "Posterize needs at least 2 levels",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let gradients = sobel_gradients(&self.image.to_luma8());
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let is_edge = gradients.get_pixel(x, y)[0] / 4 > edge_threshold as u16;
+            for c in 0..3 {
+                pixel[c] = if is_edge { 0 } else { posterize_channel(pixel[c], levels) };
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Cartoonize { levels, edge_threshold });
+        Ok(self)
+    }
+
+    /// Glitch effect: move the red, green and blue channels independently by their
+    /// (dx, dy) offsets. Each channel is sampled from the clamped position it moved from,
+    /// so edges smear instead of leaving gaps. All-zero offsets change nothing
+    pub fn rgb_shift(
+        &mut self,
+        r_offset: (i32, i32),
+        g_offset: (i32, i32),
+        b_offset: (i32, i32),
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let offsets = [r_offset, g_offset, b_offset];
+        let shifted = ImageBuffer::from_fn(width, height, |x, y| {
+            let mut pixel = *img.get_pixel(x, y);
+            for (c, &(dx, dy)) in offsets.iter().enumerate() {
+                let sx = (x as i64 - dx as i64).clamp(0, width as i64 - 1) as u32;
+                let sy = (y as i64 - dy as i64).clamp(0, height as i64 - 1) as u32;
+                pixel[c] = img.get_pixel(sx, sy)[c];
+            }
+            pixel
+        });
+        self.image = DynamicImage::ImageRgba8(shifted);
+        let ((r_dx, r_dy), (g_dx, g_dy), (b_dx, b_dy)) = (r_offset, g_offset, b_offset);
+        self.record(started, Operation::RgbShift { r_dx, r_dy, g_dx, g_dy, b_dx, b_dy });
+        Ok(self)
+    }
+
+    /// Radial symmetry: the wedge of the image between 0 and 180/`segments` degrees
+    /// (clockwise from the positive x axis, around the center) is mirrored into a
+    /// 360/`segments` degree slice and that slice repeated `segments` times. The output
+    /// keeps the input size and is bilinearly sampled, clamping to the edges
+    pub fn kaleidoscope(&mut self, segments: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if segments == 0 {
+            return Err(ImageError::operation(
+                "kaleidoscope",
+                "Kaleidoscope needs at least 1 segment",
+            ));
+        }
+
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let cx = (width as f32 - 1.0) / 2.0;
+        let cy = (height as f32 - 1.0) / 2.0;
+        let max_x = (width as f32 - 1.0).max(0.0);
+        let max_y = (height as f32 - 1.0).max(0.0);
+        let slice = std::f32::consts::TAU / segments as f32;
+        let mirrored = ImageBuffer::from_fn(width, height, |x, y| {
+            let dx = x as f32 - cx;
+            let dy = y as f32 - cy;
+            let radius = dx.hypot(dy);
+            let mut angle = dy.atan2(dx).rem_euclid(slice);
+            if angle > slice / 2.0 {
+                angle = slice - angle;
+            }
+            let sx = (cx + radius * angle.cos()).clamp(0.0, max_x);
+            let sy = (cy + radius * angle.sin()).clamp(0.0, max_y);
+            image::imageops::interpolate_bilinear(&img, sx, sy).unwrap_or(Rgba([0, 0, 0, 0]))
+        });
+        self.image = DynamicImage::ImageRgba8(mirrored);
+        self.record(started, Operation::Kaleidoscope { segments });
+        Ok(self)
+    }
+
+    /// Tint the image by giving every pixel the same hue (degrees) and saturation (0.0..=1.0)
+    /// while keeping its HSL lightness, so the full tonal range is preserved
+    pub fn colorize(&mut self, hue: f32, saturation: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let saturation = saturation.clamp(0.0, 1.0);
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let (_, _, lightness) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+            let [r, g, b] = hsl_to_rgb(hue, saturation, lightness);
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Colorize { hue, saturation });
+        Ok(self)
+    }
+
+    /// Shift white balance: positive `amount` warms (more red, less blue), negative cools.
+    /// ±1.0 changes red and blue by 20% in opposite directions
+    pub fn adjust_temperature(&mut self, amount: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let gains = [1.0 + TEMPERATURE_SCALE * amount, 1.0, 1.0 - TEMPERATURE_SCALE * amount];
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as f32 * gains[c]).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustTemperature { amount });
+        Ok(self)
+    }
+
+    /// Scale saturation by `factor` in the given color model: 0.0 gives grayscale, 1.0 is
+    /// unchanged, > 1.0 intensifies colors. HSL keeps lightness fixed, so strong boosts stay
+    /// balanced; HSV keeps the brightest channel fixed, so desaturating lifts the darker ones
+    pub fn adjust_saturation(&mut self, factor: f32, model: ColorModel) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let [r, g, b] = match model {
+                ColorModel::Hsl => {
+                    let (hue, saturation, lightness) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+                    hsl_to_rgb(hue, (saturation * factor).clamp(0.0, 1.0), lightness)
+                }
+                ColorModel::Hsv => {
+                    let (hue, saturation, value) = rgb_to_hsv(pixel[0], pixel[1], pixel[2]);
+                    hsv_to_rgb(hue, (saturation * factor).clamp(0.0, 1.0), value)
+                }
+            };
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustSaturation { factor, model });
+        Ok(self)
+    }
+
+    /// Rotate every pixel's hue by `degrees`. Hue is defined identically in HSL and HSV,
+    /// so this needs no color model
+    pub fn adjust_hue(&mut self, degrees: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let (hue, saturation, lightness) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+            let [r, g, b] = hsl_to_rgb(hue + degrees, saturation, lightness);
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustHue { degrees });
+        Ok(self)
+    }
+
+    /// Selective color: pixels whose hue lies within `range` degrees of `target_hue` get their
+    /// hue shifted by `hue_shift` and saturation scaled by `sat_factor`. The full adjustment
+    /// applies within `range / 2` and fades out smoothly by `range`; grays are never touched
+    pub fn adjust_hue_range(
+        &mut self,
+        target_hue: f32,
+        range: f32,
+        hue_shift: f32,
+        sat_factor: f32,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if range <= 0.0 {
+            return Err(ImageError::operation(
+                "adjust_hue_range",
+                "Hue range must be positive",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let (hue, saturation, lightness) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+            if saturation == 0.0 {
+                continue;
+            }
+            let distance = (hue - target_hue).rem_euclid(360.0);
+            let distance = distance.min(360.0 - distance);
+            let t = ((distance - range / 2.0) / (range / 2.0)).clamp(0.0, 1.0);
+            let weight = 1.0 - t * t * (3.0 - 2.0 * t);
+            if weight > 0.0 {
+                let saturation = (saturation * (1.0 + (sat_factor - 1.0) * weight)).clamp(0.0, 1.0);
+                let [r, g, b] = hsl_to_rgb(hue + hue_shift * weight, saturation, lightness);
+                pixel[0] = r;
+                pixel[1] = g;
+                pixel[2] = b;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustHueRange { target_hue, range, hue_shift, sat_factor });
+        Ok(self)
+    }
+
+    /// Color splash: turn everything to grayscale except pixels whose hue lies within
+    /// `hue_range` degrees of `keep_hue`. Hues within `hue_range / 2` keep full color and
+    /// the rest fade to gray by `hue_range`, as in `adjust_hue_range`
+    pub fn selective_desaturate(&mut self, keep_hue: f32, hue_range: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if hue_range <= 0.0 {
+            return Err(ImageError::operation(
+                "selective_desaturate",
+                "Hue range must be positive",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let (hue, saturation, _) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+            let distance = (hue - keep_hue).rem_euclid(360.0);
+            let distance = distance.min(360.0 - distance);
+            let t = ((distance - hue_range / 2.0) / (hue_range / 2.0)).clamp(0.0, 1.0);
+            let weight = if saturation == 0.0 { 0.0 } else { 1.0 - t * t * (3.0 - 2.0 * t) };
+            let gray = luminance(pixel);
+            for c in 0..3 {
+                pixel[c] = (gray + (pixel[c] as f32 - gray) * weight).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::SelectiveDesaturate { keep_hue, hue_range });
+        Ok(self)
+    }
+
+    /// Scale HSV value (the brightest channel) by `factor`, keeping hue and HSV saturation.
+    /// Unlike `adjust_brightness`, a saturated color that is boosted past full value stays
+    /// the same color instead of having its channels clip unevenly
+    pub fn adjust_value(&mut self, factor: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let (hue, saturation, value) = rgb_to_hsv(pixel[0], pixel[1], pixel[2]);
+            let [r, g, b] = hsv_to_rgb(hue, saturation, (value * factor).clamp(0.0, 1.0));
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::AdjustValue { factor });
+        Ok(self)
+    }
+
+    /// Darken toward the corners: each pixel is scaled by 1 - strength * d², where d is its
+    /// distance from the center as a fraction of the half-diagonal (1.0 at the corners)
+    pub fn vignette(&mut self, strength: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let (cx, cy) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+        let max_sq = (cx * cx + cy * cy).max(f32::EPSILON);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let gain = (1.0 - strength * (dx * dx + dy * dy) / max_sq).max(0.0);
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as f32 * gain).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Vignette { strength });
+        Ok(self)
+    }
+
+    /// Flatten lens vignetting by dividing each pixel by the gain `vignette` would apply,
+    /// 1 - strength * d², so corners are brightened most. `strength` must be in 0.0..1.0;
+    /// highlights pushed past white clip
+    pub fn correct_vignette(&mut self, strength: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(0.0..1.0).contains(&strength) {
+            return Err(ImageError::operation(
+                "correct_vignette",
+                "Vignette correction strength must be at least 0.0 and below 1.0",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let (cx, cy) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+        let max_sq = (cx * cx + cy * cy).max(f32::EPSILON);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let gain = 1.0 - strength * (dx * dx + dy * dy) / max_sq;
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as f32 / gain).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::CorrectVignette { strength });
+        Ok(self)
+    }
+
+    /// Apply a named look. Presets are built from recorded operations, so the log shows
+    /// the individual steps rather than the preset
+    pub fn apply_preset(&mut self, preset: Preset) -> Result<&mut Self, ImageError> {
+        match preset {
+            Preset::WarmFilm => self
+                .adjust_temperature(0.5)?
+                .adjust_contrast(0.9)?
+                .adjust_saturation(0.9, ColorModel::Hsl)?
+                .vignette(0.3),
+            Preset::CoolCinematic => self
+                .adjust_temperature(-0.4)?
+                .adjust_contrast(1.2)?
+                .adjust_saturation(0.8, ColorModel::Hsl)?
+                .vignette(0.4),
+            Preset::Vintage => self
+                .adjust_temperature(0.3)?
+                .adjust_contrast(0.75)?
+                .adjust_saturation(0.6, ColorModel::Hsl)?
+                .vignette(0.6),
+        }
+    }
+
+    /// Remove color casts using the gray-world assumption: each channel is scaled so its
+    /// mean equals the image's mean luminance. Fully transparent pixels are ignored
+    pub fn auto_white_balance(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let mut sums = [0.0f64; 3];
+        let mut count = 0u64;
+        for pixel in img.pixels().filter(|p| p[3] > 0) {
+            for c in 0..3 {
+                sums[c] += pixel[c] as f64;
+            }
+            count += 1;
+        }
+
+        if count > 0 {
+            let means = sums.map(|sum| sum / count as f64);
+            let target = 0.2126 * means[0] + 0.7152 * means[1] + 0.0722 * means[2];
+            let gains = means.map(|mean| if mean > 0.0 { (target / mean) as f32 } else { 1.0 });
+            for pixel in img.pixels_mut() {
+                for c in 0..3 {
+                    pixel[c] = (pixel[c] as f32 * gains[c]).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            self.image = DynamicImage::ImageRgba8(img);
+        }
+        self.record(started, Operation::AutoWhiteBalance);
+        Ok(self)
+    }
+
+    /// Stretch R, G and B independently so each spans the full 0..=255 range. Channels
+    /// with a single value are left as they are; fully transparent pixels are ignored
+    pub fn normalize(&mut self) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let mut min = [u8::MAX; 3];
+        let mut max = [u8::MIN; 3];
+        for pixel in img.pixels().filter(|p| p[3] > 0) {
+            for c in 0..3 {
+                min[c] = min[c].min(pixel[c]);
+                max[c] = max[c].max(pixel[c]);
+            }
+        }
+
+        for pixel in img.pixels_mut() {
+            for c in 0..3 {
+                if max[c] > min[c] {
+                    let range = (max[c] - min[c]) as f32;
+                    let value = pixel[c].saturating_sub(min[c]) as f32 * 255.0 / range;
+                    pixel[c] = value.round().min(255.0) as u8;
+                }
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Normalize);
+        Ok(self)
+    }
+
+    /// Remove specks from a thresholded image: light pixels (luminance 128 and up) are the
+    /// foreground, and any 8-connected foreground region smaller than `min_size` pixels is
+    /// painted black
+    pub fn despeckle(&mut self, min_size: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let mask: Vec<bool> = img.pixels().map(|p| luminance(p) >= 128.0).collect();
+        let (labels, regions) = label_regions(&mask, width, height);
+        for (pixel, &label) in img.pixels_mut().zip(&labels) {
+            if label != 0 && regions[label as usize - 1].size < min_size {
+                pixel[0] = 0;
+                pixel[1] = 0;
+                pixel[2] = 0;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Despeckle { min_size });
+        Ok(self)
+    }
+
+    /// Suppress color spill from a green or blue screen: wherever the keyed channel exceeds
+    /// the larger of the other two, it is pulled down toward that limit by `amount`
+    /// (0.0 = unchanged, 1.0 = clamped to the limit)
+    pub fn despill(&mut self, key: KeyColor, amount: f32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (keyed, others) = match key {
+            KeyColor::Green => (1, [0, 2]),
+            KeyColor::Blue => (2, [0, 1]),
+        };
+        let amount = amount.clamp(0.0, 1.0);
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let limit = pixel[others[0]].max(pixel[others[1]]);
+            if pixel[keyed] > limit {
+                let excess = (pixel[keyed] - limit) as f32;
+                pixel[keyed] = (pixel[keyed] as f32 - excess * amount).round() as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::Despill { key, amount });
+        Ok(self)
+    }
+
+    /// Split toning: tint dark pixels toward `shadow_color` and bright ones toward
+    /// `highlight_color`. Only each color's hue is added, not its brightness, and its alpha
+    /// sets the strength. The tint fades to nothing at a midpoint luminance that `balance`
+    /// (-1.0..=1.0) moves down (more highlights) or up (more shadows) from mid-gray
+    pub fn split_tone(
+        &mut self,
+        shadow_color: Rgba<u8>,
+        highlight_color: Rgba<u8>,
+        balance: f32,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if !(-1.0..=1.0).contains(&balance) {
+            return Err(ImageError::operation(
+                "split_tone",
+                "Balance must be between -1.0 and 1.0",
+            ));
+        }
+
+        let tint = |color: Rgba<u8>| {
+            let luma = luminance(&color);
+            let strength = color[3] as f32 / 255.0;
+            [0, 1, 2].map(|c| (color[c] as f32 - luma) * strength)
+        };
+        let (shadow_tint, highlight_tint) = (tint(shadow_color), tint(highlight_color));
+        let midpoint = (0.5 + balance * 0.5).clamp(0.01, 0.99);
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let l = luminance(pixel) / 255.0;
+            let shadow = ((midpoint - l) / midpoint).clamp(0.0, 1.0);
+            let highlight = ((l - midpoint) / (1.0 - midpoint)).clamp(0.0, 1.0);
+            for c in 0..3 {
+                let value = pixel[c] as f32 + shadow * shadow_tint[c] + highlight * highlight_tint[c];
+                pixel[c] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::SplitTone { shadow_color, highlight_color, balance });
+        Ok(self)
+    }
+
+    /// Reduce red-eye within the given rectangle: pixels whose red channel strongly dominates
+    /// green and blue are desaturated to their luminance, leaving skin tones alone
+    pub fn remove_red_eye(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        check_region("remove_red_eye", self.image.dimensions(), x, y, width, height)?;
+
+        let mut img = self.image.to_rgba8();
+        for py in y..y + height {
+            for px in x..x + width {
+                let pixel = img.get_pixel_mut(px, py);
+                let others = (pixel[1] as f32 + pixel[2] as f32) / 2.0;
+                if pixel[0] as f32 > RED_EYE_RATIO * others.max(1.0) {
+                    let gray = luminance(pixel).round() as u8;
+                    pixel[0] = gray;
+                    pixel[1] = gray;
+                    pixel[2] = gray;
+                }
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::RemoveRedEye { x, y, width, height });
+        Ok(self)
+    }
+
+    /// Fill the pixels where `mask` is light (luminance >= 128) from the surrounding image,
+    /// for dust and scratch removal. The hole is filled from its border inwards in order of
+    /// distance, fast-marching style: a priority queue holds the masked pixels on the
+    /// boundary, and each one popped is set to the inverse-distance-weighted average of the
+    /// known pixels within `radius`, becomes known, and queues its masked neighbours. Works
+    /// best on small or thin regions
+    pub fn inpaint(&mut self, mask: &DynamicImage, radius: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (width, height) = self.image.dimensions();
+        if mask.dimensions() != (width, height) {
+            return Err(ImageError::operation("inpaint", format!(
+                "Mask is {}x{} but the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                width,
+                height
+            )));
+        }
+        if radius == 0 {
+            return Err(ImageError::operation(
+                "inpaint",
+                "Inpaint radius must be positive",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let mask = mask.to_rgba8();
+        let mut known: Vec<bool> = mask.pixels().map(|p| luminance(p) < 128.0).collect();
+        let index = |x: u32, y: u32| (y * width + x) as usize;
+        let neighbours = |x: u32, y: u32| {
+            [(-1i64, 0i64), (1, 0), (0, -1), (0, 1)].into_iter().filter_map(move |(dx, dy)| {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                (nx >= 0 && ny >= 0 && nx < width as i64 && ny < height as i64).then_some((nx as u32, ny as u32))
+            })
+        };
+
+        // Arrival time is the distance from the known region (0 inside it). The queue is
+        // keyed on its bit pattern, which orders like the value for non-negative floats
+        let mut arrival: Vec<f32> = known.iter().map(|&k| if k { 0.0 } else { f32::INFINITY }).collect();
+        let mut queue = BinaryHeap::new();
+        for y in 0..height {
+            for x in 0..width {
+                if !known[index(x, y)] && neighbours(x, y).any(|(nx, ny)| known[index(nx, ny)]) {
+                    arrival[index(x, y)] = 1.0;
+                    queue.push(Reverse((1.0f32.to_bits(), x, y)));
+                }
+            }
+        }
+
+        let radius = radius as i64;
+        while let Some(Reverse((time, x, y))) = queue.pop() {
+            // Skip entries superseded by an earlier arrival
+            if known[index(x, y)] || f32::from_bits(time) > arrival[index(x, y)] {
+                continue;
+            }
+
+            let mut sum = [0.0f32; 4];
+            let mut total = 0.0f32;
+            for ny in (y as i64 - radius).max(0)..=(y as i64 + radius).min(height as i64 - 1) {
+                for nx in (x as i64 - radius).max(0)..=(x as i64 + radius).min(width as i64 - 1) {
+                    let (dx, dy) = (nx - x as i64, ny - y as i64);
+                    if dx * dx + dy * dy > radius * radius || !known[index(nx as u32, ny as u32)] {
+                        continue;
+                    }
+                    let weight = 1.0 / ((dx * dx + dy * dy) as f32).sqrt();
+                    let pixel = img.get_pixel(nx as u32, ny as u32);
+                    for c in 0..4 {
+                        sum[c] += weight * pixel[c] as f32;
+                    }
+                    total += weight;
+                }
+            }
+            img.put_pixel(x, y, Rgba(sum.map(|v| (v / total).round().clamp(0.0, 255.0) as u8)));
+            known[index(x, y)] = true;
+
+            for (nx, ny) in neighbours(x, y) {
+                if known[index(nx, ny)] {
+                    continue;
+                }
+                // Solve the 4-neighbour eikonal equation |grad T| = 1 from known neighbours
+                let known_time = |a: Option<(u32, u32)>, b: Option<(u32, u32)>| {
+                    [a, b]
+                        .into_iter()
+                        .flatten()
+                        .filter(|&(px, py)| known[index(px, py)])
+                        .map(|(px, py)| arrival[index(px, py)])
+                        .fold(f32::INFINITY, f32::min)
+                };
+                let horizontal = known_time(nx.checked_sub(1).map(|px| (px, ny)), (nx + 1 < width).then_some((nx + 1, ny)));
+                let vertical = known_time(ny.checked_sub(1).map(|py| (nx, py)), (ny + 1 < height).then_some((nx, ny + 1)));
+                let (low, high) = (horizontal.min(vertical), horizontal.max(vertical));
+                let time = if high - low >= 1.0 {
+                    low + 1.0
+                } else {
+                    (low + high + (2.0 - (high - low) * (high - low)).sqrt()) / 2.0
+                };
+                if time < arrival[index(nx, ny)] {
+                    arrival[index(nx, ny)] = time;
+                    queue.push(Reverse((time.to_bits(), nx, ny)));
+                }
+            }
+        }
+
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record_unrecordable(started, "inpaint");
+        Ok(self)
+    }
+
+    /// Color grade the image through an Adobe `.cube` 3D LUT, trilinearly interpolated
+    pub fn apply_lut_cube(&mut self, path: &str) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let text = std::fs::read_to_string(path).map_err(|e| ImageError::LoadError(e.to_string()))?;
+        let lut = CubeLut::parse(&text)?;
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let graded = lut.lookup([pixel[0], pixel[1], pixel[2]].map(|v| v as f32 / 255.0));
+            for c in 0..3 {
+                pixel[c] = (graded[c] * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::ApplyLutCube { path: path.to_string() });
+        Ok(self)
+    }
+
+    /// Draw `text` inside `rect` (x, y, width, height) over a `bg` box, word-wrapped to the
+    /// box width. Lines that don't fit vertically are dropped and the last visible line is
+    /// cut short with an ellipsis. `scale` is the font size in pixels
+    pub fn draw_caption(
+        &mut self,
+        text: &str,
+        rect: (u32, u32, u32, u32),
+        font: &impl Font,
+        scale: f32,
+        text_color: Rgba<u8>,
+        bg: Rgba<u8>,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (x, y, width, height) = rect;
+        check_region("draw_caption", self.image.dimensions(), x, y, width, height)?;
+
+        let mut img = self.image.to_rgba8();
+        for py in y..y + height {
+            for px in x..x + width {
+                blend_pixel(img.get_pixel_mut(px, py), bg);
+            }
+        }
+
+        let scaled = font.as_scaled(scale);
+        let line_height = (scaled.ascent() - scaled.descent() + scaled.line_gap()).ceil().max(1.0);
+        let inner_width = width.saturating_sub(2 * CAPTION_PADDING) as f32;
+        let inner_height = height.saturating_sub(2 * CAPTION_PADDING) as f32;
+        let max_lines = (inner_height / line_height).floor() as usize;
+
+        let mut lines = wrap_text(text, font, scale, inner_width);
+        if lines.len() > max_lines {
+            lines.truncate(max_lines);
+            if let Some(last) = lines.last_mut() {
+                while !last.is_empty() && text_width(font, scale, &format!("{}...", last)) > inner_width {
+                    last.pop();
+                }
+                *last = format!("{}...", last.trim_end());
+            }
+        }
+
+        let clip = (x, y, x + width, y + height);
+        for (i, line) in lines.iter().enumerate() {
+            let line_x = (x + CAPTION_PADDING) as f32;
+            let line_y = (y + CAPTION_PADDING) as f32 + i as f32 * line_height;
+            draw_text_line(&mut img, line, (line_x, line_y), font, scale, text_color, clip);
+        }
+
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record_unrecordable(started, "draw_caption");
+        Ok(self)
+    }
+
+    /// Draw `text` on one line at the largest font size that fits inside `rect`
+    /// (x, y, width, height), found by binary search on the scale, centered in the box
+    pub fn draw_text_fit(
+        &mut self,
+        text: &str,
+        rect: (u32, u32, u32, u32),
+        font: &impl Font,
+        color: Rgba<u8>,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (x, y, width, height) = rect;
+        check_region("draw_text_fit", self.image.dimensions(), x, y, width, height)?;
+
+        let line_height = |scale: f32| {
+            let scaled = font.as_scaled(scale);
+            scaled.ascent() - scaled.descent()
+        };
+        let fits = |scale: f32| text_width(font, scale, text) <= width as f32 && line_height(scale) <= height as f32;
+        let (mut low, mut high) = (0.0f32, 2.0 * height as f32);
+        for _ in 0..24 {
+            let mid = (low + high) / 2.0;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        let mut img = self.image.to_rgba8();
+        if low > 0.0 {
+            let origin = (
+                x as f32 + (width as f32 - text_width(font, low, text)) / 2.0,
+                y as f32 + (height as f32 - line_height(low)) / 2.0,
+            );
+            draw_text_line(&mut img, text, origin, font, low, color, (x, y, x + width, y + height));
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record_unrecordable(started, "draw_text_fit");
+        Ok(self)
+    }
+
+    /// Recolor by luminance through a multi-stop color ramp. `stops` are (position, color)
+    /// pairs sorted by position, starting at 0.0 and ending at 1.0. Alpha is preserved
+    pub fn gradient_map(&mut self, stops: &[(f32, Rgba<u8>)]) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if stops.len() < 2 {
+            return Err(ImageError::operation(
+                "gradient_map",
+                "Gradient map needs at least two stops",
+            ));
+        }
+        if stops.windows(2).any(|w| w[0].0 > w[1].0) {
+            return Err(ImageError::operation(
+                "gradient_map",
+                "Gradient map stops must be sorted by position",
+            ));
+        }
+        if stops[0].0 != 0.0 || stops[stops.len() - 1].0 != 1.0 {
+            return Err(ImageError::operation(
+                "gradient_map",
+                "Gradient map stops must start at 0.0 and end at 1.0",
+            ));
+        }
+
+        let ramp: Vec<[u8; 3]> = (0..256)
+            .map(|i| {
+                let t = i as f32 / 255.0;
+                let upper = stops.iter().position(|(pos, _)| *pos >= t).unwrap_or(stops.len() - 1).max(1);
+                let ((p0, c0), (p1, c1)) = (stops[upper - 1], stops[upper]);
+                let f = if p1 > p0 { (t - p0) / (p1 - p0) } else { 1.0 };
+                [0, 1, 2].map(|c| (c0[c] as f32 + (c1[c] as f32 - c0[c] as f32) * f).round() as u8)
+            })
+            .collect();
+
+        let mut img = self.image.to_rgba8();
+        for pixel in img.pixels_mut() {
+            let [r, g, b] = ramp[luminance(pixel).round() as usize];
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::GradientMap { stops: stops.to_vec() });
+        Ok(self)
+    }
+
+    /// Run `op` on a copy of the image and blend the result back in, weighting each pixel by
+    /// the luminance of `mask` (white = fully affected, black = untouched). The mask must match
+    /// the image's dimensions, and `op` must not change them
+    pub fn apply_masked<F>(&mut self, mask: &DynamicImage, op: F) -> Result<&mut Self, ImageError>
+    where
+        F: FnOnce(&mut ImageProcessor) -> Result<(), ImageError>,
+    {
+        let started = self.start_timer();
+        let dimensions = (self.image.width(), self.image.height());
+        if mask.dimensions() != dimensions {
+            return Err(ImageError::operation("apply_masked", format!(
+                "Mask is {}x{} but the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                dimensions.0,
+                dimensions.1
+            )));
+        }
+
+        let mut processed = ImageProcessor::from_dynamic_image(self.image.clone());
+        op(&mut processed)?;
+        if processed.image.dimensions() != dimensions {
+            return Err(ImageError::operation(
+                "apply_masked",
+                "Masked operation must not change the image dimensions",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        let processed = processed.image.to_rgba8();
+        let mask = mask.to_rgba8();
+        for ((pixel, effect), mask_pixel) in img.pixels_mut().zip(processed.pixels()).zip(mask.pixels()) {
+            let weight = luminance(mask_pixel) / 255.0;
+            for c in 0..4 {
+                let value = pixel[c] as f32 * (1.0 - weight) + effect[c] as f32 * weight;
+                pixel[c] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record_unrecordable(started, "apply_masked");
+        Ok(self)
+    }
+
+    /// Run `op` only inside the polygon through `points` (at least 3, implicitly closed),
+    /// using an anti-aliased mask so the effect blends smoothly at the polygon's edges
+    pub fn apply_in_polygon<F>(&mut self, points: &[(i32, i32)], op: F) -> Result<&mut Self, ImageError>
+    where
+        F: FnOnce(&mut ImageProcessor) -> Result<(), ImageError>,
+    {
+        let mut polygon: Vec<Point<i32>> = points.iter().map(|&(x, y)| Point::new(x, y)).collect();
+        if polygon.len() > 1 && polygon.first() == polygon.last() {
+            polygon.pop();
+        }
+        if polygon.len() < 3 {
+            return Err(ImageError::operation(
+                "apply_in_polygon",
+                "Polygon needs at least 3 distinct points",
+            ));
+        }
+
+        let mut mask = ImageBuffer::from_pixel(self.image.width(), self.image.height(), Luma([0u8]));
+        draw_antialiased_polygon_mut(&mut mask, &polygon, Luma([255u8]), interpolate);
+        self.apply_masked(&DynamicImage::ImageLuma8(mask), op)
+    }
+
+    /// Paint bucket: replace the 4-connected region around (`x`, `y`) whose pixels are within
+    /// `tolerance` of the seed pixel on every channel with `fill`. Uses a scanline fill with an
+    /// explicit stack, so large regions can't overflow the call stack
+    pub fn flood_fill(&mut self, x: u32, y: u32, fill: Rgba<u8>, tolerance: u8) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        if x >= width || y >= height {
+            return Err(ImageError::operation(
+                "flood_fill",
+                "Flood fill seed is outside the image",
+            ));
+        }
+
+        let seed = *img.get_pixel(x, y);
+        let mut visited = vec![false; (width * height) as usize];
+        let matches = |img: &RgbaImage, visited: &[bool], x: u32, y: u32| {
+            !visited[(y * width + x) as usize]
+                && (0..4).all(|c| img.get_pixel(x, y)[c].abs_diff(seed[c]) <= tolerance)
+        };
+
+        let mut stack = vec![(x, y)];
+        while let Some((x, y)) = stack.pop() {
+            if !matches(&img, &visited, x, y) {
+                continue;
+            }
+            let mut left = x;
+            while left > 0 && matches(&img, &visited, left - 1, y) {
+                left -= 1;
+            }
+            let mut right = x;
+            while right + 1 < width && matches(&img, &visited, right + 1, y) {
+                right += 1;
+            }
+            for span_x in left..=right {
+                visited[(y * width + span_x) as usize] = true;
+                img.put_pixel(span_x, y, fill);
+            }
+
+            for row in [y.checked_sub(1), (y + 1 < height).then_some(y + 1)].into_iter().flatten() {
+                let mut in_run = false;
+                for span_x in left..=right {
+                    let hit = matches(&img, &visited, span_x, row);
+                    if hit && !in_run {
+                        stack.push((span_x, row));
+                    }
+                    in_run = hit;
+                }
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::FloodFill { x, y, fill, tolerance });
+        Ok(self)
+    }
+
+    /// Draw a hard-edged circle of `radius` around `center`, as a disc when `filled` or
+    /// otherwise as a one-pixel outline, blending `color` by its alpha. Parts outside the
+    /// image are clipped
+    pub fn draw_circle(
+        &mut self,
+        center: (i32, i32),
+        radius: i32,
+        color: Rgba<u8>,
+        filled: bool,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if radius < 0 {
+            return Err(ImageError::operation(
+                "draw_circle",
+                "Circle radius must not be negative",
+            ));
+        }
+
+        let mut coverage = ImageBuffer::from_pixel(self.image.width(), self.image.height(), Luma([0u8]));
+        if filled {
+            draw_filled_circle_mut(&mut coverage, center, radius, Luma([255u8]));
+        } else {
+            draw_hollow_circle_mut(&mut coverage, center, radius, Luma([255u8]));
+        }
+        let mut img = self.image.to_rgba8();
+        blend_coverage(&mut img, &coverage, color);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::DrawCircle { center, radius, color, filled });
+        Ok(self)
+    }
+
+    /// Draw an anti-aliased one-pixel line from `start` to `end`. Each pixel's coverage
+    /// scales `color`'s alpha before it is blended over the image, so edges fade smoothly.
+    /// Parts of the line outside the image are clipped
+    pub fn draw_line_aa(&mut self, start: (i32, i32), end: (i32, i32), color: Rgba<u8>) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut coverage = ImageBuffer::from_pixel(self.image.width(), self.image.height(), Luma([0u8]));
+        draw_antialiased_line_segment_mut(&mut coverage, start, end, Luma([255u8]), interpolate);
+        let mut img = self.image.to_rgba8();
+        blend_coverage(&mut img, &coverage, color);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::DrawLineAa { start, end, color });
+        Ok(self)
+    }
+
+    /// Draw an anti-aliased circle of `radius` around `center`, as a disc when `filled` or
+    /// otherwise as a one-pixel outline. Coverage comes from each pixel center's distance
+    /// to the edge and is blended like `draw_line_aa`
+    pub fn draw_circle_aa(
+        &mut self,
+        center: (i32, i32),
+        radius: u32,
+        color: Rgba<u8>,
+        filled: bool,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let (width, height) = self.image.dimensions();
+        let (cx, cy) = (center.0 as i64, center.1 as i64);
+        let reach = radius as i64 + 1;
+        let mut coverage = ImageBuffer::from_pixel(width, height, Luma([0u8]));
+        for y in (cy - reach).max(0)..(cy + reach + 1).min(height as i64) {
+            for x in (cx - reach).max(0)..(cx + reach + 1).min(width as i64) {
+                let distance = (((x - cx).pow(2) + (y - cy).pow(2)) as f32).sqrt();
+                let amount = if filled {
+                    radius as f32 + 0.5 - distance
+                } else {
+                    1.0 - (distance - radius as f32).abs()
+                };
+                coverage.put_pixel(x as u32, y as u32, Luma([(amount.clamp(0.0, 1.0) * 255.0).round() as u8]));
+            }
+        }
+        let mut img = self.image.to_rgba8();
+        blend_coverage(&mut img, &coverage, color);
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::DrawCircleAa { center, radius, color, filled });
+        Ok(self)
+    }
+
+    /// Draw a reference grid: one-pixel vertical and horizontal lines at every multiple of
+    /// `spacing` (starting at 0), blended with `color`'s alpha. Pixels between lines are untouched
+    pub fn draw_grid(&mut self, spacing: u32, color: Rgba<u8>) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        if spacing == 0 {
+            return Err(ImageError::operation(
+                "draw_grid",
+                "Grid spacing must be non-zero",
+            ));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if x.is_multiple_of(spacing) || y.is_multiple_of(spacing) {
+                blend_pixel(pixel, color);
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::DrawGrid { spacing, color });
+        Ok(self)
+    }
+
+    /// Rule-of-thirds guides: two vertical and two horizontal one-pixel lines dividing the
+    /// image into a 3x3 grid, blended with `color`'s alpha
+    pub fn draw_thirds(&mut self, color: Rgba<u8>) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let mut img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let columns = [width / 3, 2 * width / 3];
+        let rows = [height / 3, 2 * height / 3];
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if columns.contains(&x) || rows.contains(&y) {
+                blend_pixel(pixel, color);
+            }
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record(started, Operation::DrawThirds { color });
+        Ok(self)
+    }
+
+    /// Overlay another image at specified coordinates
+    pub fn overlay_image(
+        &mut self,
+        overlay: &DynamicImage,
+        x: u32,
+        y: u32,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        check_region("overlay_image", self.image.dimensions(), x, y, overlay.width(), overlay.height())?;
+
+        // Convert both images to RGBA
+        let mut base: ImageBuffer<Rgba<u8>, Vec<u8>> = self.image.to_rgba8();
+        let overlay = overlay.to_rgba8();
+
+        // Iterate through overlay pixels and blend them with base image
+        for (i, j, pixel) in overlay.enumerate_pixels() {
+            let x_pos = x + i;
+            let y_pos = y + j;
+
+            if pixel[3] > 0 {  // Only blend non-transparent pixels
+                if pixel[3] == 255 {
+                    base.put_pixel(x_pos, y_pos, *pixel);
+                } else {
+                    let base_pixel = base.get_pixel(x_pos, y_pos);
+                    let alpha = pixel[3] as f32 / 255.0;
+                    let new_pixel = Rgba([
+                        ((1.0 - alpha) * base_pixel[0] as f32 + alpha * pixel[0] as f32) as u8,
+                        ((1.0 - alpha) * base_pixel[1] as f32 + alpha * pixel[1] as f32) as u8,
+                        ((1.0 - alpha) * base_pixel[2] as f32 + alpha * pixel[2] as f32) as u8,
+                        255,
+                    ]);
+                    base.put_pixel(x_pos, y_pos, new_pixel);
+                }
+            }
+        }
+
+        self.image = DynamicImage::ImageRgba8(base);
+        self.record_unrecordable(started, "overlay_image");
+        Ok(self)
+    }
+
+    /// Like `overlay_image`, but blends in linear light rather than on gamma-encoded sRGB
+    /// values, so anti-aliased and semi-transparent edges don't come out too dark
+    pub fn overlay_image_linear(
+        &mut self,
+        overlay: &DynamicImage,
+        x: u32,
+        y: u32,
+    ) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        check_region("overlay_image_linear", self.image.dimensions(), x, y, overlay.width(), overlay.height())?;
+
+        let mut base = self.image.to_rgba8();
         let overlay = overlay.to_rgba8();
+        for (i, j, pixel) in overlay.enumerate_pixels() {
+            if pixel[3] == 0 {
+                continue;
+            }
+            let base_pixel = base.get_pixel_mut(x + i, y + j);
+            let alpha = pixel[3] as f32 / 255.0;
+            for c in 0..3 {
+                let blended = (1.0 - alpha) * srgb_to_linear(base_pixel[c]) + alpha * srgb_to_linear(pixel[c]);
+                base_pixel[c] = linear_to_srgb(blended);
+            }
+            base_pixel[3] = 255;
+        }
+
+        self.image = DynamicImage::ImageRgba8(base);
+        self.record_unrecordable(started, "overlay_image_linear");
+        Ok(self)
+    }
+
+    /// Combine `src` placed at (`x`, `y`) with the image using a Porter-Duff operator,
+    /// computed on premultiplied alpha. Only the pixels under `src` are affected, so
+    /// operators like `SrcIn` don't clear the rest of the image
+    pub fn composite(&mut self, src: &DynamicImage, x: u32, y: u32, op: PorterDuff) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        check_region("composite", self.image.dimensions(), x, y, src.width(), src.height())?;
+
+        let mut base = self.image.to_rgba8();
+        let src = src.to_rgba8();
+        for (i, j, src_pixel) in src.enumerate_pixels() {
+            let dst_pixel = base.get_pixel_mut(x + i, y + j);
+            let src_alpha = src_pixel[3] as f32 / 255.0;
+            let dst_alpha = dst_pixel[3] as f32 / 255.0;
+            let (src_weight, dst_weight) = op.factors(src_alpha, dst_alpha);
+            let out_alpha = src_weight * src_alpha + dst_weight * dst_alpha;
+            for c in 0..3 {
+                let premultiplied = src_weight * src_alpha * src_pixel[c] as f32 + dst_weight * dst_alpha * dst_pixel[c] as f32;
+                dst_pixel[c] = if out_alpha > 0.0 {
+                    (premultiplied / out_alpha).round().clamp(0.0, 255.0) as u8
+                } else {
+                    0
+                };
+            }
+            dst_pixel[3] = (out_alpha * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        self.image = DynamicImage::ImageRgba8(base);
+        self.record_unrecordable(started, "composite");
+        Ok(self)
+    }
+
+    /// Paste another processor's image at specified coordinates, alpha-blending it
+    pub fn paste(&mut self, other: &ImageProcessor, x: u32, y: u32) -> Result<&mut Self, ImageError> {
+        self.overlay_image(other.get_image(), x, y)
+    }
+
+    /// Paste another processor's image at specified coordinates, overwriting the pixels beneath it
+    pub fn paste_replace(&mut self, other: &ImageProcessor, x: u32, y: u32) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let overlay = other.get_image();
+        check_region("paste_replace", self.image.dimensions(), x, y, overlay.width(), overlay.height())?;
+
+        let mut base = self.image.to_rgba8();
+        image::imageops::replace(&mut base, &overlay.to_rgba8(), x as i64, y as i64);
+        self.image = DynamicImage::ImageRgba8(base);
+        self.record_unrecordable(started, "paste_replace");
+        Ok(self)
+    }
+
+    /// Scale `patch` as a nine-patch to `target` (width, height) and overlay it centred on
+    /// the image. `insets` are the (left, top, right, bottom) border widths: corners are
+    /// copied unscaled, the edges stretch along their length and the center both ways
+    pub fn nine_patch(
+        &mut self,
+        patch: &DynamicImage,
+        insets: (u32, u32, u32, u32),
+        target: (u32, u32),
+    ) -> Result<&mut Self, ImageError> {
+        let (left, top, right, bottom) = insets;
+        let (target_width, target_height) = target;
+        let (patch_width, patch_height) = patch.dimensions();
+        let (horizontal, vertical) = (left as u64 + right as u64, top as u64 + bottom as u64);
+        if horizontal > patch_width as u64 || vertical > patch_height as u64 {
+            return Err(ImageError::operation("nine_patch", format!(
+                "Insets ({}, {}, {}, {}) don't fit a {}x{} patch",
+                left, top, right, bottom, patch_width, patch_height
+            )));
+        }
+        if horizontal > target_width as u64 || vertical > target_height as u64 {
+            return Err(ImageError::operation("nine_patch", format!(
+                "Target {}x{} is smaller than the patch borders",
+                target_width, target_height
+            )));
+        }
+        if target_width > self.image.width() || target_height > self.image.height() {
+            return Err(ImageError::operation(
+                "nine_patch",
+                "Nine-patch target exceeds base image bounds",
+            ));
+        }
+
+        let patch = patch.to_rgba8();
+        let src_xs = [0, left, patch_width - right, patch_width];
+        let src_ys = [0, top, patch_height - bottom, patch_height];
+        let dst_xs = [0, left, target_width - right, target_width];
+        let dst_ys = [0, top, target_height - bottom, target_height];
+        let mut scaled = RgbaImage::new(target_width, target_height);
+        for row in 0..3 {
+            for col in 0..3 {
+                let (src_width, src_height) = (src_xs[col + 1] - src_xs[col], src_ys[row + 1] - src_ys[row]);
+                let (dst_width, dst_height) = (dst_xs[col + 1] - dst_xs[col], dst_ys[row + 1] - dst_ys[row]);
+                if src_width == 0 || src_height == 0 || dst_width == 0 || dst_height == 0 {
+                    continue;
+                }
+                let piece = image::imageops::crop_imm(&patch, src_xs[col], src_ys[row], src_width, src_height).to_image();
+                let piece = if (src_width, src_height) == (dst_width, dst_height) {
+                    piece
+                } else {
+                    image::imageops::resize(&piece, dst_width, dst_height, image::imageops::FilterType::Triangle)
+                };
+                image::imageops::replace(&mut scaled, &piece, dst_xs[col] as i64, dst_ys[row] as i64);
+            }
+        }
+
+        let x = (self.image.width() - target_width) / 2;
+        let y = (self.image.height() - target_height) / 2;
+        self.overlay_image(&DynamicImage::ImageRgba8(scaled), x, y)
+    }
+
+    /// Mean color of the image. RGB is weighted by alpha so transparent pixels don't pull
+    /// the result towards their (invisible) color; alpha is the plain mean
+    pub fn average_color(&self) -> Rgba<u8> {
+        let img = self.image.to_rgba8();
+        let mut sum = [0.0f64; 3];
+        let mut alpha_sum = 0.0f64;
+        for pixel in img.pixels() {
+            let alpha = pixel[3] as f64;
+            for c in 0..3 {
+                sum[c] += pixel[c] as f64 * alpha;
+            }
+            alpha_sum += alpha;
+        }
+        if alpha_sum == 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        let pixel_count = (img.width() as f64 * img.height() as f64).max(1.0);
+        let [r, g, b] = sum.map(|v| (v / alpha_sum).round() as u8);
+        Rgba([r, g, b, (alpha_sum / pixel_count).round() as u8])
+    }
+
+    /// Most common color after coarse quantization (32 levels per channel), returned as
+    /// the mean of the pixels in the winning bucket. Fully transparent pixels are ignored
+    pub fn dominant_color(&self) -> Rgba<u8> {
+        let img = self.image.to_rgba8();
+        let mut buckets: HashMap<[u8; 4], (u64, [u64; 4])> = HashMap::new();
+        for pixel in img.pixels().filter(|p| p[3] > 0) {
+            let entry = buckets.entry(pixel.0.map(|v| v >> 3)).or_insert((0, [0; 4]));
+            entry.0 += 1;
+            for c in 0..4 {
+                entry.1[c] += pixel[c] as u64;
+            }
+        }
+
+        // Break ties on the bucket key so the result doesn't depend on hash order
+        buckets
+            .into_iter()
+            .max_by_key(|(key, (count, _))| (*count, *key))
+            .map(|(_, (count, sum))| Rgba(sum.map(|v| (v as f64 / count as f64).round() as u8)))
+            .unwrap_or(Rgba([0, 0, 0, 0]))
+    }
+
+    /// Likely background color for `crop_to_content` and friends: the most common color
+    /// among the border pixels (every pixel of the outer rows and columns, corners
+    /// included). Colors are bucketed like `dominant_color` so compression noise along
+    /// the edge still counts as one color; the winning bucket's average is returned
+    pub fn detect_background(&self) -> Rgba<u8> {
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let mut buckets: HashMap<[u8; 4], (u64, [u64; 4])> = HashMap::new();
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if x != 0 && y != 0 && x != width - 1 && y != height - 1 {
+                continue;
+            }
+            let entry = buckets.entry(pixel.0.map(|v| v >> 3)).or_insert((0, [0; 4]));
+            entry.0 += 1;
+            for c in 0..4 {
+                entry.1[c] += pixel[c] as u64;
+            }
+        }
+
+        buckets
+            .into_iter()
+            .max_by_key(|(key, (count, _))| (*count, *key))
+            .map(|(_, (count, sum))| Rgba(sum.map(|v| (v as f64 / count as f64).round() as u8)))
+            .unwrap_or(Rgba([0, 0, 0, 0]))
+    }
+
+    /// Mean structural similarity (SSIM) of the luminance against `other`, over 8x8 windows
+    /// stepped by 4 pixels. 1.0 means identical; images must have the same dimensions
+    pub fn ssim(&self, other: &DynamicImage) -> Result<f64, ImageError> {
+        let (width, height) = (self.image.width(), self.image.height());
+        if (other.width(), other.height()) != (width, height) {
+            return Err(ImageError::operation("ssim", format!(
+                "SSIM needs equal dimensions: {}x{} vs {}x{}",
+                width,
+                height,
+                other.width(),
+                other.height()
+            )));
+        }
+        if width == 0 || height == 0 {
+            return Err(ImageError::operation(
+                "ssim",
+                "SSIM is undefined for an empty image",
+            ));
+        }
+
+        let to_luma = |img: &DynamicImage| -> Vec<f64> {
+            img.to_rgba8().pixels().map(|p| luminance(p) as f64).collect()
+        };
+        let (a, b) = (to_luma(&self.image), to_luma(other));
+        let (c1, c2) = ((0.01f64 * 255.0).powi(2), (0.03f64 * 255.0).powi(2));
+        let window_w = width.min(8);
+        let window_h = height.min(8);
+
+        let mut total = 0.0;
+        let mut windows = 0u32;
+        let mut y = 0;
+        while y + window_h <= height {
+            let mut x = 0;
+            while x + window_w <= width {
+                let n = (window_w * window_h) as f64;
+                let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+                for wy in y..y + window_h {
+                    for wx in x..x + window_w {
+                        let i = (wy * width + wx) as usize;
+                        sum_a += a[i];
+                        sum_b += b[i];
+                        sum_aa += a[i] * a[i];
+                        sum_bb += b[i] * b[i];
+                        sum_ab += a[i] * b[i];
+                    }
+                }
+                let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+                let var_a = sum_aa / n - mean_a * mean_a;
+                let var_b = sum_bb / n - mean_b * mean_b;
+                let covariance = sum_ab / n - mean_a * mean_b;
+                total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2))
+                    / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+                windows += 1;
+                x += 4;
+            }
+            y += 4;
+        }
+        Ok(total / windows as f64)
+    }
+
+    /// Per-channel histograms: counts of each value 0..=255 for red, green and blue
+    pub fn histogram(&self) -> [[u32; 256]; 3] {
+        let mut histogram = [[0u32; 256]; 3];
+        for pixel in self.image.to_rgba8().pixels() {
+            for c in 0..3 {
+                histogram[c][pixel[c] as usize] += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Draw the red, green and blue histograms as overlaid bars on black, scaled so the
+    /// tallest bar fills the height. Each column sums the bins that fall under it, and
+    /// where channels overlap their colors add up
+    pub fn render_histogram(&self, width: u32, height: u32) -> DynamicImage {
+        let histogram = self.histogram();
+        let columns: Vec<[u64; 3]> = (0..width as usize)
+            .map(|x| {
+                let start = x * 256 / width as usize;
+                let end = ((x + 1) * 256 / width as usize).max(start + 1);
+                histogram.map(|counts| counts[start..end].iter().map(|&n| n as u64).sum())
+            })
+            .collect();
+        let peak = columns.iter().flatten().copied().max().unwrap_or(0).max(1);
+
+        let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+        for (x, column) in columns.iter().enumerate() {
+            for (c, &count) in column.iter().enumerate() {
+                let bar = (count * height as u64).div_ceil(peak) as u32;
+                for y in height - bar..height {
+                    canvas.get_pixel_mut(x as u32, y)[c] = 255;
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(canvas)
+    }
+
+    /// Encode a BlurHash: a short string describing a blurred version of the image with
+    /// `x_components` x `y_components` cosine components (each clamped to 1..=9), for
+    /// placeholders while the real image loads. Decode with `from_blurhash`
+    pub fn blurhash(&self, x_components: u32, y_components: u32) -> String {
+        let (x_components, y_components) = (x_components.clamp(1, 9), y_components.clamp(1, 9));
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        let linear: Vec<[f32; 3]> = img
+            .pixels()
+            .map(|p| [srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2])])
+            .collect();
+
+        let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+        for j in 0..y_components {
+            for i in 0..x_components {
+                let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+                let mut sum = [0.0f32; 3];
+                for y in 0..height {
+                    let basis_y = (std::f32::consts::PI * j as f32 * y as f32 / height as f32).cos();
+                    for x in 0..width {
+                        let basis = basis_y * (std::f32::consts::PI * i as f32 * x as f32 / width as f32).cos();
+                        let pixel = linear[(y * width + x) as usize];
+                        for c in 0..3 {
+                            sum[c] += basis * pixel[c];
+                        }
+                    }
+                }
+                let scale = normalisation / (width as f32 * height as f32).max(1.0);
+                factors.push(sum.map(|v| v * scale));
+            }
+        }
+
+        let mut hash = String::new();
+        encode_base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+        let ac = &factors[1..];
+        let maximum = if ac.is_empty() {
+            encode_base83(0, 1, &mut hash);
+            1.0
+        } else {
+            let actual_max = ac.iter().flatten().fold(0.0f32, |max, v| max.max(v.abs()));
+            let quantised_max = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+            encode_base83(quantised_max, 1, &mut hash);
+            (quantised_max + 1) as f32 / 166.0
+        };
+        let dc = factors[0].map(|v| linear_to_srgb(v) as u32);
+        encode_base83((dc[0] << 16) + (dc[1] << 8) + dc[2], 4, &mut hash);
+        for factor in ac {
+            let q = factor.map(|v| (sign_pow(v / maximum, 0.5) * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32);
+            encode_base83(q[0] * 19 * 19 + q[1] * 19 + q[2], 2, &mut hash);
+        }
+        hash
+    }
+
+    /// DCT-based perceptual hash for finding near-duplicates: the grayscale image is shrunk
+    /// to 32x32, transformed with a 2D DCT, and each of the 8x8 lowest-frequency
+    /// coefficients sets a bit when it's above their median (the DC term is left out of
+    /// the median). Compare hashes with `hamming_distance`; re-encodes and small edits
+    /// stay within a few bits
+    pub fn perceptual_hash(&self) -> u64 {
+        const SIZE: usize = 32;
+        const LOW: usize = 8;
+        let small = image::imageops::resize(
+            &self.image.to_luma8(),
+            SIZE as u32,
+            SIZE as u32,
+            image::imageops::FilterType::Triangle,
+        );
+        let cosines: Vec<f32> = (0..LOW * SIZE)
+            .map(|i| {
+                let (k, n) = (i / SIZE, i % SIZE);
+                (std::f32::consts::PI / SIZE as f32 * (n as f32 + 0.5) * k as f32).cos()
+            })
+            .collect();
+
+        // Rows first, then columns, keeping only the low-frequency coefficients
+        let mut rows = [[0.0f32; LOW]; SIZE];
+        for (y, row) in rows.iter_mut().enumerate() {
+            for (k, coefficient) in row.iter_mut().enumerate() {
+                *coefficient = (0..SIZE).map(|x| small.get_pixel(x as u32, y as u32)[0] as f32 * cosines[k * SIZE + x]).sum();
+            }
+        }
+        let mut coefficients = [0.0f32; LOW * LOW];
+        for (i, coefficient) in coefficients.iter_mut().enumerate() {
+            let (ky, kx) = (i / LOW, i % LOW);
+            *coefficient = (0..SIZE).map(|y| rows[y][kx] * cosines[ky * SIZE + y]).sum();
+        }
+
+        let mut ac = coefficients[1..].to_vec();
+        ac.sort_by(|a, b| a.total_cmp(b));
+        // 63 AC terms, so the median is the single middle one
+        let median = ac[ac.len() / 2];
+        coefficients
+            .iter()
+            .enumerate()
+            .fold(0u64, |hash, (i, &coefficient)| if coefficient > median { hash | 1 << i } else { hash })
+    }
+
+    /// Focus score: the variance of the 4-neighbour Laplacian of the luminance over the
+    /// image interior. Higher means sharper; images smaller than 3x3 score 0.0
+    pub fn sharpness(&self) -> f64 {
+        let (width, height) = (self.image.width() as usize, self.image.height() as usize);
+        if width < 3 || height < 3 {
+            return 0.0;
+        }
+
+        let luma: Vec<f64> = self.image.to_rgba8().pixels().map(|p| luminance(p) as f64).collect();
+        let mut sum = 0.0;
+        let mut sum_sq = 0.0;
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let i = y * width + x;
+                let laplacian = luma[i - 1] + luma[i + 1] + luma[i - width] + luma[i + width] - 4.0 * luma[i];
+                sum += laplacian;
+                sum_sq += laplacian * laplacian;
+            }
+        }
+        let count = ((width - 2) * (height - 2)) as f64;
+        let mean = sum / count;
+        sum_sq / count - mean * mean
+    }
+
+    /// Pipeline checkpoint: error unless the image is exactly `width` x `height`
+    pub fn assert_dimensions(&self, width: u32, height: u32) -> Result<&Self, ImageError> {
+        let (actual_width, actual_height) = (self.image.width(), self.image.height());
+        if (actual_width, actual_height) != (width, height) {
+            return Err(ImageError::operation("assert_dimensions", format!(
+                "Expected dimensions {}x{} but image is {}x{}",
+                width, height, actual_width, actual_height
+            )));
+        }
+        Ok(self)
+    }
+
+    /// Whether the image was loaded from a four-component (CMYK or YCCK) JPEG, whose colors
+    /// went through a CMYK to RGB conversion on load
+    pub fn is_cmyk_source(&self) -> bool {
+        self.cmyk_source
+    }
+
+    /// Whether any pixel is less than fully opaque. Images without an alpha channel are
+    /// never transparent, so this is false whenever saving as JPEG would lose nothing
+    pub fn has_transparency(&self) -> bool {
+        self.image.color().has_alpha() && self.image.to_rgba8().pixels().any(|p| p[3] < 255)
+    }
+
+    /// Average color of the square of side `2 * radius + 1` centred on (`x`, `y`),
+    /// clipped to the image, for color pickers that shouldn't land on a single noisy pixel
+    pub fn sample_average(&self, x: u32, y: u32, radius: u32) -> Result<Rgba<u8>, ImageError> {
+        let (width, height) = self.image.dimensions();
+        if x >= width || y >= height {
+            return Err(ImageError::operation(
+                "sample_average",
+                format!("Point ({}, {}) is outside the {}x{} image", x, y, width, height),
+            ));
+        }
+
+        let rgba = self.image.to_rgba8();
+        let (x0, y0) = (x.saturating_sub(radius), y.saturating_sub(radius));
+        let (x1, y1) = (x.saturating_add(radius).min(width - 1), y.saturating_add(radius).min(height - 1));
+        let mut sums = [0u64; 4];
+        for py in y0..=y1 {
+            for px in x0..=x1 {
+                for (sum, &value) in sums.iter_mut().zip(rgba.get_pixel(px, py).0.iter()) {
+                    *sum += value as u64;
+                }
+            }
+        }
+        let count = (x1 - x0 + 1) as u64 * (y1 - y0 + 1) as u64;
+        Ok(Rgba(sums.map(|sum| ((sum + count / 2) / count) as u8)))
+    }
+
+    /// Color profile along a line: `samples` evenly spaced points from `start` to `end`
+    /// inclusive, in pixel coordinates, bilinearly interpolated. Points outside the image
+    /// are clamped to its edge; a single sample is taken at `start`
+    pub fn sample_line(&self, start: (f32, f32), end: (f32, f32), samples: u32) -> Vec<Rgba<u8>> {
+        let img = self.image.to_rgba8();
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            return Vec::new();
+        }
+
+        let max_x = (width - 1) as f32;
+        let max_y = (height - 1) as f32;
+        (0..samples)
+            .filter_map(|i| {
+                let t = if samples > 1 { i as f32 / (samples - 1) as f32 } else { 0.0 };
+                let x = (start.0 + (end.0 - start.0) * t).clamp(0.0, max_x);
+                let y = (start.1 + (end.1 - start.1) * t).clamp(0.0, max_y);
+                image::imageops::interpolate_bilinear(&img, x, y)
+            })
+            .collect()
+    }
+
+    /// Rough peak memory in bytes for a typical operation on this image: the current
+    /// buffer plus the RGBA8 working copy and output most operations allocate
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let (width, height) = self.image.dimensions();
+        self.image.as_bytes().len() + 2 * width as usize * height as usize * 4
+    }
+
+    /// Pipeline checkpoint: `UnsupportedColorType` unless the image has an alpha channel
+    pub fn assert_has_alpha(&self) -> Result<&Self, ImageError> {
+        let color = self.image.color();
+        if !color.has_alpha() {
+            return Err(ImageError::unsupported_color_type("assert_has_alpha", color));
+        }
+        Ok(self)
+    }
+
+    /// The alpha channel as a grayscale mask. `UnsupportedColorType` if the image has no
+    /// alpha channel, rather than inventing a solid white one
+    pub fn alpha_mask(&self) -> Result<DynamicImage, ImageError> {
+        let color = self.image.color();
+        if !color.has_alpha() {
+            return Err(ImageError::unsupported_color_type("alpha_mask", color));
+        }
+
+        let img = self.image.to_rgba8();
+        Ok(DynamicImage::ImageLuma8(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+            Luma([img.get_pixel(x, y)[3]])
+        })))
+    }
+
+    /// Replace the alpha channel with `mask`'s luminance, the inverse of `alpha_mask`.
+    /// The mask must match the image dimensions. `UnsupportedColorType` if the image has
+    /// no alpha channel; `convert_to` an alpha format first to add one
+    pub fn apply_alpha_mask(&mut self, mask: &DynamicImage) -> Result<&mut Self, ImageError> {
+        let started = self.start_timer();
+        let color = self.image.color();
+        if !color.has_alpha() {
+            return Err(ImageError::unsupported_color_type("apply_alpha_mask", color));
+        }
+        if mask.dimensions() != self.image.dimensions() {
+            return Err(ImageError::operation("apply_alpha_mask", format!(
+                "Mask is {}x{} but the image is {}x{}",
+                mask.width(),
+                mask.height(),
+                self.image.width(),
+                self.image.height()
+            )));
+        }
+
+        let mut img = self.image.to_rgba8();
+        for (pixel, alpha) in img.pixels_mut().zip(mask.to_luma8().pixels()) {
+            pixel[3] = alpha[0];
+        }
+        self.image = DynamicImage::ImageRgba8(img);
+        self.record_unrecordable(started, "apply_alpha_mask");
+        Ok(self)
+    }
+
+    /// Serialize the operations applied so far as a JSON recipe. Fails once an operation
+    /// the recipe can't describe has run, e.g. `overlay_image`
+    pub fn recipe_json(&self) -> Result<String, ImageError> {
+        self.log.to_json()
+    }
+
+    /// Replay a JSON recipe produced by `recipe_json` onto this image
+    pub fn apply_recipe(&mut self, json: &str) -> Result<&mut Self, ImageError> {
+        let recipe = OperationLog::from_json(json)?;
+        for op in recipe.operations() {
+            op.apply(self)?;
+        }
+        Ok(self)
+    }
+
+    /// Get the log of operations applied so far
+    pub fn operation_log(&self) -> &OperationLog {
+        &self.log
+    }
+
+    /// Place images side by side, left to right, top-aligned. Shorter images are padded
+    /// with `background` up to the tallest height
+    pub fn concat_horizontal(images: &[&DynamicImage], background: Rgba<u8>) -> Result<DynamicImage, ImageError> {
+        if images.is_empty() {
+            return Err(ImageError::operation(
+                "concat_horizontal",
+                "Cannot concatenate an empty list of images",
+            ));
+        }
+
+        let width = images.iter().map(|img| img.width()).sum();
+        let height = images.iter().map(|img| img.height()).max().unwrap_or(0);
+        let mut canvas = RgbaImage::from_pixel(width, height, background);
+        let mut x = 0;
+        for img in images {
+            image::imageops::replace(&mut canvas, &img.to_rgba8(), x as i64, 0);
+            x += img.width();
+        }
+        Ok(DynamicImage::ImageRgba8(canvas))
+    }
+
+    /// Stack images top to bottom, left-aligned. Narrower images are padded with
+    /// `background` up to the widest width
+    pub fn concat_vertical(images: &[&DynamicImage], background: Rgba<u8>) -> Result<DynamicImage, ImageError> {
+        if images.is_empty() {
+            return Err(ImageError::operation(
+                "concat_vertical",
+                "Cannot concatenate an empty list of images",
+            ));
+        }
+
+        let width = images.iter().map(|img| img.width()).max().unwrap_or(0);
+        let height = images.iter().map(|img| img.height()).sum();
+        let mut canvas = RgbaImage::from_pixel(width, height, background);
+        let mut y = 0;
+        for img in images {
+            image::imageops::replace(&mut canvas, &img.to_rgba8(), 0, y as i64);
+            y += img.height();
+        }
+        Ok(DynamicImage::ImageRgba8(canvas))
+    }
+
+    /// Average same-size exposures channel by channel (alpha included) to reduce noise
+    pub fn stack_average(images: &[DynamicImage]) -> Result<DynamicImage, ImageError> {
+        let stack = stack_rgba("stack_average", images)?;
+        let count = stack.len() as f32;
+        let (width, height) = stack[0].dimensions();
+        let mut averaged = RgbaImage::new(width, height);
+        for (i, pixel) in averaged.pixels_mut().enumerate() {
+            let mut sum = [0.0f32; 4];
+            for img in &stack {
+                for (total, value) in sum.iter_mut().zip(&img.as_raw()[i * 4..i * 4 + 4]) {
+                    *total += *value as f32;
+                }
+            }
+            *pixel = Rgba(sum.map(|v| (v / count).round() as u8));
+        }
+        Ok(DynamicImage::ImageRgba8(averaged))
+    }
+
+    /// Like `stack_average`, but takes the per-channel median, so a value that is off in
+    /// only a minority of exposures (satellite trails, hot pixels) is rejected outright.
+    /// With an even count the two middle values are averaged
+    pub fn stack_median(images: &[DynamicImage]) -> Result<DynamicImage, ImageError> {
+        let stack = stack_rgba("stack_median", images)?;
+        let (width, height) = stack[0].dimensions();
+        let mut values = vec![0u8; stack.len()];
+        let mut median = RgbaImage::new(width, height);
+        for (i, pixel) in median.pixels_mut().enumerate() {
+            for c in 0..4 {
+                for (value, img) in values.iter_mut().zip(&stack) {
+                    *value = img.as_raw()[i * 4 + c];
+                }
+                values.sort_unstable();
+                let mid = values.len() / 2;
+                pixel[c] = if values.len().is_multiple_of(2) {
+                    (values[mid - 1] as u16 + values[mid] as u16).div_ceil(2) as u8
+                } else {
+                    values[mid]
+                };
+            }
+        }
+        Ok(DynamicImage::ImageRgba8(median))
+    }
+
+    /// Lay images out in a grid with `columns` columns. Each image is scaled to fit a
+    /// `cell_size` square (preserving aspect ratio) and centered in its cell, with
+    /// `padding` pixels around and between cells
+    pub fn contact_sheet(
+        images: &[DynamicImage],
+        columns: u32,
+        cell_size: u32,
+        padding: u32,
+        background: Rgba<u8>,
+    ) -> Result<DynamicImage, ImageError> {
+        if images.is_empty() || columns == 0 || cell_size == 0 {
+            return Err(ImageError::operation(
+                "contact_sheet",
+                "Contact sheet needs at least one image, one column and a non-zero cell size",
+            ));
+        }
+
+        let rows = (images.len() as u32).div_ceil(columns);
+        let width = columns * cell_size + (columns + 1) * padding;
+        let height = rows * cell_size + (rows + 1) * padding;
+        let mut sheet = RgbaImage::from_pixel(width, height, background);
+
+        for (i, img) in images.iter().enumerate() {
+            let (col, row) = (i as u32 % columns, i as u32 / columns);
+            let thumb = img
+                .resize(cell_size, cell_size, image::imageops::FilterType::Lanczos3)
+                .to_rgba8();
+            let x = padding + col * (cell_size + padding) + (cell_size - thumb.width()) / 2;
+            let y = padding + row * (cell_size + padding) + (cell_size - thumb.height()) / 2;
+            image::imageops::overlay(&mut sheet, &thumb, x as i64, y as i64);
+        }
+        Ok(DynamicImage::ImageRgba8(sheet))
+    }
+
+    /// Before/after comparison: this image on the left and `other` on the right, top-aligned
+    /// on a white background with a gray divider between them, and `labels` centered in a
+    /// strip beneath each
+    pub fn side_by_side(
+        &self,
+        other: &DynamicImage,
+        labels: (&str, &str),
+        font: &impl Font,
+    ) -> Result<DynamicImage, ImageError> {
+        let (left_width, right_width) = (self.image.width(), other.width());
+        let images_height = self.image.height().max(other.height());
+        let scaled = font.as_scaled(COMPARISON_LABEL_SCALE);
+        let line_height = (scaled.ascent() - scaled.descent() + scaled.line_gap()).ceil().max(1.0) as u32;
+        let width = left_width + COMPARISON_DIVIDER_WIDTH + right_width;
+        let height = images_height + line_height + 2 * CAPTION_PADDING;
+
+        let mut canvas = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+        image::imageops::replace(&mut canvas, &self.image.to_rgba8(), 0, 0);
+        image::imageops::replace(&mut canvas, &other.to_rgba8(), (left_width + COMPARISON_DIVIDER_WIDTH) as i64, 0);
+        for y in 0..height {
+            for x in left_width..left_width + COMPARISON_DIVIDER_WIDTH {
+                canvas.put_pixel(x, y, Rgba([128, 128, 128, 255]));
+            }
+        }
+
+        let label_y = (images_height + CAPTION_PADDING) as f32;
+        for (label, x0, column_width) in [
+            (labels.0, 0, left_width),
+            (labels.1, left_width + COMPARISON_DIVIDER_WIDTH, right_width),
+        ] {
+            let label_x = x0 as f32 + (column_width as f32 - text_width(font, COMPARISON_LABEL_SCALE, label)) / 2.0;
+            let clip = (x0, images_height, x0 + column_width, height);
+            draw_text_line(
+                &mut canvas,
+                label,
+                (label_x, label_y),
+                font,
+                COMPARISON_LABEL_SCALE,
+                Rgba([0, 0, 0, 255]),
+                clip,
+            );
+        }
+        Ok(DynamicImage::ImageRgba8(canvas))
+    }
+
+    /// Split into a low-frequency layer (the image blurred by `sigma`, carrying color and
+    /// tone) and a high-frequency layer (original minus low, offset by 128, carrying texture)
+    /// for retouching each separately. The high layer keeps the original alpha. A
+    /// non-positive `sigma` puts everything in the low layer. See `recombine`
+    pub fn frequency_separation(&self, sigma: f32) -> (DynamicImage, DynamicImage) {
+        let img = self.image.to_rgba8();
+        let low = if sigma > 0.0 { gaussian_blur(&img, sigma) } else { img.clone() };
+        let high = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+            let (original, blurred) = (img.get_pixel(x, y), low.get_pixel(x, y));
+            let mut detail = *original;
+            for c in 0..3 {
+                detail[c] = (original[c] as i16 - blurred[c] as i16 + 128).clamp(0, 255) as u8;
+            }
+            detail
+        });
+        (DynamicImage::ImageRgba8(low), DynamicImage::ImageRgba8(high))
+    }
+
+    /// Merge layers from `frequency_separation` back into one image: low + high - 128 per
+    /// channel, with the high layer's alpha. Detail beyond ±127 that was clipped while
+    /// splitting is lost
+    pub fn recombine(low: &DynamicImage, high: &DynamicImage) -> Result<DynamicImage, ImageError> {
+        if low.dimensions() != high.dimensions() {
+            return Err(ImageError::operation("recombine", format!(
+                "Low layer is {}x{} but high layer is {}x{}",
+                low.width(),
+                low.height(),
+                high.width(),
+                high.height()
+            )));
+        }
+
+        let low = low.to_rgba8();
+        let mut merged = high.to_rgba8();
+        for (pixel, base) in merged.pixels_mut().zip(low.pixels()) {
+            for c in 0..3 {
+                pixel[c] = (base[c] as i16 + pixel[c] as i16 - 128).clamp(0, 255) as u8;
+            }
+        }
+        Ok(DynamicImage::ImageRgba8(merged))
+    }
+
+    /// Save the processed image to a file
+    pub fn save(&self, path: &str) -> Result<(), ImageError> {
+        self.image
+            .save(path)
+            .map_err(|e| ImageError::operation("save", e.to_string()))
+    }
+
+    /// Like `save`, but writes to a temporary file next to `path` and renames it into place
+    /// only once encoding succeeds, so `path` never holds a partially written image. On
+    /// failure the temporary file is removed and any existing file at `path` is untouched
+    pub fn save_atomic(&self, path: &str) -> Result<(), ImageError> {
+        let target = Path::new(path);
+        let format = ImageFormat::from_path(target).map_err(|e| ImageError::operation("save_atomic", e.to_string()))?;
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| ImageError::operation("save_atomic", format!("'{}' is not a file path", path)))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = target.with_file_name(temp_name);
+
+        let result = self
+            .image
+            .save_with_format(&temp_path, format)
+            .map_err(|e| ImageError::operation("save_atomic", e.to_string()))
+            .and_then(|_| fs::rename(&temp_path, target).map_err(|e| ImageError::operation("save_atomic", e.to_string())));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Save as TIFF. 8-bit and 16-bit gray, RGB and RGBA are written as-is; other
+    /// layouts are converted to RGBA at 8 or 16 bits depending on the source depth
+    pub fn save_tiff(&self, path: &str) -> Result<(), ImageError> {
+        let image = match &self.image {
+            DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => self.image.clone(),
+            DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                DynamicImage::ImageRgba16(self.image.to_rgba16())
+            }
+            _ => DynamicImage::ImageRgba8(self.image.to_rgba8()),
+        };
+        write_output("save_tiff", path, |writer| image.write_with_encoder(TiffEncoder::new(writer)))
+    }
+
+    /// Save as PNG with explicit compression settings. 8-bit and 16-bit layouts are written
+    /// as-is; floating-point images are converted to RGBA16
+    pub fn save_png_optimized(&self, path: &str, compression: PngCompression) -> Result<(), ImageError> {
+        let (compression_type, filter_type) = match compression {
+            PngCompression::Fast => (CompressionType::Fast, FilterType::NoFilter),
+            PngCompression::Default => (CompressionType::Default, FilterType::Adaptive),
+            PngCompression::Best => (CompressionType::Best, FilterType::Adaptive),
+        };
+        let image = match &self.image {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                DynamicImage::ImageRgba16(self.image.to_rgba16())
+            }
+            _ => self.image.clone(),
+        };
+        write_output("save_png_optimized", path, |writer| {
+            image.write_with_encoder(PngEncoder::new_with_quality(writer, compression_type, filter_type))
+        })
+    }
+
+    /// Save as a 16-bit PNG. Floating-point images (e.g. after `convert_to(Rgba32F)`) are
+    /// quantized straight to 16 bits, keeping smooth gradients free of 8-bit banding; 8-bit
+    /// images are widened. Gray stays gray and images without alpha are written as RGB16
+    pub fn save_png16(&self, path: &str) -> Result<(), ImageError> {
+        let color = self.image.color();
+        let image = match (color.has_color(), color.has_alpha()) {
+            (false, false) => DynamicImage::ImageLuma16(self.image.to_luma16()),
+            (false, true) => DynamicImage::ImageLumaA16(self.image.to_luma_alpha16()),
+            (true, false) => DynamicImage::ImageRgb16(self.image.to_rgb16()),
+            (true, true) => DynamicImage::ImageRgba16(self.image.to_rgba16()),
+        };
+        write_output("save_png16", path, |writer| image.write_with_encoder(PngEncoder::new(writer)))
+    }
+
+    /// Save as BMP. BMP is 8 bits per channel, so deeper images are converted to RGBA8
+    pub fn save_bmp(&self, path: &str) -> Result<(), ImageError> {
+        write_output("save_bmp", path, |writer| self.to_8bit_for_encoding().write_with_encoder(BmpEncoder::new(writer)))
+    }
+
+    /// Save as TGA. TGA is 8 bits per channel, so deeper images are converted to RGBA8
+    pub fn save_tga(&self, path: &str) -> Result<(), ImageError> {
+        write_output("save_tga", path, |writer| self.to_8bit_for_encoding().write_with_encoder(TgaEncoder::new(writer)))
+    }
+
+    /// Save as a baseline JPEG with `quality` in 1..=100 and the given chroma subsampling
+    /// (`ChromaSubsampling::default()` is 4:2:0). Alpha is dropped
+    #[cfg(feature = "jpeg-encoder")]
+    pub fn save_jpeg(&self, path: &str, quality: u8, subsampling: ChromaSubsampling) -> Result<(), ImageError> {
+        self.validate_jpeg_output("save_jpeg", quality)?;
+        let writer = create_output("save_jpeg", path)?;
+        jpeg::encode(&self.image.to_rgb8(), quality, false, subsampling, writer)
+            .map_err(|e| ImageError::operation("save_jpeg", e.to_string()))
+    }
+
+    /// Save as a progressive JPEG (a coarse full-image pass first, refined by later scans),
+    /// with `quality` in 1..=100 and the given chroma subsampling. Alpha is dropped
+    #[cfg(feature = "jpeg-encoder")]
+    pub fn save_jpeg_progressive(
+        &self,
+        path: &str,
+        quality: u8,
+        subsampling: ChromaSubsampling,
+    ) -> Result<(), ImageError> {
+        self.validate_jpeg_output("save_jpeg_progressive", quality)?;
+        let writer = create_output("save_jpeg_progressive", path)?;
+        jpeg::encode(&self.image.to_rgb8(), quality, true, subsampling, writer)
+            .map_err(|e| ImageError::operation("save_jpeg_progressive", e.to_string()))
+    }
+
+    #[cfg(feature = "jpeg-encoder")]
+    fn validate_jpeg_output(&self, op: &str, quality: u8) -> Result<(), ImageError> {
+        if !(1..=100).contains(&quality) {
+            return Err(ImageError::operation(
+                op,
+                "JPEG quality must be between 1 and 100",
+            ));
+        }
+        let (width, height) = (self.image.width(), self.image.height());
+        if width == 0 || height == 0 || width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(ImageError::operation(op, format!(
+                "JPEG dimensions must be between 1 and 65535, got {}x{}",
+                width, height
+            )));
+        }
+        Ok(())
+    }
+
+    /// Save frames as a looping, lossless animated WebP. Every frame is shown for `delay_ms`
+    /// and must match the first frame's dimensions; a `loop_count` of 0 loops forever
+    #[cfg(feature = "webp-animation")]
+    pub fn save_animated_webp(
+        frames: &[DynamicImage],
+        path: &str,
+        delay_ms: u16,
+        loop_count: u16,
+    ) -> Result<(), ImageError> {
+        let encode_error = |e: webp_animation::Error| ImageError::operation("save_animated_webp", e.to_string());
+        let first = frames.first().ok_or_else(|| {
+            ImageError::operation("save_animated_webp", "Animated WebP needs at least one frame")
+        })?;
+        let (width, height) = (first.width(), first.height());
+        if let Some(index) = frames.iter().position(|f| (f.width(), f.height()) != (width, height)) {
+            return Err(ImageError::operation("save_animated_webp", format!(
+                "Frame {} is {}x{} but the first frame is {}x{}",
+                index,
+                frames[index].width(),
+                frames[index].height(),
+                width,
+                height
+            )));
+        }
+
+        let options = webp_animation::EncoderOptions {
+            anim_params: webp_animation::AnimParams { loop_count: loop_count as i32 },
+            encoding_config: Some(webp_animation::EncodingConfig {
+                encoding_type: webp_animation::EncodingType::Lossless,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut encoder = webp_animation::Encoder::new_with_options((width, height), options).map_err(encode_error)?;
+        // Frames are placed by start time; the final timestamp ends the last frame
+        let mut timestamp = 0;
+        for frame in frames {
+            encoder.add_frame(frame.to_rgba8().as_raw(), timestamp).map_err(encode_error)?;
+            timestamp += delay_ms as i32;
+        }
+        let webp = encoder.finalize(timestamp).map_err(encode_error)?;
+        std::fs::write(path, &*webp).map_err(|e| ImageError::operation("save_animated_webp", e.to_string()))
+    }
+
+    /// Get the image in a layout accepted by 8-bit-only encoders (gray, gray+alpha, RGB, RGBA)
+    fn to_8bit_for_encoding(&self) -> DynamicImage {
+        match &self.image {
+            DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_) => self.image.clone(),
+            _ => DynamicImage::ImageRgba8(self.image.to_rgba8()),
+        }
+    }
+
+    /// Start the clock for an operation; doesn't read the clock when timing is disabled
+    fn start_timer(&self) -> Option<Instant> {
+        self.timings.as_ref().map(|_| Instant::now())
+    }
+
+    /// Log a completed operation and, if timing is enabled, how long it took
+    fn record(&mut self, started: Option<Instant>, op: Operation) {
+        self.record_timing(started, op.name());
+        self.log.record(op);
+    }
+
+    /// The image as a mutable RGBA8 buffer for in-place edits by `op`. Only converts (and
+    /// allocates) when the image is in some other format
+    fn rgba8_mut(&mut self, op: &str) -> Result<&mut RgbaImage, ImageError> {
+        if self.image.as_rgba8().is_none() {
+            self.image = DynamicImage::ImageRgba8(self.image.to_rgba8());
+        }
+        self.image
+            .as_mut_rgba8()
+            .ok_or_else(|| ImageError::operation(op, "Image could not be converted to RGBA8"))
+    }
 
-        // Iterate through overlay pixels and blend them with base image
-        for (i, j, pixel) in overlay.enumerate_pixels() {
-            let x_pos = x + i;
-            let y_pos = y + j;
+    /// Time a mutating operation whose inputs a recipe can't hold, and mark the log as no
+    /// longer replayable
+    fn record_unrecordable(&mut self, started: Option<Instant>, name: &str) {
+        self.record_timing(started, name);
+        self.log.record_unrecordable(name);
+    }
 
-            if pixel[3] > 0 {  // Only blend non-transparent pixels
-                if pixel[3] == 255 {
-                    base.put_pixel(x_pos, y_pos, *pixel);
+    /// Time an operation that isn't part of the recipe log
+    fn record_timing(&mut self, started: Option<Instant>, name: &str) {
+        if let (Some(timings), Some(started)) = (self.timings.as_mut(), started) {
+            timings.push((name.to_string(), started.elapsed()));
+        }
+    }
+
+    /// Width and height of the current image
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.image.width(), self.image.height())
+    }
+
+    /// Get the underlying DynamicImage
+    pub fn get_image(&self) -> &DynamicImage {
+        &self.image
+    }
+
+    /// Stream the image's rows top to bottom as RGBA8 bytes. RGBA8 images are borrowed
+    /// in place; other formats are converted one row at a time rather than all at once
+    pub fn rows(&self) -> impl Iterator<Item = Cow<'_, [u8]>> {
+        let row_len = self.image.width() as usize * 4;
+        let rgba = self.image.as_rgba8();
+        (0..self.image.height()).map(move |y| match rgba {
+            Some(img) => {
+                let start = y as usize * row_len;
+                Cow::Borrowed(&img.as_raw()[start..start + row_len])
+            }
+            None => Cow::Owned((0..self.image.width()).flat_map(|x| self.image.get_pixel(x, y).0).collect()),
+        })
+    }
+
+    /// Export as a channel-first (CHW) float tensor for ML models: all red values row by
+    /// row, then green, then blue, each as `(value / 255 - mean) / std`. Alpha is dropped
+    pub fn to_normalized_tensor(&self, mean: [f32; 3], std: [f32; 3]) -> Vec<f32> {
+        let img = self.image.to_rgb8();
+        let plane_len = img.width() as usize * img.height() as usize;
+        let mut tensor = vec![0.0f32; 3 * plane_len];
+        for (i, pixel) in img.pixels().enumerate() {
+            for c in 0..3 {
+                tensor[c * plane_len + i] = (pixel[c] as f32 / 255.0 - mean[c]) / std[c];
+            }
+        }
+        tensor
+    }
+}
+
+/// How a layer's colors combine with the layers beneath it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+}
+
+impl BlendMode {
+    /// Blend a source channel onto a backdrop channel, both normalized to 0.0..=1.0
+    fn blend(&self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+            BlendMode::Overlay => {
+                if backdrop <= 0.5 {
+                    2.0 * backdrop * source
                 } else {
-                    let base_pixel = base.get_pixel(x_pos, y_pos);
-                    let alpha = pixel[3] as f32 / 255.0;
-                    let new_pixel = Rgba([
-                        ((1.0 - alpha) * base_pixel[0] as f32 + alpha * pixel[0] as f32) as u8,
-                        ((1.0 - alpha) * base_pixel[1] as f32 + alpha * pixel[1] as f32) as u8,
-                        ((1.0 - alpha) * base_pixel[2] as f32 + alpha * pixel[2] as f32) as u8,
-                        255,
-                    ]);
-                    base.put_pixel(x_pos, y_pos, new_pixel);
+                    1.0 - 2.0 * (1.0 - backdrop) * (1.0 - source)
                 }
             }
+            BlendMode::Darken => backdrop.min(source),
+            BlendMode::Lighten => backdrop.max(source),
+        }
+    }
+}
+
+/// Porter-Duff operator for `composite`: which of the source and destination survive
+/// where each is present
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PorterDuff {
+    /// Source on top of destination
+    #[default]
+    SrcOver,
+    /// Destination on top of source
+    DstOver,
+    /// Source only where the destination is
+    SrcIn,
+    /// Destination only where the source is
+    DstIn,
+    /// Source only where the destination isn't
+    SrcOut,
+    /// Destination only where the source isn't
+    DstOut,
+    /// Source over destination, only where the destination is
+    SrcAtop,
+    /// Destination over source, only where the source is
+    DstAtop,
+    /// Source and destination only where the other isn't
+    Xor,
+}
+
+impl PorterDuff {
+    /// Weights of the premultiplied source and destination given their alphas (0.0..=1.0)
+    fn factors(self, src_alpha: f32, dst_alpha: f32) -> (f32, f32) {
+        match self {
+            PorterDuff::SrcOver => (1.0, 1.0 - src_alpha),
+            PorterDuff::DstOver => (1.0 - dst_alpha, 1.0),
+            PorterDuff::SrcIn => (dst_alpha, 0.0),
+            PorterDuff::DstIn => (0.0, src_alpha),
+            PorterDuff::SrcOut => (1.0 - dst_alpha, 0.0),
+            PorterDuff::DstOut => (0.0, 1.0 - src_alpha),
+            PorterDuff::SrcAtop => (dst_alpha, 1.0 - src_alpha),
+            PorterDuff::DstAtop => (1.0 - dst_alpha, src_alpha),
+            PorterDuff::Xor => (1.0 - dst_alpha, 1.0 - src_alpha),
         }
+    }
+}
 
-        self.image = DynamicImage::ImageRgba8(base);
+/// A named image in a `LayerStack`, placed at (`x`, `y`) on the canvas
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    pub image: DynamicImage,
+    pub x: i32,
+    pub y: i32,
+    /// Multiplies the layer's own alpha, 0.0..=1.0
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+}
+
+impl Layer {
+    /// Create a fully opaque, normally blended layer at the canvas origin
+    pub fn new(name: &str, image: DynamicImage) -> Self {
+        Layer {
+            name: name.to_string(),
+            image,
+            x: 0,
+            y: 0,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+        }
+    }
+}
+
+/// Ordered stack of named layers, bottom first, composited only when flattened
+#[derive(Debug, Clone)]
+pub struct LayerStack {
+    width: u32,
+    height: u32,
+    layers: Vec<Layer>,
+}
+
+impl LayerStack {
+    /// Create an empty stack whose flattened output is `width` x `height`
+    pub fn new(width: u32, height: u32) -> Self {
+        LayerStack {
+            width,
+            height,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Add a layer on top of the stack. Layer names must be unique
+    pub fn add_layer(&mut self, layer: Layer) -> Result<&mut Self, ImageError> {
+        if self.layers.iter().any(|l| l.name == layer.name) {
+            return Err(ImageError::operation("add_layer", format!(
+                "A layer named '{}' already exists",
+                layer.name
+            )));
+        }
+        self.layers.push(layer);
         Ok(self)
     }
 
-    /// Save the processed image to a file
-    pub fn save(&self, path: &str) -> Result<(), ImageError> {
-        self.image
-            .save(path)
-            .map_err(|e| ImageError::OperationError(e.to_string()))
+    /// Remove and return the named layer
+    pub fn remove_layer(&mut self, name: &str) -> Result<Layer, ImageError> {
+        let index = self.index_of(name)?;
+        Ok(self.layers.remove(index))
     }
 
-    /// Get the underlying DynamicImage
-    pub fn get_image(&self) -> &DynamicImage {
-        &self.image
+    /// Move the named layer to `index` in the stack (0 is the bottom)
+    pub fn reorder(&mut self, name: &str, index: usize) -> Result<&mut Self, ImageError> {
+        if index >= self.layers.len() {
+            return Err(ImageError::operation("reorder", format!(
+                "Layer index {} is out of range for {} layers",
+                index,
+                self.layers.len()
+            )));
+        }
+        let layer = self.layers.remove(self.index_of(name)?);
+        self.layers.insert(index, layer);
+        Ok(self)
+    }
+
+    /// Get a mutable reference to the named layer, e.g. to change its opacity
+    pub fn layer_mut(&mut self, name: &str) -> Result<&mut Layer, ImageError> {
+        let index = self.index_of(name)?;
+        Ok(&mut self.layers[index])
+    }
+
+    /// Layers from bottom to top
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Composite all layers, bottom to top, onto a transparent canvas
+    pub fn flatten(&self) -> DynamicImage {
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        for layer in &self.layers {
+            let source = layer.image.to_rgba8();
+            let opacity = layer.opacity.clamp(0.0, 1.0);
+            for (i, j, src) in source.enumerate_pixels() {
+                let (x, y) = (layer.x as i64 + i as i64, layer.y as i64 + j as i64);
+                if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+                    continue;
+                }
+                let dst = canvas.get_pixel_mut(x as u32, y as u32);
+                let src_alpha = src[3] as f32 / 255.0 * opacity;
+                if src_alpha == 0.0 {
+                    continue;
+                }
+                let dst_alpha = dst[3] as f32 / 255.0;
+                let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+                for c in 0..3 {
+                    let (s, d) = (src[c] as f32 / 255.0, dst[c] as f32 / 255.0);
+                    // The blended color only applies where there is a backdrop to blend with
+                    let mixed = (1.0 - dst_alpha) * s + dst_alpha * layer.blend_mode.blend(d, s);
+                    let value = (src_alpha * mixed + dst_alpha * (1.0 - src_alpha) * d) / out_alpha;
+                    dst[c] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+                }
+                dst[3] = (out_alpha * 255.0).round() as u8;
+            }
+        }
+        DynamicImage::ImageRgba8(canvas)
+    }
+
+    fn index_of(&self, name: &str) -> Result<usize, ImageError> {
+        self.layers
+            .iter()
+            .position(|l| l.name == name)
+            .ok_or_else(|| ImageError::operation("layer_stack", format!("No layer named '{}'", name)))
+    }
+}
+
+/// Read an image file's width and height from its header, without decoding any pixels
+pub fn image_dimensions(path: &str) -> Result<(u32, u32), ImageError> {
+    image::image_dimensions(path).map_err(|e| ImageError::LoadError(e.to_string()))
+}
+
+/// Number of differing bits between two hashes from `ImageProcessor::perceptual_hash`.
+/// 0 means perceptually identical; unrelated images land around 32
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Convert a non-empty stack of same-size images to RGBA8 for `stack_average` and
+/// `stack_median`, erroring as `op` otherwise
+fn stack_rgba(op: &str, images: &[DynamicImage]) -> Result<Vec<RgbaImage>, ImageError> {
+    let first = images
+        .first()
+        .ok_or_else(|| ImageError::operation(op, "Cannot stack an empty list of images"))?;
+    if let Some(other) = images.iter().find(|img| img.dimensions() != first.dimensions()) {
+        return Err(ImageError::operation(op, format!(
+            "Stacked images must all be {}x{}, found one that is {}x{}",
+            first.width(),
+            first.height(),
+            other.width(),
+            other.height()
+        )));
+    }
+    Ok(images.iter().map(|img| img.to_rgba8()).collect())
+}
+
+/// Digits of the base-83 alphabet used by BlurHash
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Append `value` to `out` as `length` base-83 digits, most significant first
+fn encode_base83(value: u32, length: u32, out: &mut String) {
+    for i in (0..length).rev() {
+        out.push(BASE83[(value / 83u32.pow(i) % 83) as usize] as char);
+    }
+}
+
+/// Parse base-83 digits, or `None` if any character isn't in the alphabet
+fn decode_base83(digits: &str) -> Option<u32> {
+    digits
+        .bytes()
+        .try_fold(0u32, |value, digit| Some(value * 83 + BASE83.iter().position(|&d| d == digit)? as u32))
+}
+
+/// `|value|^exponent` with the sign of `value`
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+/// Fail as `op` unless the rectangle lies within an image of the given dimensions. The
+/// edges are summed in u64 so offsets near `u32::MAX` can't wrap past the check
+fn check_region(
+    op: &str,
+    (image_width, image_height): (u32, u32),
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<(), ImageError> {
+    if x as u64 + width as u64 > image_width as u64 || y as u64 + height as u64 > image_height as u64 {
+        return Err(ImageError::operation(op, format!(
+            "Region {}x{} at ({}, {}) exceeds the {}x{} image",
+            width, height, x, y, image_width, image_height
+        )));
+    }
+    Ok(())
+}
+
+/// Decode just one rectangle of an image through `ImageDecoderRect`
+fn read_region<D: ImageDecoderRect>(
+    mut decoder: D,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<DynamicImage, ImageError> {
+    check_region("open_region", decoder.dimensions(), x, y, width, height)?;
+    let color = decoder.color_type();
+    let row_pitch = width as usize * color.bytes_per_pixel() as usize;
+    let mut buf = vec![0u8; row_pitch * height as usize];
+    decoder
+        .read_rect(x, y, width, height, &mut buf, row_pitch)
+        .map_err(|e| ImageError::LoadError(e.to_string()))?;
+
+    let image = match color {
+        ColorType::Rgb8 => ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8),
+        ColorType::Rgba8 => ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8),
+        ColorType::Rgba16 => {
+            let samples = buf.chunks_exact(2).map(|b| u16::from_ne_bytes([b[0], b[1]])).collect();
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+        }
+        other => {
+            return Err(ImageError::LoadError(format!(
+                "Unsupported color type {:?} for region decoding",
+                other
+            )))
+        }
+    };
+    image.ok_or_else(|| ImageError::LoadError("Decoded region has the wrong size".to_string()))
+}
+
+/// Create (or truncate) the output file for an explicit-format save
+fn create_output(op: &str, path: &str) -> Result<BufWriter<File>, ImageError> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| ImageError::operation(op, e.to_string()))
+}
+
+/// Create the output file, run `encode` into it and flush. Dropping a `BufWriter` swallows
+/// the error from its last write, so a full disk would otherwise report success
+fn write_output(
+    op: &str,
+    path: &str,
+    encode: impl FnOnce(&mut BufWriter<File>) -> image::ImageResult<()>,
+) -> Result<(), ImageError> {
+    let mut writer = create_output(op, path)?;
+    encode(&mut writer).map_err(|e| ImageError::operation(op, e.to_string()))?;
+    writer.flush().map_err(|e| ImageError::operation(op, e.to_string()))
+}
+
+/// Reject operations with NaN or infinite parameters, which JSON can't represent and
+/// which serde_json would otherwise write as `null`
+fn check_finite(op: &str, operation: &Operation) -> Result<(), ImageError> {
+    fn has_null(value: &serde_json::Value) -> bool {
+        match value {
+            serde_json::Value::Null => true,
+            serde_json::Value::Array(items) => items.iter().any(has_null),
+            serde_json::Value::Object(fields) => fields.values().any(has_null),
+            _ => false,
+        }
+    }
+
+    let value = serde_json::to_value(operation).map_err(|e| ImageError::operation(op, e.to_string()))?;
+    if has_null(&value) {
+        return Err(ImageError::operation(op, format!(
+            "'{}' has a NaN or infinite parameter",
+            operation.name()
+        )));
+    }
+    Ok(())
+}
+
+/// A connected region found by `label_regions`
+struct Region {
+    size: u32,
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+}
+
+/// Label the 8-connected regions of `true` pixels in a row-major mask. Returns a label per
+/// pixel (0 for unset pixels, otherwise 1 + the index into the returned regions)
+fn label_regions(mask: &[bool], width: u32, height: u32) -> (Vec<u32>, Vec<Region>) {
+    let mut labels = vec![0u32; mask.len()];
+    let mut regions = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..mask.len() {
+        if !mask[start] || labels[start] != 0 {
+            continue;
+        }
+        let label = regions.len() as u32 + 1;
+        let (x0, y0) = (start as u32 % width, start as u32 / width);
+        let mut region = Region { size: 0, min_x: x0, min_y: y0, max_x: x0, max_y: y0 };
+        labels[start] = label;
+        stack.push(start);
+
+        while let Some(index) = stack.pop() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            region.size += 1;
+            region.min_x = region.min_x.min(x);
+            region.min_y = region.min_y.min(y);
+            region.max_x = region.max_x.max(x);
+            region.max_y = region.max_y.max(y);
+
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let neighbour = (ny * width + nx) as usize;
+                    if mask[neighbour] && labels[neighbour] == 0 {
+                        labels[neighbour] = label;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        regions.push(region);
     }
+    (labels, regions)
+}
+
+/// Remove `count` minimum-energy vertical seams, recomputing the energy map after each
+fn remove_vertical_seams(img: RgbaImage, count: u32) -> RgbaImage {
+    let (mut width, height) = (img.width() as usize, img.height() as usize);
+    let mut pixels: Vec<Rgba<u8>> = img.pixels().copied().collect();
+
+    for _ in 0..count {
+        let luma: Vec<f32> = pixels[..width * height].iter().map(luminance).collect();
+        let at = |x: usize, y: usize| luma[y * width + x];
+
+        // Cumulative minimum energy, top to bottom
+        let mut cost = vec![0.0f32; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let dx = at((x + 1).min(width - 1), y) - at(x.saturating_sub(1), y);
+                let dy = at(x, (y + 1).min(height - 1)) - at(x, y.saturating_sub(1));
+                let energy = dx.abs() + dy.abs();
+                cost[y * width + x] = if y == 0 {
+                    energy
+                } else {
+                    let above = &cost[(y - 1) * width..y * width];
+                    let lo = x.saturating_sub(1);
+                    let hi = (x + 1).min(width - 1);
+                    energy + above[lo..=hi].iter().copied().fold(f32::INFINITY, f32::min)
+                };
+            }
+        }
+
+        // Trace the cheapest seam back up from the bottom row
+        let last_row = &cost[(height - 1) * width..];
+        let mut x = (0..width)
+            .min_by(|&a, &b| last_row[a].total_cmp(&last_row[b]))
+            .unwrap_or(0);
+        let mut seam = vec![0usize; height];
+        for y in (0..height).rev() {
+            seam[y] = x;
+            if y > 0 {
+                let row = &cost[(y - 1) * width..y * width];
+                let lo = x.saturating_sub(1);
+                let hi = (x + 1).min(width - 1);
+                x = (lo..=hi).min_by(|&a, &b| row[a].total_cmp(&row[b])).unwrap_or(x);
+            }
+        }
+
+        let mut carved = Vec::with_capacity((width - 1) * height);
+        for (y, &skip) in seam.iter().enumerate() {
+            let row = &pixels[y * width..(y + 1) * width];
+            carved.extend(row.iter().enumerate().filter(|&(x, _)| x != skip).map(|(_, p)| *p));
+        }
+        pixels = carved;
+        width -= 1;
+    }
+
+    ImageBuffer::from_fn(width as u32, height as u32, |x, y| pixels[y as usize * width + x as usize])
+}
+
+/// Apply `matrix * rgb + offset` to every pixel in place, leaving alpha alone
+fn apply_color_matrix(img: &mut RgbaImage, matrix: &[[f32; 3]; 3], offset: &[f32; 3]) {
+    // Plain indexing over the raw buffer, with rounding folded into the saturating cast,
+    // keeps this the cheap per-pixel path `rotate_hue_matrix` relies on
+    let [[m00, m01, m02], [m10, m11, m12], [m20, m21, m22]] = *matrix;
+    let [o0, o1, o2] = offset.map(|add| add + 0.5);
+    for pixel in img.chunks_exact_mut(4) {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        pixel[0] = (m00 * r + m01 * g + m02 * b + o0) as u8;
+        pixel[1] = (m10 * r + m11 * g + m12 * b + o1) as u8;
+        pixel[2] = (m20 * r + m21 * g + m22 * b + o2) as u8;
+    }
+}
+
+/// Size of the largest axis-aligned rectangle inside a `width` x `height` rectangle
+/// rotated by an angle with the given absolute sine and cosine, centered on it
+fn inscribed_rect(width: f64, height: f64, sin_a: f64, cos_a: f64) -> (f64, f64) {
+    if width <= 0.0 || height <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let (long_side, short_side) = if width >= height { (width, height) } else { (height, width) };
+    if short_side <= 2.0 * sin_a * cos_a * long_side || (sin_a - cos_a).abs() < 1e-10 {
+        // Half-constrained: two corners of the rectangle touch the longer side
+        let x = 0.5 * short_side;
+        if width >= height { (x / sin_a, x / cos_a) } else { (x / cos_a, x / sin_a) }
+    } else {
+        // Fully constrained: all four corners touch the rotated edges
+        let cos_2a = cos_a * cos_a - sin_a * sin_a;
+        ((width * cos_a - height * sin_a) / cos_2a, (height * cos_a - width * sin_a) / cos_2a)
+    }
+}
+
+/// Pixel as premultiplied (red, green, blue, alpha), each 0.0..=1.0
+fn premultiply(pixel: &Rgba<u8>) -> [f32; 4] {
+    let alpha = pixel[3] as f32 / 255.0;
+    let channel = |v: u8| v as f32 / 255.0 * alpha;
+    [channel(pixel[0]), channel(pixel[1]), channel(pixel[2]), alpha]
+}
+
+/// Inverse of `premultiply`, rounding back to 8 bits. Fully transparent results are
+/// transparent black
+fn unpremultiply(pixel: [f32; 4]) -> Rgba<u8> {
+    let alpha = pixel[3].clamp(0.0, 1.0);
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |v: f32| (v / alpha * 255.0).round().clamp(0.0, 255.0) as u8;
+    Rgba([channel(pixel[0]), channel(pixel[1]), channel(pixel[2]), (alpha * 255.0).round() as u8])
+}
+
+/// Resample `img` to `x_weights.len()` x `y_weights.len()`: each output column (row)
+/// is the weighted sum of the source columns (rows) listed for it. Filters premultiplied
+/// alpha, so transparent pixels' colors don't leak into their neighbors. `progress`
+/// receives the completed fraction after each row of each pass
+fn resample(
+    img: &RgbaImage,
+    x_weights: &[Vec<(u32, f32)>],
+    y_weights: &[Vec<(u32, f32)>],
+    progress: &mut dyn FnMut(f32),
+) -> RgbaImage {
+    let (width, height) = (x_weights.len() as u32, y_weights.len() as u32);
+    let src_height = img.height();
+    let total_rows = (src_height + height) as f32;
+
+    let mut horizontal = vec![[0.0f32; 4]; (width * src_height) as usize];
+    for y in 0..src_height {
+        for (x, weights) in x_weights.iter().enumerate() {
+            let mut sum = [0.0f32; 4];
+            for &(sx, weight) in weights {
+                let pixel = premultiply(img.get_pixel(sx, y));
+                for c in 0..4 {
+                    sum[c] += weight * pixel[c];
+                }
+            }
+            horizontal[(y * width) as usize + x] = sum;
+        }
+        progress((y + 1) as f32 / total_rows);
+    }
+
+    let mut resized = RgbaImage::new(width, height);
+    for (y, weights) in y_weights.iter().enumerate() {
+        for x in 0..width {
+            let mut sum = [0.0f32; 4];
+            for &(sy, weight) in weights {
+                let row = horizontal[(sy * width + x) as usize];
+                for c in 0..4 {
+                    sum[c] += weight * row[c];
+                }
+            }
+            resized.put_pixel(x, y as u32, unpremultiply(sum));
+        }
+        progress((src_height + y as u32 + 1) as f32 / total_rows);
+    }
+    resized
+}
+
+/// For each of `dst_len` output cells, the source indices `filter` draws on and their
+/// normalized weights when `src_len` cells are scaled onto `dst_len`. Indices past either
+/// end are clamped to the edge
+fn filter_weights(src_len: u32, dst_len: u32, filter: ResizeFilter) -> Vec<Vec<(u32, f32)>> {
+    let scale = src_len as f32 / dst_len as f32;
+    if filter == ResizeFilter::Nearest {
+        return (0..dst_len)
+            .map(|i| vec![((((i as f32 + 0.5) * scale) as u32).min(src_len - 1), 1.0)])
+            .collect();
+    }
+    // Stretch the kernel when shrinking so it still covers every source pixel
+    let stretch = scale.max(1.0);
+    let radius = filter.support() * stretch;
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale - 0.5;
+            let first = (center - radius).floor() as i64;
+            let last = (center + radius).ceil() as i64;
+            let mut weights: Vec<(u32, f32)> = (first..=last)
+                .map(|s| (s.clamp(0, src_len as i64 - 1) as u32, filter.weight((s as f32 - center) / stretch)))
+                .filter(|&(_, weight)| weight != 0.0)
+                .collect();
+            let total: f32 = weights.iter().map(|&(_, weight)| weight).sum();
+            if total == 0.0 {
+                return vec![((center.round().max(0.0) as u32).min(src_len - 1), 1.0)];
+            }
+            for (_, weight) in &mut weights {
+                *weight /= total;
+            }
+            weights
+        })
+        .collect()
+}
+
+/// Normalized sinc, sin(pi x) / (pi x)
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+/// For each of `dst_len` output cells, the source indices it covers and their normalized
+/// coverage weights when `src_len` source cells are shrunk onto it
+fn area_weights(src_len: u32, dst_len: u32) -> Vec<Vec<(u32, f32)>> {
+    let scale = src_len as f64 / dst_len as f64;
+    (0..dst_len)
+        .map(|i| {
+            let start = i as f64 * scale;
+            let end = start + scale;
+            let first = start.floor() as u32;
+            let last = (end.ceil() as u32).min(src_len);
+            (first..last)
+                .map(|s| {
+                    let overlap = (end.min(s as f64 + 1.0) - start.max(s as f64)).max(0.0);
+                    (s, (overlap / scale) as f32)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Normalized 1D gaussian kernel, truncated at two standard deviations like imageproc's
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (2.0 * sigma).ceil() as i64;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|v| v / sum).collect()
+}
+
+/// Gaussian blur with clamped edges, as `blur` applies
+fn gaussian_blur(img: &RgbaImage, sigma: f32) -> RgbaImage {
+    separable_filter(img, &gaussian_kernel(sigma), EdgeMode::Clamp, &mut |_| {})
+}
+
+/// Correlate `img` with `kernel` along rows and then along columns, keeping the intermediate
+/// result in floating point. `progress` receives the completed fraction after each row of each pass
+fn separable_filter(
+    img: &RgbaImage,
+    kernel: &[f32],
+    edge_mode: EdgeMode,
+    progress: &mut dyn FnMut(f32),
+) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let r = (kernel.len() / 2) as i64;
+    let total_rows = 2.0 * height as f32;
+
+    let mut horizontal = vec![[0.0f32; 4]; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0.0f32; 4];
+            for (i, weight) in kernel.iter().enumerate() {
+                let pixel = edge_mode.sample(img, x as i64 + i as i64 - r, y as i64);
+                for c in 0..4 {
+                    sum[c] += weight * pixel[c] as f32;
+                }
+            }
+            horizontal[(y * width + x) as usize] = sum;
+        }
+        progress((y + 1) as f32 / total_rows);
+    }
+
+    let outside = match edge_mode {
+        EdgeMode::Constant(color) => color.0.map(|v| v as f32),
+        _ => [0.0; 4],
+    };
+    // Run the vertical pass along contiguous columns of the transposed result
+    let columns = transpose(&horizontal, width as usize, height as usize);
+    let mut vertical = vec![[0u8; 4]; columns.len()];
+    for x in 0..width as usize {
+        let column = &columns[x * height as usize..(x + 1) * height as usize];
+        for y in 0..height as usize {
+            let mut sum = [0.0f32; 4];
+            for (i, weight) in kernel.iter().enumerate() {
+                let row = match edge_mode.resolve(y as i64 + i as i64 - r, height) {
+                    Some(row) => column[row as usize],
+                    None => outside,
+                };
+                for c in 0..4 {
+                    sum[c] += weight * row[c];
+                }
+            }
+            vertical[x * height as usize + y] = sum.map(|v| v.round().clamp(0.0, 255.0) as u8);
+        }
+        progress((height as f32 + (x + 1) as f32 * height as f32 / width as f32) / total_rows);
+    }
+    let out = transpose(&vertical, height as usize, width as usize);
+    ImageBuffer::from_fn(width, height, |x, y| Rgba(out[(y * width + x) as usize]))
+}
+
+/// Transpose a row-major `width` x `height` grid into a `height` x `width` one. Copies
+/// square tiles of `TRANSPOSE_TILE` so reads and writes both stay within a few cache
+/// lines, instead of striding across the whole output on every write
+fn transpose<T: Copy + Default>(src: &[T], width: usize, height: usize) -> Vec<T> {
+    let mut out = vec![T::default(); src.len()];
+    for tile_y in (0..height).step_by(TRANSPOSE_TILE) {
+        for tile_x in (0..width).step_by(TRANSPOSE_TILE) {
+            for y in tile_y..(tile_y + TRANSPOSE_TILE).min(height) {
+                for x in tile_x..(tile_x + TRANSPOSE_TILE).min(width) {
+                    out[x * height + y] = src[y * width + x];
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Quarter turn clockwise: transpose, then mirror each row
+fn rotate90_pixels(img: &RgbaImage) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let pixels: Vec<[u8; 4]> = img.pixels().map(|p| p.0).collect();
+    let transposed = transpose(&pixels, width as usize, height as usize);
+    ImageBuffer::from_fn(height, width, |x, y| Rgba(transposed[(y * height + height - 1 - x) as usize]))
+}
+
+/// Quarter turn counter-clockwise: transpose, then mirror each column
+fn rotate270_pixels(img: &RgbaImage) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let pixels: Vec<[u8; 4]> = img.pixels().map(|p| p.0).collect();
+    let transposed = transpose(&pixels, width as usize, height as usize);
+    ImageBuffer::from_fn(height, width, |x, y| Rgba(transposed[((width - 1 - y) * height + x) as usize]))
+}
+
+/// Convert RGB to (hue in degrees 0..360, saturation 0..=1, lightness 0..=1)
+fn rgb_to_hsl(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return (0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    (hue_degrees(r, g, b, max, delta), saturation, lightness)
+}
+
+/// Convert RGB to (hue in degrees 0..360, saturation 0..=1, value 0..=1)
+fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return (0.0, 0.0, max);
+    }
+    (hue_degrees(r, g, b, max, delta), delta / max, max)
+}
+
+/// Hue shared by HSL and HSV, given normalized channels, their max and max - min (> 0)
+fn hue_degrees(r: f32, g: f32, b: f32, max: f32, delta: f32) -> f32 {
+    if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    }
+}
+
+/// Convert (hue in degrees, saturation 0..=1, lightness 0..=1) back to RGB
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    chroma_to_rgb(hue, chroma, lightness - chroma / 2.0)
+}
+
+/// Convert (hue in degrees, saturation 0..=1, value 0..=1) back to RGB
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+    let chroma = value * saturation;
+    chroma_to_rgb(hue, chroma, value - chroma)
+}
+
+/// Build RGB from a hue, chroma and the offset `m` added to every channel
+fn chroma_to_rgb(hue: f32, chroma: f32, m: f32) -> [u8; 3] {
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    [r, g, b].map(|v| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+/// Push a pixel's color away from its blurred counterpart by `amount` (unsharp masking)
+fn unsharp_pixel(pixel: &Rgba<u8>, blurred: &Rgba<u8>, amount: f32) -> Rgba<u8> {
+    let mut out = *pixel;
+    for c in 0..3 {
+        let detail = pixel[c] as f32 - blurred[c] as f32;
+        out[c] = (pixel[c] as f32 + amount * detail).round().clamp(0.0, 255.0) as u8;
+    }
+    out
+}
+
+/// Composite `color` over `base` using `color`'s alpha (source-over)
+fn blend_pixel(base: &mut Rgba<u8>, color: Rgba<u8>) {
+    let src_alpha = color[3] as f32 / 255.0;
+    if src_alpha == 0.0 {
+        return;
+    }
+    let dst_alpha = base[3] as f32 / 255.0;
+    let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+    for c in 0..3 {
+        let value = (color[c] as f32 * src_alpha + base[c] as f32 * dst_alpha * (1.0 - src_alpha)) / out_alpha;
+        base[c] = value.round().clamp(0.0, 255.0) as u8;
+    }
+    base[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Blend `color` over `img` wherever `coverage` is non-zero, scaling its alpha by the coverage
+fn blend_coverage(img: &mut RgbaImage, coverage: &ImageBuffer<Luma<u8>, Vec<u8>>, color: Rgba<u8>) {
+    for (pixel, amount) in img.pixels_mut().zip(coverage.pixels()) {
+        if amount[0] > 0 {
+            let mut covered = color;
+            covered[3] = (color[3] as f32 * amount[0] as f32 / 255.0).round() as u8;
+            blend_pixel(pixel, covered);
+        }
+    }
+}
+
+/// Snap a channel value to the nearest of `levels` evenly spaced values in 0..=255
+fn posterize_channel(value: u8, levels: u8) -> u8 {
+    let steps = (levels - 1) as f32;
+    ((value as f32 / 255.0 * steps).round() / steps * 255.0).round() as u8
+}
+
+/// Decode an sRGB channel value to linear light (0.0..=1.0)
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode linear light (0.0..=1.0) back to an sRGB channel value
+fn linear_to_srgb(value: f32) -> u8 {
+    let v = value.clamp(0.0, 1.0);
+    let encoded = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+/// Lay out one line of text, calling `f` with each outlined glyph positioned relative to
+/// the line's top-left corner. Returns the line's advance width
+fn layout_line(font: &impl Font, scale: f32, text: &str, mut f: impl FnMut(OutlinedGlyph)) -> f32 {
+    let scaled = font.as_scaled(scale);
+    let mut x = 0.0;
+    let mut last = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(last) = last {
+            x += scaled.kern(last, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(x, scaled.ascent()));
+        x += scaled.h_advance(id);
+        last = Some(id);
+        if let Some(outlined) = font.outline_glyph(glyph) {
+            f(outlined);
+        }
+    }
+    x
+}
+
+/// Advance width of a single line of text
+fn text_width(font: &impl Font, scale: f32, text: &str) -> f32 {
+    layout_line(font, scale, text, |_| {})
+}
+
+/// Draw one line of text with its top-left corner at `origin`, blending glyph coverage
+/// with `color`'s alpha and clipping to `clip` (min x, min y, max x, max y; exclusive)
+fn draw_text_line(
+    img: &mut RgbaImage,
+    text: &str,
+    origin: (f32, f32),
+    font: &impl Font,
+    scale: f32,
+    color: Rgba<u8>,
+    clip: (u32, u32, u32, u32),
+) {
+    let clip = (clip.0, clip.1, clip.2.min(img.width()), clip.3.min(img.height()));
+    layout_line(font, scale, text, |glyph| {
+        let bounds = glyph.px_bounds();
+        glyph.draw(|gx, gy, coverage| {
+            let px = (origin.0 + bounds.min.x).round() as i64 + gx as i64;
+            let py = (origin.1 + bounds.min.y).round() as i64 + gy as i64;
+            if px < clip.0 as i64 || py < clip.1 as i64 || px >= clip.2 as i64 || py >= clip.3 as i64 {
+                return;
+            }
+            let mut glyph_color = color;
+            glyph_color[3] = (color[3] as f32 * coverage.clamp(0.0, 1.0)).round() as u8;
+            blend_pixel(img.get_pixel_mut(px as u32, py as u32), glyph_color);
+        });
+    });
+}
+
+/// Greedily word-wrap `text` so each line fits `max_width`, breaking words that are
+/// longer than a whole line
+fn wrap_text(text: &str, font: &impl Font, scale: f32, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let candidate = if current.is_empty() { word.to_string() } else { format!("{} {}", current, word) };
+        if text_width(font, scale, &candidate) <= max_width {
+            current = candidate;
+            continue;
+        }
+        if !current.is_empty() {
+            lines.push(std::mem::take(&mut current));
+        }
+        for c in word.chars() {
+            current.push(c);
+            if text_width(font, scale, &current) > max_width && current.chars().count() > 1 {
+                current.pop();
+                lines.push(std::mem::replace(&mut current, c.to_string()));
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Rec. 709 luminance of a pixel, in the 0.0..=255.0 range
+fn luminance(pixel: &Rgba<u8>) -> f32 {
+    0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32
 }
 
 // Example usage
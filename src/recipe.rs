@@ -0,0 +1,70 @@
+//! Serde representations for recipe parameters whose types don't implement serde
+//! themselves, for use with `#[serde(with = "...")]` on `Operation` fields
+
+use image::{ColorType, Rgba};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A color as its `[r, g, b, a]` channels
+pub(crate) mod rgba {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(color: &Rgba<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        color.0.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rgba<u8>, D::Error> {
+        <[u8; 4]>::deserialize(deserializer).map(Rgba)
+    }
+}
+
+/// Gradient stops as `[position, [r, g, b, a]]` pairs
+pub(crate) mod gradient_stops {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(stops: &[(f32, Rgba<u8>)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(stops.iter().map(|&(position, color)| (position, color.0)))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(f32, Rgba<u8>)>, D::Error> {
+        let stops = Vec::<(f32, [u8; 4])>::deserialize(deserializer)?;
+        Ok(stops.into_iter().map(|(position, color)| (position, Rgba(color))).collect())
+    }
+}
+
+/// A color type as its lower-case name, e.g. "rgba8". Only the types `convert_to`
+/// accepts have names
+pub(crate) mod color_type {
+    use super::*;
+
+    const NAMES: [(ColorType, &str); 10] = [
+        (ColorType::L8, "l8"),
+        (ColorType::La8, "la8"),
+        (ColorType::Rgb8, "rgb8"),
+        (ColorType::Rgba8, "rgba8"),
+        (ColorType::L16, "l16"),
+        (ColorType::La16, "la16"),
+        (ColorType::Rgb16, "rgb16"),
+        (ColorType::Rgba16, "rgba16"),
+        (ColorType::Rgb32F, "rgb32f"),
+        (ColorType::Rgba32F, "rgba32f"),
+    ];
+
+    pub(crate) fn serialize<S: Serializer>(color: &ColorType, serializer: S) -> Result<S::Ok, S::Error> {
+        let (_, name) = NAMES
+            .iter()
+            .find(|(known, _)| known == color)
+            .ok_or_else(|| S::Error::custom(format!("color type {:?} has no recipe name", color)))?;
+        serializer.serialize_str(name)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ColorType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        NAMES
+            .iter()
+            .find(|(_, known)| *known == name)
+            .map(|&(color, _)| color)
+            .ok_or_else(|| D::Error::custom(format!("unknown color type '{}'", name)))
+    }
+}
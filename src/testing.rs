@@ -0,0 +1,98 @@
+//! Golden-image assertions for tests, this crate's and (with the `testing` feature)
+//! those of pipelines built on it
+
+use image::{DynamicImage, GenericImageView};
+
+/// Panic unless `actual` and `expected` have the same dimensions and no RGBA channel differs
+/// by more than `tolerance`. The panic message gives the number of differing pixels, the
+/// largest channel difference and the first differing coordinate with both pixel values
+#[track_caller]
+pub fn assert_images_eq(actual: &DynamicImage, expected: &DynamicImage, tolerance: u8) {
+    assert!(
+        actual.dimensions() == expected.dimensions(),
+        "Images differ in size: {}x{} vs {}x{}",
+        actual.width(),
+        actual.height(),
+        expected.width(),
+        expected.height()
+    );
+
+    let (actual, expected) = (actual.to_rgba8(), expected.to_rgba8());
+    let mut differing = 0usize;
+    let mut max_delta = 0u8;
+    let mut first = None;
+    for ((x, y, a), e) in actual.enumerate_pixels().zip(expected.pixels()) {
+        let delta = a.0.iter().zip(e.0.iter()).map(|(&a, &e)| a.abs_diff(e)).max().unwrap_or(0);
+        max_delta = max_delta.max(delta);
+        if delta > tolerance {
+            differing += 1;
+            first.get_or_insert((x, y, *a, *e));
+        }
+    }
+
+    if let Some((x, y, a, e)) = first {
+        panic!(
+            "Images differ beyond tolerance {}: {} of {} pixels differ, max channel delta {}, \
+             first at ({}, {}): {:?} vs {:?}",
+            tolerance,
+            differing,
+            actual.width() as usize * actual.height() as usize,
+            max_delta,
+            x,
+            y,
+            a.0,
+            e.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::panic;
+
+    fn panic_message(result: std::thread::Result<()>) -> String {
+        let payload = result.expect_err("assertion should have failed");
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn identical_images_pass() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 6, |x, y| Rgba([x as u8 * 30, y as u8 * 40, 7, 255])));
+        assert_images_eq(&img, &img.clone(), 0);
+    }
+
+    #[test]
+    fn differences_within_tolerance_pass() {
+        let a = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255])));
+        let b = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([102, 99, 100, 255])));
+        assert_images_eq(&a, &b, 2);
+    }
+
+    #[test]
+    fn single_differing_pixel_reports_count_delta_and_position() {
+        let a = DynamicImage::ImageRgba8(RgbaImage::from_pixel(5, 5, Rgba([10, 20, 30, 255])));
+        let mut b = a.to_rgba8();
+        b.put_pixel(3, 1, Rgba([10, 20, 90, 255]));
+        let b = DynamicImage::ImageRgba8(b);
+
+        let message = panic_message(panic::catch_unwind(|| assert_images_eq(&a, &b, 0)));
+        assert!(message.contains("1 of 25 pixels differ"), "{}", message);
+        assert!(message.contains("max channel delta 60"), "{}", message);
+        assert!(message.contains("first at (3, 1)"), "{}", message);
+        assert!(message.contains("[10, 20, 30, 255] vs [10, 20, 90, 255]"), "{}", message);
+    }
+
+    #[test]
+    fn size_mismatch_is_reported() {
+        let a = DynamicImage::ImageRgba8(RgbaImage::new(4, 3));
+        let b = DynamicImage::ImageRgba8(RgbaImage::new(3, 4));
+        let message = panic_message(panic::catch_unwind(|| assert_images_eq(&a, &b, 255)));
+        assert!(message.contains("4x3 vs 3x4"), "{}", message);
+    }
+}
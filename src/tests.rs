@@ -0,0 +1,2558 @@
+use super::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Wraps the system allocator to track the bytes allocated by the current thread, so
+/// tests can check an operation's memory use without other tests interfering
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK_ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| {
+            allocated.set(allocated.get() + layout.size());
+            let _ = PEAK_ALLOCATED.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+        });
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get().saturating_sub(layout.size())));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Run `f` and return its result with the peak number of bytes it had allocated at once
+/// (beyond what was live when it started) and how many allocations it made
+fn measure_allocations<T>(f: impl FnOnce() -> T) -> (T, usize, usize) {
+    let baseline = ALLOCATED.with(Cell::get);
+    PEAK_ALLOCATED.with(|peak| peak.set(baseline));
+    let count = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    let peak = PEAK_ALLOCATED.with(Cell::get) - baseline;
+    (result, peak, ALLOCATIONS.with(Cell::get) - count)
+}
+
+/// Fastest of `runs` timings of `f`. Only the `#[ignore]`d benchmarks use this; wall-clock
+/// comparisons are too noisy for the default suite
+fn best_time(runs: usize, mut f: impl FnMut()) -> std::time::Duration {
+    (0..runs)
+        .map(|_| {
+            let started = Instant::now();
+            f();
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+/// A path in the system temp directory that is unique to this test process
+fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("image_processor_test_{}_{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// DejaVu Sans Mono, checked in under tests/fixtures with its license
+fn test_font() -> ab_glyph::FontRef<'static> {
+    ab_glyph::FontRef::try_from_slice(include_bytes!("../tests/fixtures/DejaVuSansMono.ttf")).unwrap()
+}
+
+fn processor(img: RgbaImage) -> ImageProcessor {
+    ImageProcessor::from_dynamic_image(DynamicImage::ImageRgba8(img))
+}
+
+/// Opaque test pattern: red ramps left to right, green top to bottom, blue is constant
+fn gradient(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        Rgba([(x * 255 / (width - 1).max(1)) as u8, (y * 255 / (height - 1).max(1)) as u8, 96, 255])
+    })
+}
+
+/// Opaque black and white checkerboard with `cell`-pixel squares
+fn checkerboard(width: u32, height: u32, cell: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let v = if (x / cell + y / cell).is_multiple_of(2) { 255 } else { 0 };
+        Rgba([v, v, v, 255])
+    })
+}
+
+/// The operation name and detail of an `OperationError`, panicking on anything else
+fn operation_error<T>(result: Result<T, ImageError>) -> (String, String) {
+    match result {
+        Err(ImageError::OperationError { op, detail }) => (op, detail),
+        Err(other) => panic!("expected an OperationError, got {:?}", other),
+        Ok(_) => panic!("expected an OperationError, got Ok"),
+    }
+}
+
+#[test]
+fn failing_crop_names_crop() {
+    let mut p = processor(gradient(10, 10));
+    let (op, detail) = operation_error(p.crop(5, 5, 10, 10));
+    assert_eq!(op, "crop");
+    assert!(!detail.is_empty());
+}
+
+#[test]
+fn save_errors_name_the_save_method() {
+    let p = processor(gradient(4, 4));
+    let missing_dir = temp_path("missing_dir/out");
+    assert_eq!(operation_error(p.save_tiff(&format!("{}.tiff", missing_dir))).0, "save_tiff");
+    assert_eq!(operation_error(p.save_bmp(&format!("{}.bmp", missing_dir))).0, "save_bmp");
+    assert_eq!(operation_error(p.save_tga(&format!("{}.tga", missing_dir))).0, "save_tga");
+    assert_eq!(operation_error(p.save_png16(&format!("{}.png", missing_dir))).0, "save_png16");
+    #[cfg(feature = "jpeg-encoder")]
+    assert_eq!(
+        operation_error(p.save_jpeg_progressive(&temp_path("never.jpg"), 0, ChromaSubsampling::default())).0,
+        "save_jpeg_progressive"
+    );
+}
+
+/// Small images fit in the `BufWriter` buffer, so /dev/full only fails them on the final flush
+#[cfg(target_os = "linux")]
+#[test]
+fn save_errors_surface_a_failed_final_write() {
+    let p = processor(gradient(4, 4));
+    assert_eq!(operation_error(p.save_tiff("/dev/full")).0, "save_tiff");
+    assert_eq!(operation_error(p.save_bmp("/dev/full")).0, "save_bmp");
+    assert_eq!(operation_error(p.save_tga("/dev/full")).0, "save_tga");
+    assert_eq!(operation_error(p.save_png_optimized("/dev/full", PngCompression::Best)).0, "save_png_optimized");
+    assert_eq!(operation_error(p.save_png16("/dev/full")).0, "save_png16");
+}
+
+#[test]
+fn region_errors_name_the_caller() {
+    let path = temp_path("region_names.png");
+    processor(gradient(8, 8)).save(&path).unwrap();
+    let (op, detail) = operation_error(ImageProcessor::open_region(&path, 6, 0, 4, 4));
+    assert_eq!(op, "open_region");
+    assert!(detail.contains("4x4 at (6, 0)"), "{}", detail);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn recipe_errors_name_apply_recipe() {
+    let mut p = processor(gradient(4, 4));
+    let (op, _) = operation_error(p.apply_recipe(r#"[{"op":"blur"}]"#));
+    assert_eq!(op, "apply_recipe");
+}
+
+#[test]
+fn paste_matches_overlay_image() {
+    let top = RgbaImage::from_fn(6, 4, |x, y| Rgba([200, 40 * y as u8, 30 * x as u8, 60 + 30 * x as u8]));
+    let mut pasted = processor(gradient(16, 12));
+    pasted.paste(&processor(top.clone()), 5, 3).unwrap();
+    let mut overlaid = processor(gradient(16, 12));
+    overlaid.overlay_image(&DynamicImage::ImageRgba8(top), 5, 3).unwrap();
+    assert_eq!(pasted.get_image().to_rgba8(), overlaid.get_image().to_rgba8());
+}
+
+#[test]
+fn paste_replace_overwrites_including_alpha() {
+    let top = RgbaImage::from_pixel(3, 3, Rgba([10, 20, 30, 128]));
+    let mut p = processor(gradient(8, 8));
+    p.paste_replace(&processor(top), 2, 4).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(2, 4), Rgba([10, 20, 30, 128]));
+    assert_eq!(*out.get_pixel(4, 6), Rgba([10, 20, 30, 128]));
+    assert_eq!(*out.get_pixel(5, 4), *gradient(8, 8).get_pixel(5, 4));
+}
+
+#[test]
+fn paste_offsets_near_u32_max_are_rejected() {
+    let other = processor(RgbaImage::new(2, 1));
+    let mut p = processor(gradient(8, 8));
+    assert_eq!(operation_error(p.paste_replace(&other, u32::MAX, 0)).0, "paste_replace");
+    assert_eq!(operation_error(p.paste(&other, u32::MAX, 0)).0, "overlay_image");
+}
+
+#[test]
+fn recipe_replays_blur_and_grayscale() {
+    let mut original = processor(gradient(24, 16));
+    original.blur(1.5).unwrap().grayscale().unwrap();
+    let json = original.recipe_json().unwrap();
+
+    let mut replayed = processor(gradient(24, 16));
+    replayed.apply_recipe(&json).unwrap();
+    assert_eq!(replayed.get_image(), original.get_image());
+    assert_eq!(replayed.operation_log(), original.operation_log());
+}
+
+#[test]
+fn recipe_replays_ops_with_color_and_tuple_parameters() {
+    let mut original = processor(gradient(20, 20));
+    original
+        .blur(1.0)
+        .unwrap()
+        .grayscale()
+        .unwrap()
+        .rotate_about(30.0, (4.0, 6.0), Rgba([0, 0, 0, 0]))
+        .unwrap()
+        .adjust_saturation(1.3, ColorModel::Hsv)
+        .unwrap()
+        .box_blur(1, EdgeMode::Constant(Rgba([255, 0, 0, 255])))
+        .unwrap()
+        .draw_circle_aa((10, 10), 4, Rgba([0, 200, 0, 128]), true)
+        .unwrap();
+    let json = original.recipe_json().unwrap();
+
+    let mut replayed = processor(gradient(20, 20));
+    replayed.apply_recipe(&json).unwrap();
+    assert_eq!(replayed.get_image(), original.get_image());
+}
+
+#[test]
+fn recipe_json_fails_after_unrecordable_ops() {
+    let mut p = processor(gradient(8, 8));
+    p.blur(1.0).unwrap();
+    p.overlay_image(&DynamicImage::ImageRgba8(RgbaImage::new(2, 2)), 0, 0).unwrap();
+    let (op, detail) = operation_error(p.recipe_json());
+    assert_eq!(op, "recipe_json");
+    assert!(detail.contains("overlay_image"), "{}", detail);
+}
+
+#[test]
+fn recipe_json_rejects_non_finite_parameters() {
+    let mut log = OperationLog::new();
+    log.record(Operation::Blur { sigma: f32::NAN });
+    assert_eq!(operation_error(log.to_json()).0, "recipe_json");
+
+    let mut log = OperationLog::new();
+    log.record(Operation::ColorMatrix { matrix: [[1.0, 0.0, 0.0], [0.0, f32::INFINITY, 0.0], [0.0, 0.0, 1.0]], offset: [0.0; 3] });
+    assert_eq!(operation_error(log.to_json()).0, "recipe_json");
+
+    // f64 values beyond f32's range would otherwise parse as infinity
+    assert_eq!(operation_error(OperationLog::from_json(r#"[{"op":"blur","sigma":1e300}]"#)).0, "apply_recipe");
+}
+
+#[test]
+fn recipe_parsing_is_strict() {
+    let invalid = [
+        r#"[{"op":"unknown"}]"#,
+        r#"[{"op":"adjust_saturation","factor":1.0,"model":"cmyk"}]"#,
+        r#"[{"op":"adjust_saturation","factor":1.0,"model":7}]"#,
+        r#"[{"op":"despill","key":"red","amount":0.5}]"#,
+        r#"[{"op":"crop","x":-1,"y":0,"width":2,"height":2}]"#,
+        r#"[{"op":"crop","x":0.5,"y":0,"width":2,"height":2}]"#,
+        r#"[{"op":"posterize","levels":256}]"#,
+        r#"[{"op":"blur","sigma":1.0,"extra":2}]"#,
+        r#"[{"op":"grayscale"}"#,
+    ];
+    for json in invalid {
+        let (op, detail) = operation_error(OperationLog::from_json(json));
+        assert_eq!(op, "apply_recipe", "{}", json);
+        assert!(detail.starts_with("Invalid recipe"), "{}: {}", json, detail);
+    }
+}
+
+#[test]
+fn recipe_tags_match_operation_names() {
+    let json = r#"[{"op":"rotate_with","angle":10.0,"interpolation":"bicubic"},
+        {"op":"resize_to_multiple","multiple":8,"filter":"lanczos3"},
+        {"op":"convert_to","target":"la8"},
+        {"op":"gradient_map","stops":[[0.0,[0,0,0,255]],[1.0,[255,255,255,255]]]}]"#;
+    let log = OperationLog::from_json(json).unwrap();
+    let names: Vec<_> = log.operations().iter().map(Operation::name).collect();
+    assert_eq!(names, ["rotate_with", "resize_to_multiple", "convert_to", "gradient_map"]);
+    assert_eq!(OperationLog::from_json(&log.to_json().unwrap()).unwrap(), log);
+}
+
+#[test]
+fn clarity_increases_midtone_edge_contrast_and_keeps_color_balance() {
+    // Warm midtone step: channel offsets are the same on both sides
+    let img = RgbaImage::from_fn(64, 16, |x, _| if x < 32 { Rgba([110, 90, 70, 255]) } else { Rgba([160, 140, 120, 255]) });
+    let mut p = processor(img.clone());
+    p.clarity(1.0).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    let (dark, light) = (out.get_pixel(30, 8), out.get_pixel(33, 8));
+    assert!(
+        light[1] as i32 - dark[1] as i32 > 50,
+        "edge contrast did not increase: {:?} vs {:?}",
+        dark,
+        light
+    );
+    for pixel in out.pixels() {
+        assert_eq!(pixel[0] as i32 - pixel[1] as i32, 20, "{:?}", pixel);
+        assert_eq!(pixel[1] as i32 - pixel[2] as i32, 20, "{:?}", pixel);
+    }
+
+    let mut unchanged = processor(img.clone());
+    unchanged.clarity(0.0).unwrap();
+    assert_eq!(unchanged.get_image().to_rgba8(), img);
+
+    // A tiny amount moves no pixel by a whole level in either direction, so it rounds away
+    let mut subtle = processor(img.clone());
+    subtle.clarity(1e-3).unwrap();
+    assert_eq!(subtle.get_image().to_rgba8(), img);
+}
+
+#[test]
+fn edge_modes_differ_at_the_boundary() {
+    // Dark left column on a light row: reflecting keeps the dark pixel as its own
+    // neighbour, wrapping pulls in the light pixel from the far side
+    let img = RgbaImage::from_fn(8, 1, |x, _| if x == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([240, 240, 240, 255]) });
+    let blurred_left = |edge_mode| {
+        let mut p = processor(img.clone());
+        p.box_blur(1, edge_mode).unwrap();
+        p.get_image().to_rgba8().get_pixel(0, 0)[0]
+    };
+    assert_eq!(blurred_left(EdgeMode::Reflect), 80);
+    assert_eq!(blurred_left(EdgeMode::Clamp), 80);
+    assert_eq!(blurred_left(EdgeMode::Wrap), 160);
+
+    let kernel = [1.0 / 3.0; 3];
+    let mut wrapped = processor(img.clone());
+    wrapped.convolve(&kernel, 3, EdgeMode::Wrap).unwrap();
+    assert_eq!(wrapped.get_image().to_rgba8().get_pixel(0, 0)[0], 160);
+    let mut constant = processor(img.clone());
+    constant.convolve(&kernel, 3, EdgeMode::Constant(Rgba([0, 0, 0, 255]))).unwrap();
+    assert_eq!(constant.get_image().to_rgba8().get_pixel(7, 0)[0], 160);
+    assert_eq!(constant.get_image().to_rgba8().get_pixel(6, 0)[0], 240);
+
+    let mut median_wrapped = processor(img.clone());
+    median_wrapped.median_filter(1, EdgeMode::Wrap).unwrap();
+    assert_eq!(median_wrapped.get_image().to_rgba8().get_pixel(0, 0)[0], 240);
+    let mut median_reflected = processor(img);
+    median_reflected.median_filter(1, EdgeMode::Reflect).unwrap();
+    assert_eq!(median_reflected.get_image().to_rgba8().get_pixel(0, 0)[0], 0);
+}
+
+/// Run `op` with a progress callback and check the reported fractions climb to 1.0
+fn assert_progress_completes(op: impl FnOnce(&mut dyn FnMut(f32))) {
+    let mut reported = Vec::new();
+    op(&mut |fraction| reported.push(fraction));
+    assert!(!reported.is_empty());
+    assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", reported);
+    assert!(reported.iter().all(|fraction| (0.0..=1.0).contains(fraction)), "{:?}", reported);
+    assert!((reported.last().unwrap() - 1.0).abs() < 1e-4, "{:?}", reported);
+}
+
+#[test]
+fn heavy_operations_report_monotonic_progress() {
+    let mut p = processor(gradient(40, 30));
+    assert_progress_completes(|progress| {
+        p.blur_with_progress(2.0, progress).unwrap();
+    });
+    assert_progress_completes(|progress| {
+        p.convolve_with_progress(&[0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0], 3, EdgeMode::Clamp, progress)
+            .unwrap();
+    });
+    assert_progress_completes(|progress| {
+        p.resize_with_progress(17, 45, ResizeFilter::Lanczos3, progress).unwrap();
+    });
+}
+
+#[test]
+fn blur_matches_blur_with_progress() {
+    let mut plain = processor(gradient(33, 21));
+    plain.blur(2.5).unwrap();
+    let mut reporting = processor(gradient(33, 21));
+    reporting.blur_with_progress(2.5, &mut |_| {}).unwrap();
+    assert_eq!(plain.get_image(), reporting.get_image());
+
+    for sigma in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+        assert_eq!(operation_error(processor(gradient(4, 4)).blur(sigma)).0, "blur");
+    }
+}
+
+#[test]
+fn resize_keeps_flat_colors_and_transparent_edges() {
+    for filter in [ResizeFilter::Nearest, ResizeFilter::Triangle, ResizeFilter::CatmullRom, ResizeFilter::Gaussian, ResizeFilter::Lanczos3] {
+        let flat = RgbaImage::from_pixel(9, 7, Rgba([30, 120, 200, 255]));
+        for (width, height) in [(4, 3), (20, 15)] {
+            let mut p = processor(flat.clone());
+            p.resize(width, height, filter).unwrap();
+            assert_eq!(p.get_image().to_rgba8(), RgbaImage::from_pixel(width, height, Rgba([30, 120, 200, 255])), "{:?}", filter);
+        }
+
+        // Transparent black next to opaque red must not darken the red
+        let half = RgbaImage::from_fn(8, 2, |x, _| if x < 4 { Rgba([0, 0, 0, 0]) } else { Rgba([255, 0, 0, 255]) });
+        let mut p = processor(half);
+        p.resize(16, 2, filter).unwrap();
+        for pixel in p.get_image().to_rgba8().pixels().filter(|pixel| pixel[3] > 0) {
+            assert_eq!(pixel[0], 255, "{:?}: {:?}", filter, pixel);
+        }
+    }
+    assert_eq!(operation_error(processor(gradient(4, 4)).resize(0, 4, ResizeFilter::Triangle)).0, "resize");
+}
+
+#[test]
+fn crop_view_reads_without_copying() {
+    let source = gradient(1000, 800);
+    let p = processor(source.clone());
+    let (sum, peak, _) = measure_allocations(|| {
+        let view = p.crop_view(100, 50, 600, 500).unwrap();
+        assert_eq!(view.get_pixel(0, 0), Some(*source.get_pixel(100, 50)));
+        assert_eq!(view.get_pixel(600, 0), None);
+        let mut sum = 0u64;
+        for y in 0..view.height() {
+            for x in 0..view.width() {
+                sum += view.get_pixel(x, y).unwrap()[0] as u64;
+            }
+        }
+        sum
+    });
+    assert!(sum > 0);
+    // The viewed region alone is 1.2 MB of RGBA
+    assert!(peak < 64 * 1024, "reading through the view allocated {} bytes", peak);
+}
+
+#[test]
+fn crop_view_rejects_offsets_near_u32_max() {
+    let p = processor(gradient(8, 8));
+    assert_eq!(operation_error(p.crop_view(u32::MAX, 0, 2, 2)).0, "crop_view");
+    assert_eq!(operation_error(p.crop_view(0, 4, 2, u32::MAX)).0, "crop_view");
+    assert!(p.crop_view(6, 6, 2, 2).is_ok());
+}
+
+#[test]
+fn blur_radius_matches_blur_with_a_third_the_sigma() {
+    let mut by_radius = processor(gradient(30, 20));
+    by_radius.blur_radius(3.0).unwrap();
+    let mut by_sigma = processor(gradient(30, 20));
+    by_sigma.blur(1.0).unwrap();
+    crate::testing::assert_images_eq(by_radius.get_image(), by_sigma.get_image(), 1);
+    assert_eq!(operation_error(processor(gradient(4, 4)).blur_radius(0.0)).0, "blur_radius");
+}
+
+#[test]
+fn concat_places_images_side_by_side() {
+    let left = DynamicImage::ImageRgba8(gradient(10, 10));
+    let right = DynamicImage::ImageRgba8(RgbaImage::from_fn(10, 10, |x, y| Rgba([0, x as u8 * 20, y as u8 * 20, 255])));
+    let joined = ImageProcessor::concat_horizontal(&[&left, &right], Rgba([0, 0, 0, 0])).unwrap();
+    assert_eq!(joined.dimensions(), (20, 10));
+    assert_eq!(joined.crop_imm(0, 0, 10, 10).to_rgba8(), left.to_rgba8());
+    assert_eq!(joined.crop_imm(10, 0, 10, 10).to_rgba8(), right.to_rgba8());
+
+    // The shorter image is top-aligned and padded with the background
+    let short = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 6, Rgba([255, 0, 0, 255])));
+    let stacked = ImageProcessor::concat_vertical(&[&short, &left], Rgba([1, 2, 3, 255])).unwrap();
+    assert_eq!(stacked.dimensions(), (10, 16));
+    assert_eq!(stacked.get_pixel(3, 5), Rgba([255, 0, 0, 255]));
+    assert_eq!(stacked.get_pixel(4, 0), Rgba([1, 2, 3, 255]));
+    assert_eq!(stacked.crop_imm(0, 6, 10, 10).to_rgba8(), left.to_rgba8());
+
+    assert_eq!(operation_error(ImageProcessor::concat_horizontal(&[], Rgba([0; 4]))).0, "concat_horizontal");
+}
+
+#[test]
+fn contact_sheet_lays_out_rows_of_cells() {
+    let images: Vec<DynamicImage> = (0..5)
+        .map(|i| DynamicImage::ImageRgba8(RgbaImage::from_pixel(40 + 10 * i, 30, Rgba([50 * i as u8, 0, 0, 255]))))
+        .collect();
+    let sheet = ImageProcessor::contact_sheet(&images, 2, 20, 4, Rgba([255, 255, 255, 255])).unwrap();
+    // 2 columns and 3 rows of 20px cells with 4px around and between them
+    assert_eq!(sheet.dimensions(), (2 * 20 + 3 * 4, 3 * 20 + 4 * 4));
+
+    // The fifth image sits centered in the first cell of the third row; the cell beside it is empty
+    let (cell_x, cell_y) = (4, 4 + 2 * 24);
+    assert_eq!(sheet.get_pixel(cell_x + 10, cell_y + 10), Rgba([200, 0, 0, 255]));
+    assert_eq!(sheet.get_pixel(cell_x + 24 + 10, cell_y + 10), Rgba([255, 255, 255, 255]));
+    assert_eq!(sheet.get_pixel(1, 1), Rgba([255, 255, 255, 255]));
+
+    assert_eq!(operation_error(ImageProcessor::contact_sheet(&images, 0, 20, 4, Rgba([0; 4]))).0, "contact_sheet");
+}
+
+#[test]
+fn colorize_tints_while_keeping_lightness() {
+    let grays = [128u8, 30, 230];
+    let mut p = processor(RgbaImage::from_fn(3, 1, |x, _| {
+        let v = grays[x as usize];
+        Rgba([v, v, v, 255])
+    }));
+    p.colorize(120.0, 0.6).unwrap();
+    let out = p.get_image().to_rgba8();
+    for (x, gray) in grays.into_iter().enumerate() {
+        let pixel = out.get_pixel(x as u32, 0);
+        let (hue, saturation, lightness) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+        let (_, _, original) = rgb_to_hsl(gray, gray, gray);
+        assert!((hue - 120.0).abs() < 2.0, "{:?} has hue {}", pixel, hue);
+        assert!((lightness - original).abs() < 0.01, "{:?}", pixel);
+        if x == 0 {
+            assert!((saturation - 0.6).abs() < 0.02, "{:?}", pixel);
+        }
+    }
+}
+
+#[test]
+fn lossless_formats_round_trip_exactly() {
+    let img = RgbaImage::from_fn(13, 9, |x, y| Rgba([x as u8 * 19, y as u8 * 28, 200, 255 - x as u8 * 10]));
+    let p = processor(img.clone());
+    for (name, save) in [
+        ("tiff", ImageProcessor::save_tiff as fn(&ImageProcessor, &str) -> Result<(), ImageError>),
+        ("bmp", ImageProcessor::save_bmp),
+        ("tga", ImageProcessor::save_tga),
+    ] {
+        let path = temp_path(&format!("round_trip.{}", name));
+        save(&p, &path).unwrap();
+        let loaded = ImageProcessor::new(&path).unwrap();
+        assert_eq!(loaded.get_image().to_rgba8(), img, "{}", name);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn tiff_keeps_16_bit_samples() {
+    let img = ImageBuffer::from_fn(7, 5, |x, y| image::Rgb([x as u16 * 9000 + 1, y as u16 * 13000 + 3, 65535]));
+    let path = temp_path("deep.tiff");
+    ImageProcessor::from_dynamic_image(DynamicImage::ImageRgb16(img.clone())).save_tiff(&path).unwrap();
+    let loaded = ImageProcessor::new(&path).unwrap();
+    assert_eq!(loaded.get_image().color(), ColorType::Rgb16);
+    assert_eq!(loaded.get_image().to_rgb16(), img);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn assertion_checkpoints_report_expected_and_actual() {
+    let p = processor(gradient(12, 7));
+    assert!(p.assert_dimensions(12, 7).and_then(|p| p.assert_has_alpha()).is_ok());
+
+    let (op, detail) = operation_error(p.assert_dimensions(10, 10));
+    assert_eq!(op, "assert_dimensions");
+    assert!(detail.contains("10x10") && detail.contains("12x7"), "{}", detail);
+
+    let opaque = ImageProcessor::from_dynamic_image(DynamicImage::ImageRgb8(image::RgbImage::new(2, 2)));
+    assert!(matches!(
+        opaque.assert_has_alpha(),
+        Err(ImageError::UnsupportedColorType { ref op, color_type: ColorType::Rgb8 }) if op == "assert_has_alpha"
+    ));
+}
+
+#[test]
+fn rotate_crop_inside_leaves_no_transparent_pixels() {
+    for angle in [45.0, 10.0, -30.0, 1.0, 100.0, 200.0] {
+        let mut p = processor(gradient(64, 48));
+        p.rotate_crop_inside(angle).unwrap();
+        let out = p.get_image().to_rgba8();
+        assert!(out.width() > 4 && out.height() > 4, "{}: {:?}", angle, out.dimensions());
+        assert!(out.pixels().all(|pixel| pixel[3] == 255), "{}: transparent pixel in {:?}", angle, out.dimensions());
+    }
+
+    // Small angles lose only the strip the rotation actually uncovers
+    let mut slight = processor(gradient(64, 48));
+    slight.rotate_crop_inside(1.0).unwrap();
+    let (width, height) = slight.dimensions();
+    assert!(width >= 61 && height >= 45, "{}x{}", width, height);
+}
+
+#[test]
+fn rotate_crop_inside_turns_multiples_of_90_exactly() {
+    let source = gradient(64, 48);
+    let turned = |angle| {
+        let mut p = processor(source.clone());
+        p.rotate_crop_inside(angle).unwrap();
+        p.get_image().to_rgba8()
+    };
+    assert_eq!(turned(0.0), source);
+    assert_eq!(turned(360.0), source);
+    assert_eq!(turned(90.0), image::imageops::rotate90(&source));
+    assert_eq!(turned(-270.0), image::imageops::rotate90(&source));
+    assert_eq!(turned(180.0), image::imageops::rotate180(&source));
+    assert_eq!(turned(270.0), image::imageops::rotate270(&source));
+
+    let mut empty = processor(RgbaImage::new(0, 0));
+    assert_eq!(operation_error(empty.rotate_crop_inside(30.0)).0, "rotate_crop_inside");
+    let mut line = processor(RgbaImage::from_pixel(20, 1, Rgba([9, 9, 9, 255])));
+    assert_eq!(operation_error(line.rotate_crop_inside(30.0)).0, "rotate_crop_inside");
+}
+
+#[test]
+fn resize_area_averages_a_checkerboard_to_gray() {
+    let checkerboard = RgbaImage::from_fn(300, 200, |x, y| {
+        if (x + y) % 2 == 0 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+    });
+    let mut p = processor(checkerboard);
+    p.resize_area(37, 23).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(out.dimensions(), (37, 23));
+    for pixel in out.pixels() {
+        for c in 0..3 {
+            assert!((pixel[c] as i32 - 128).abs() <= 4, "moiré: {:?}", pixel);
+        }
+        assert_eq!(pixel[3], 255);
+    }
+}
+
+#[test]
+fn average_and_dominant_colors() {
+    let halves = RgbaImage::from_fn(10, 4, |x, _| if x < 5 { Rgba([200, 0, 100, 255]) } else { Rgba([0, 100, 0, 255]) });
+    assert_eq!(processor(halves).average_color(), Rgba([100, 50, 50, 255]));
+
+    // Transparent pixels don't pull the average towards their color
+    let faded = RgbaImage::from_fn(4, 1, |x, _| if x == 0 { Rgba([255, 255, 255, 0]) } else { Rgba([10, 20, 30, 255]) });
+    assert_eq!(processor(faded).average_color(), Rgba([10, 20, 30, 191]));
+
+    let mostly_teal = RgbaImage::from_fn(10, 10, |x, _| if x < 7 { Rgba([0, 128, 128, 255]) } else { Rgba([250, 10, 10, 255]) });
+    assert_eq!(processor(mostly_teal).dominant_color(), Rgba([0, 128, 128, 255]));
+}
+
+/// The markers of a JPEG stream in order, skipping segment payloads and entropy-coded data
+#[cfg(feature = "jpeg-encoder")]
+fn jpeg_markers(bytes: &[u8]) -> Vec<u8> {
+    let mut markers = Vec::new();
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        assert_eq!(bytes[i], 0xFF, "expected a marker at byte {}", i);
+        let marker = bytes[i + 1];
+        markers.push(marker);
+        i += 2;
+        if marker == 0xD8 || marker == 0xD9 {
+            continue;
+        }
+        i += u16::from_be_bytes([bytes[i], bytes[i + 1]]) as usize;
+        if marker == 0xDA {
+            // Entropy-coded data runs until a marker other than a stuffed zero or a restart
+            while i + 1 < bytes.len() && !(bytes[i] == 0xFF && bytes[i + 1] != 0 && !(0xD0..=0xD7).contains(&bytes[i + 1])) {
+                i += 1;
+            }
+        }
+    }
+    markers
+}
+
+#[cfg(feature = "jpeg-encoder")]
+#[test]
+fn progressive_jpeg_has_sof2_and_several_scans() {
+    let img = gradient(40, 24);
+    let path = temp_path("progressive.jpg");
+    processor(img.clone()).save_jpeg_progressive(&path, 90, ChromaSubsampling::default()).unwrap();
+
+    let markers = jpeg_markers(&fs::read(&path).unwrap());
+    assert_eq!(markers.first(), Some(&0xD8));
+    assert_eq!(markers.last(), Some(&0xD9));
+    assert!(markers.contains(&0xC2), "no SOF2 frame in {:02X?}", markers);
+    assert!(!markers.contains(&0xC0), "baseline SOF0 frame in {:02X?}", markers);
+    let scans = markers.iter().filter(|&&marker| marker == 0xDA).count();
+    assert!(scans > 1, "only {} scan(s)", scans);
+    // All scans come after the frame header
+    let frame = markers.iter().position(|&marker| marker == 0xC2).unwrap();
+    assert!(markers.iter().position(|&marker| marker == 0xDA).unwrap() > frame);
+
+    let decoded = image::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    crate::testing::assert_images_eq(&decoded, &DynamicImage::ImageRgba8(img), 12);
+}
+
+#[cfg(feature = "jpeg-encoder")]
+#[test]
+fn full_resolution_chroma_keeps_more_color_detail() {
+    // One-pixel red/blue stripes: luma barely changes, chroma flips every column
+    let img = RgbaImage::from_fn(32, 32, |x, _| {
+        if x % 2 == 0 {
+            Rgba([220, 30, 40, 255])
+        } else {
+            Rgba([30, 60, 220, 255])
+        }
+    });
+    let color_error = |subsampling: ChromaSubsampling, progressive: bool| {
+        let path = temp_path(&format!("chroma_{:?}_{}.jpg", subsampling, progressive));
+        let p = processor(img.clone());
+        if progressive {
+            p.save_jpeg_progressive(&path, 95, subsampling).unwrap();
+        } else {
+            p.save_jpeg(&path, 95, subsampling).unwrap();
+        }
+        let decoded = image::open(&path).unwrap().to_rgb8();
+        fs::remove_file(&path).unwrap();
+        decoded
+            .pixels()
+            .zip(img.pixels())
+            .map(|(a, b)| (0..3).map(|c| (a[c] as f64 - b[c] as f64).abs()).sum::<f64>())
+            .sum::<f64>()
+            / (32.0 * 32.0)
+    };
+    for progressive in [false, true] {
+        let full = color_error(ChromaSubsampling::Yuv444, progressive);
+        let half = color_error(ChromaSubsampling::Yuv420, progressive);
+        assert!(full * 4.0 < half, "4:4:4 error {} vs 4:2:0 error {}", full, half);
+    }
+}
+
+/// Mean of each RGB channel over all pixels
+fn channel_means(img: &RgbaImage) -> [f64; 3] {
+    let count = (img.width() * img.height()) as f64;
+    let mut sums = [0.0; 3];
+    for pixel in img.pixels() {
+        for c in 0..3 {
+            sums[c] += pixel[c] as f64;
+        }
+    }
+    sums.map(|sum| sum / count)
+}
+
+#[test]
+fn auto_white_balance_removes_a_blue_cast() {
+    let cast = RgbaImage::from_fn(32, 32, |x, y| {
+        let base = (x * 4 + y * 2) as u8;
+        Rgba([base, base.saturating_add(10), base.saturating_add(70), 255])
+    });
+    let before = channel_means(&cast);
+    assert!(before[2] - before[0] > 60.0);
+
+    let mut p = processor(cast);
+    p.auto_white_balance().unwrap();
+    let after = channel_means(&p.get_image().to_rgba8());
+    let spread = after.iter().cloned().fold(f64::MIN, f64::max) - after.iter().cloned().fold(f64::MAX, f64::min);
+    assert!(spread < 3.0, "channel means still differ: {:?}", after);
+}
+
+/// A `.cube` LUT of the given size whose entries are `f` of each grid point
+fn write_cube(name: &str, size: usize, f: impl Fn([f32; 3]) -> [f32; 3]) -> String {
+    let mut text = format!("TITLE \"test\"\nLUT_3D_SIZE {}\n", size);
+    let step = |i: usize| i as f32 / (size - 1) as f32;
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                let [r, g, b] = f([step(r), step(g), step(b)]);
+                text.push_str(&format!("{} {} {}\n", r, g, b));
+            }
+        }
+    }
+    let path = temp_path(name);
+    fs::write(&path, text).unwrap();
+    path
+}
+
+#[test]
+fn identity_and_inverting_luts() {
+    let img = gradient(20, 12);
+    for size in [2, 5, 17] {
+        let identity = write_cube(&format!("identity_{}.cube", size), size, |rgb| rgb);
+        let mut p = processor(img.clone());
+        p.apply_lut_cube(&identity).unwrap();
+        assert_eq!(p.get_image().to_rgba8(), img, "size {}", size);
+        fs::remove_file(&identity).unwrap();
+    }
+
+    let inverting = write_cube("inverting.cube", 2, |rgb| rgb.map(|v| 1.0 - v));
+    let mut graded = processor(img.clone());
+    graded.apply_lut_cube(&inverting).unwrap();
+    let mut inverted = processor(img);
+    inverted.invert().unwrap();
+    assert_eq!(graded.get_image(), inverted.get_image());
+    fs::remove_file(&inverting).unwrap();
+}
+
+#[test]
+fn malformed_luts_are_rejected() {
+    let path = temp_path("bad.cube");
+    for text in ["LUT_3D_SIZE 2\n0 0 0\n", "LUT_3D_SIZE 1\n0 0 0\n", "0 0 0\n", "LUT_3D_SIZE 2\nDOMAIN_MIN 1 1 1\nDOMAIN_MAX 0 0 0\n"] {
+        fs::write(&path, text).unwrap();
+        assert!(matches!(processor(gradient(2, 2)).apply_lut_cube(&path), Err(ImageError::LoadError(_))), "{:?}", text);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn seam_carving_removes_background_first() {
+    // A busy checkered object on a flat gray background
+    let is_object = |x: u32, y: u32| (12..22).contains(&x) && (6..18).contains(&y);
+    let img = RgbaImage::from_fn(48, 24, |x, y| {
+        if is_object(x, y) {
+            if (x + y) % 2 == 0 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+        } else {
+            Rgba([120, 120, 120, 255])
+        }
+    });
+    let mut p = processor(img);
+    p.seam_carve(36, 20).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(out.dimensions(), (36, 20));
+
+    // Every object pixel survives, still in a 10x12 block of alternating black and white
+    let object: Vec<(u32, u32, Rgba<u8>)> = out
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[0] != 120)
+        .map(|(x, y, pixel)| (x, y, *pixel))
+        .collect();
+    assert_eq!(object.len(), 10 * 12);
+    let (left, top) = (object[0].0, object[0].1);
+    for (x, y, pixel) in object {
+        assert!((left..left + 10).contains(&x) && (top..top + 12).contains(&y), "({}, {})", x, y);
+        assert_eq!(pixel[0] == 255, (x - left + y - top) % 2 == 0);
+    }
+    assert_eq!(operation_error(processor(gradient(8, 8)).seam_carve(9, 8)).0, "seam_carve");
+}
+
+#[test]
+fn layer_stack_flattens_like_manual_compositing() {
+    let red = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255])));
+    let blue = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 128])));
+    let mut stack = LayerStack::new(8, 8);
+    stack.add_layer(Layer::new("red", red)).unwrap();
+    stack.add_layer(Layer { x: 2, y: 3, ..Layer::new("blue", blue) }).unwrap();
+
+    let alpha = 128.0f32 / 255.0;
+    let mixed = Rgba([(255.0 * (1.0 - alpha)).round() as u8, 0, (255.0 * alpha).round() as u8, 255]);
+    let mut expected = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+    for y in 3..7 {
+        for x in 2..6 {
+            expected.put_pixel(x, y, mixed);
+        }
+    }
+    assert_eq!(stack.flatten().to_rgba8(), expected);
+
+    // Opacity scales the layer's alpha; moving red on top hides blue entirely
+    stack.layer_mut("blue").unwrap().opacity = 0.0;
+    assert_eq!(stack.flatten().to_rgba8(), RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255])));
+    stack.layer_mut("blue").unwrap().opacity = 1.0;
+    stack.reorder("red", 1).unwrap();
+    assert_eq!(stack.layers()[1].name, "red");
+    assert_eq!(stack.flatten().to_rgba8(), RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255])));
+
+    assert_eq!(stack.remove_layer("red").unwrap().name, "red");
+    assert_eq!(stack.flatten().get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+    assert!(stack.remove_layer("red").is_err());
+    assert_eq!(operation_error(stack.add_layer(Layer::new("blue", DynamicImage::new_rgba8(1, 1)))).0, "add_layer");
+}
+
+#[test]
+fn edge_aware_sharpening_skips_flat_noise() {
+    // Faint noise on a flat dark half, then a hard edge to a light half
+    let noise = |x: u32, y: u32| ((x * 7 + y * 13) % 5) as u8;
+    let img = RgbaImage::from_fn(32, 16, |x, y| {
+        let v = if x < 16 { 100 + noise(x, y) } else { 180 };
+        Rgba([v, v, v, 255])
+    });
+    let mut p = processor(img.clone());
+    p.sharpen_edge_aware(1.5, 1.5, 12).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    for y in 0..16 {
+        // Away from the edge the noise is left exactly as it was
+        for x in 0..12 {
+            assert_eq!(out.get_pixel(x, y), img.get_pixel(x, y), "({}, {})", x, y);
+        }
+        assert!(out.get_pixel(15, y)[0] < 100, "{:?}", out.get_pixel(15, y));
+        assert!(out.get_pixel(16, y)[0] > 180, "{:?}", out.get_pixel(16, y));
+    }
+    assert_eq!(operation_error(processor(gradient(4, 4)).sharpen_edge_aware(0.0, 1.0, 0)).0, "sharpen_edge_aware");
+}
+
+#[test]
+fn crop_to_content_ignores_stray_specks() {
+    let white = Rgba([255, 255, 255, 255]);
+    let mut img = RgbaImage::from_pixel(40, 30, white);
+    for y in 10..22 {
+        for x in 12..30 {
+            img.put_pixel(x, y, Rgba([200, 30, 30, 255]));
+        }
+    }
+    img.put_pixel(1, 1, Rgba([0, 0, 0, 255]));
+    img.put_pixel(39, 29, Rgba([0, 0, 0, 255]));
+    // Within tolerance of the background, so not content at all
+    img.put_pixel(5, 25, Rgba([250, 252, 255, 255]));
+
+    let mut p = processor(img.clone());
+    p.crop_to_content(white, 8).unwrap();
+    assert_eq!(p.dimensions(), (18, 12));
+    assert_eq!(p.get_image().to_rgba8(), image::imageops::crop_imm(&img, 12, 10, 18, 12).to_image());
+
+    let blank = RgbaImage::from_pixel(4, 4, white);
+    assert_eq!(operation_error(processor(blank).crop_to_content(white, 0)).0, "crop_to_content");
+}
+
+#[cfg(feature = "webp-animation")]
+#[test]
+fn animated_webp_decodes_to_the_same_frames_and_durations() {
+    let frames: Vec<DynamicImage> = (0..3u8)
+        .map(|i| DynamicImage::ImageRgba8(RgbaImage::from_fn(12, 8, |x, y| Rgba([i * 80, x as u8 * 20, y as u8 * 30, 255 - i * 40]))))
+        .collect();
+    let path = temp_path("animated.webp");
+    ImageProcessor::save_animated_webp(&frames, &path, 120, 2).unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let decoder = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(bytes)).unwrap();
+    let decoded = decoder.into_frames().collect_frames().unwrap();
+    assert_eq!(decoded.len(), 3);
+    for (frame, original) in decoded.iter().zip(&frames) {
+        assert_eq!(frame.delay().numer_denom_ms(), (120, 1));
+        assert_eq!(frame.buffer(), &original.to_rgba8());
+    }
+
+    let mismatched = [frames[0].clone(), DynamicImage::new_rgba8(3, 3)];
+    assert_eq!(operation_error(ImageProcessor::save_animated_webp(&mismatched, &path, 100, 0)).0, "save_animated_webp");
+    assert_eq!(operation_error(ImageProcessor::save_animated_webp(&[], &path, 100, 0)).0, "save_animated_webp");
+}
+
+#[test]
+fn convert_to_luma_and_back_expands_gray() {
+    let mut p = processor(gradient(16, 8));
+    p.convert_to(ColorType::L8).unwrap();
+    assert_eq!(p.get_image().color(), ColorType::L8);
+    let luma = p.get_image().to_luma8();
+    assert_eq!(luma, DynamicImage::ImageRgba8(gradient(16, 8)).to_luma8());
+
+    p.convert_to(ColorType::Rgba8).unwrap();
+    assert_eq!(p.get_image().color(), ColorType::Rgba8);
+    for (pixel, gray) in p.get_image().to_rgba8().pixels().zip(luma.pixels()) {
+        assert_eq!(*pixel, Rgba([gray[0], gray[0], gray[0], 255]));
+    }
+
+    p.convert_to(ColorType::Rgb16).unwrap();
+    assert_eq!(p.get_image().color(), ColorType::Rgb16);
+    assert!(processor(gradient(2, 2)).convert_to(ColorType::Rgba32F).is_ok());
+}
+
+#[test]
+fn thumbnail_cache_computes_each_recipe_once() {
+    let mut blurred = processor(gradient(8, 8));
+    blurred.blur(1.0).unwrap();
+    let mut grayed = processor(gradient(8, 8));
+    grayed.grayscale().unwrap();
+
+    let mut cache = ThumbnailCache::new(2);
+    let runs = Cell::new(0);
+    let request = |cache: &mut ThumbnailCache, source: u64, recipe: &OperationLog| {
+        cache.get_or_compute(source, recipe, || {
+            runs.set(runs.get() + 1);
+            DynamicImage::new_rgba8(1, 1)
+        });
+    };
+    request(&mut cache, 1, blurred.operation_log());
+    request(&mut cache, 1, blurred.operation_log());
+    request(&mut cache, 1, grayed.operation_log());
+    request(&mut cache, 2, grayed.operation_log());
+    // Capacity 2: the blur entry was least recently used and has been evicted
+    request(&mut cache, 1, grayed.operation_log());
+    request(&mut cache, 1, blurred.operation_log());
+    assert_eq!(runs.get(), 4);
+    assert_eq!(cache.len(), 2);
+
+    // A log that can't be written as a recipe is never cached
+    let mut overlaid = processor(gradient(8, 8));
+    overlaid.overlay_image(&DynamicImage::new_rgba8(1, 1), 0, 0).unwrap();
+    request(&mut cache, 1, overlaid.operation_log());
+    request(&mut cache, 1, overlaid.operation_log());
+    assert_eq!(runs.get(), 6);
+    assert_eq!(cache.len(), 2);
+}
+
+/// Runs of consecutive rows containing at least one pixel matching `is_ink`, as
+/// (first row, last row) pairs
+fn ink_bands(img: &RgbaImage, is_ink: impl Fn(&Rgba<u8>) -> bool) -> Vec<(u32, u32)> {
+    let mut bands: Vec<(u32, u32)> = Vec::new();
+    for y in 0..img.height() {
+        if (0..img.width()).any(|x| is_ink(img.get_pixel(x, y))) {
+            match bands.last_mut() {
+                Some((_, last)) if *last + 1 == y => *last = y,
+                _ => bands.push((y, y)),
+            }
+        }
+    }
+    bands
+}
+
+#[test]
+fn draw_caption_wraps_and_stays_inside_its_box() {
+    let font = test_font();
+    let is_ink = |pixel: &Rgba<u8>| pixel[0] > 128 && pixel[2] < 100;
+    let text = "the quick brown fox jumps over the lazy dog again and again";
+    let mut p = processor(RgbaImage::from_pixel(200, 200, Rgba([255, 255, 255, 255])));
+    p.draw_caption(text, (20, 10, 90, 150), &font, 14.0, Rgba([255, 200, 0, 255]), Rgba([0, 0, 80, 255]))
+        .unwrap();
+    let out = p.get_image().to_rgba8();
+    let lines = ink_bands(&out, is_ink);
+    assert!(lines.len() >= 4, "expected several wrapped lines, got {:?}", lines);
+    for (x, y, pixel) in out.enumerate_pixels() {
+        let inside = (20..110).contains(&x) && (10..160).contains(&y);
+        assert_eq!(*pixel != Rgba([255, 255, 255, 255]), inside, "({}, {})", x, y);
+    }
+
+    // A box two lines tall keeps two lines and truncates the rest
+    let mut short = processor(RgbaImage::from_pixel(200, 60, Rgba([255, 255, 255, 255])));
+    short
+        .draw_caption(text, (0, 0, 90, 45), &font, 14.0, Rgba([255, 200, 0, 255]), Rgba([0, 0, 80, 255]))
+        .unwrap();
+    assert_eq!(ink_bands(&short.get_image().to_rgba8(), is_ink).len(), 2);
+
+    let (op, _) = operation_error(p.draw_caption(text, (u32::MAX, 0, 2, 2), &font, 14.0, Rgba([0; 4]), Rgba([0; 4])));
+    assert_eq!(op, "draw_caption");
+}
+
+#[test]
+fn tilt_shift_keeps_the_band_sharp_and_blurs_far_rows() {
+    let img = RgbaImage::from_fn(32, 100, |x, y| if (x + y) % 4 < 2 { Rgba([240, 240, 240, 255]) } else { Rgba([20, 20, 20, 255]) });
+    let mut shifted = processor(img.clone());
+    shifted.tilt_shift(50, 20, 2.0).unwrap();
+    let mut blurred = processor(img.clone());
+    blurred.blur(2.0).unwrap();
+    let (shifted, blurred) = (shifted.get_image().to_rgba8(), blurred.get_image().to_rgba8());
+
+    for x in 0..32 {
+        for y in 40..=60 {
+            assert_eq!(shifted.get_pixel(x, y), img.get_pixel(x, y), "({}, {}) in the focus band", x, y);
+        }
+        // The fade spans a fifth of the height (20 rows) beyond each edge of the band
+        for y in (0..20).chain(81..100) {
+            assert_eq!(shifted.get_pixel(x, y), blurred.get_pixel(x, y), "({}, {}) far from the band", x, y);
+        }
+    }
+    assert_eq!(operation_error(processor(gradient(4, 4)).tilt_shift(2, 2, 0.0)).0, "tilt_shift");
+}
+
+#[test]
+fn gradient_map_sends_midtones_to_the_middle_stop() {
+    let stops = [(0.0, Rgba([0, 0, 0, 255])), (0.5, Rgba([255, 0, 0, 255])), (1.0, Rgba([255, 255, 255, 255]))];
+    let grays = [0u8, 64, 128, 255];
+    let mut p = processor(RgbaImage::from_fn(4, 1, |x, _| {
+        let v = grays[x as usize];
+        Rgba([v, v, v, 200])
+    }));
+    p.gradient_map(&stops).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(0, 0), Rgba([0, 0, 0, 200]));
+    assert_eq!(*out.get_pixel(1, 0), Rgba([128, 0, 0, 200]));
+    let mid = out.get_pixel(2, 0);
+    assert!(mid[0] == 255 && mid[1] <= 2 && mid[2] <= 2, "{:?}", mid);
+    assert_eq!(*out.get_pixel(3, 0), Rgba([255, 255, 255, 200]));
+
+    let unsorted = [stops[0], stops[2], stops[1]];
+    assert_eq!(operation_error(processor(gradient(2, 2)).gradient_map(&unsorted)).0, "gradient_map");
+    assert_eq!(operation_error(processor(gradient(2, 2)).gradient_map(&stops[..2])).0, "gradient_map");
+}
+
+#[test]
+fn ssim_is_one_for_identical_images_and_drops_after_blurring() {
+    let texture = RgbaImage::from_fn(48, 48, |x, y| {
+        let v = ((x * 7 + y * 13) % 5 * 50 + (x / 4 + y / 4) % 2 * 40) as u8;
+        Rgba([v, v, v, 255])
+    });
+    let original = DynamicImage::ImageRgba8(texture.clone());
+    let p = processor(texture.clone());
+    assert!((p.ssim(&original).unwrap() - 1.0).abs() < 1e-9);
+
+    let mut blurred = processor(texture);
+    blurred.blur(4.0).unwrap();
+    let score = p.ssim(blurred.get_image()).unwrap();
+    assert!(score < 0.5, "heavily blurred texture scored {}", score);
+
+    let smaller = DynamicImage::ImageRgba8(gradient(47, 48));
+    assert_eq!(operation_error(p.ssim(&smaller)).0, "ssim");
+}
+
+#[test]
+fn timing_records_each_mutating_operation_by_name() {
+    assert!(processor(gradient(4, 4)).timings().is_empty());
+
+    let mut p = ImageProcessor::from_dynamic_image(DynamicImage::ImageRgba8(gradient(16, 16))).with_timing();
+    p.blur(1.0).unwrap().grayscale().unwrap();
+    let names: Vec<&str> = p.timings().iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["blur", "grayscale"]);
+}
+
+#[test]
+fn split_tiles_covers_the_image_with_smaller_edge_tiles() {
+    let img = gradient(10, 10);
+    let tiles = processor(img.clone()).split_tiles(4, 4);
+    let origins: Vec<(u32, u32)> = tiles.iter().map(|(x, y, _)| (*x, *y)).collect();
+    assert_eq!(origins, [(0, 0), (4, 0), (8, 0), (0, 4), (4, 4), (8, 4), (0, 8), (4, 8), (8, 8)]);
+    let sizes: Vec<(u32, u32)> = tiles.iter().map(|(_, _, tile)| tile.dimensions()).collect();
+    assert_eq!(sizes[0], (4, 4));
+    assert_eq!(sizes[2], (2, 4));
+    assert_eq!(sizes[6], (4, 2));
+    assert_eq!(sizes[8], (2, 2));
+
+    let mut canvas = DynamicImage::new_rgba8(10, 10);
+    for (x, y, tile) in &tiles {
+        image::imageops::replace(&mut canvas, tile, *x as i64, *y as i64);
+    }
+    assert_eq!(canvas.to_rgba8(), img);
+    assert!(processor(img).split_tiles(0, 4).is_empty());
+}
+
+#[test]
+fn from_tiles_rebuilds_split_tiles_exactly() {
+    let img = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(gradient(13, 7)).to_rgb8());
+    let tiles = ImageProcessor::from_dynamic_image(img.clone()).split_tiles(5, 3);
+    let rebuilt = ImageProcessor::from_tiles(&tiles, 13, 7).unwrap();
+    assert_eq!(rebuilt.get_image().color(), ColorType::Rgb8);
+    assert_eq!(rebuilt.get_image().as_bytes(), img.as_bytes());
+
+    assert_eq!(operation_error(ImageProcessor::from_tiles(&tiles, 12, 7)).0, "from_tiles");
+    let far = [(u32::MAX, 0, DynamicImage::new_rgba8(2, 2))];
+    assert_eq!(operation_error(ImageProcessor::from_tiles(&far, 13, 7)).0, "from_tiles");
+}
+
+#[test]
+fn open_region_matches_full_load_then_crop() {
+    let img = RgbaImage::from_fn(640, 480, |x, y| Rgba([(x % 251) as u8, (y % 241) as u8, ((x ^ y) % 256) as u8, 255]));
+    let rgb = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img.clone()).to_rgb8());
+    let sources = [
+        ("region.bmp", rgb.clone()),
+        ("region.ff", DynamicImage::ImageRgba16(DynamicImage::ImageRgba8(img).to_rgba16())),
+        ("region.png", rgb),
+    ];
+    for (name, source) in sources {
+        let path = temp_path(name);
+        source.save(&path).unwrap();
+        let region = ImageProcessor::open_region(&path, 123, 301, 200, 150).unwrap();
+        let expected = image::open(&path).unwrap().crop_imm(123, 301, 200, 150);
+        assert_eq!(region.get_image().color(), expected.color(), "{}", name);
+        assert_eq!(region.get_image().as_bytes(), expected.as_bytes(), "{}", name);
+
+        let outside = ImageProcessor::open_region(&path, 600, 0, 41, 10);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(operation_error(outside).0, "open_region", "{}", name);
+    }
+}
+
+#[test]
+fn linear_overlay_gives_brighter_half_transparent_edges() {
+    let black = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+    let edge = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 128])));
+
+    let mut srgb = processor(black.clone());
+    srgb.overlay_image(&edge, 1, 1).unwrap();
+    let mut linear = processor(black.clone());
+    linear.overlay_image_linear(&edge, 1, 1).unwrap();
+
+    let srgb_value = srgb.get_image().to_rgba8().get_pixel(1, 1)[0];
+    let linear_value = linear.get_image().to_rgba8().get_pixel(1, 1)[0];
+    assert_eq!(srgb_value, 128);
+    // 50% of white in linear light is about 0.5, which encodes to sRGB 188
+    assert!(linear_value.abs_diff(188) <= 1, "linear blend gave {}", linear_value);
+    assert_eq!(*linear.get_image().to_rgba8().get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+
+    let mut p = processor(black);
+    assert_eq!(operation_error(p.overlay_image_linear(&edge, u32::MAX - 1, 0)).0, "overlay_image_linear");
+}
+
+#[test]
+fn has_transparency_only_for_pixels_below_full_alpha() {
+    assert!(!processor(gradient(6, 6)).has_transparency());
+    let rgb = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(gradient(6, 6)).to_rgb8());
+    assert!(!ImageProcessor::from_dynamic_image(rgb).has_transparency());
+
+    let mut img = gradient(6, 6);
+    img.put_pixel(5, 5, Rgba([10, 20, 30, 254]));
+    assert!(processor(img).has_transparency());
+}
+
+#[test]
+fn rows_reassemble_into_the_rgba8_buffer() {
+    let img = gradient(7, 5);
+    let p = processor(img.clone());
+    assert!(p.rows().all(|row| matches!(row, std::borrow::Cow::Borrowed(_))));
+    assert_eq!(p.rows().count(), 5);
+    assert_eq!(p.rows().flat_map(|row| row.into_owned()).collect::<Vec<u8>>(), img.as_raw().as_slice());
+
+    let rgb = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img.clone()).to_rgb8());
+    let converted = ImageProcessor::from_dynamic_image(rgb);
+    assert_eq!(converted.rows().flat_map(|row| row.into_owned()).collect::<Vec<u8>>(), img.into_raw());
+}
+
+#[test]
+fn cropped_leaves_the_original_untouched() {
+    let mut p = processor(gradient(12, 9));
+    p.grayscale().unwrap();
+    let copy = p.cropped(2, 3, 5, 4).unwrap();
+    assert_eq!(p.dimensions(), (12, 9));
+    assert_eq!(copy.dimensions(), (5, 4));
+    assert_eq!(copy.get_image().to_rgba8(), p.get_image().crop_imm(2, 3, 5, 4).to_rgba8());
+    assert_eq!(p.recipe_json().unwrap().matches("\"op\"").count(), 1);
+    assert_eq!(copy.recipe_json().unwrap().matches("\"op\"").count(), 2);
+
+    assert_eq!(operation_error(p.cropped(u32::MAX, 0, 2, 2)).0, "cropped");
+    assert_eq!(operation_error(p.crop(0, u32::MAX - 1, 2, 2)).0, "crop");
+    assert_eq!(p.dimensions(), (12, 9));
+}
+
+#[test]
+fn remove_red_eye_desaturates_red_pupils_but_not_skin() {
+    let skin = Rgba([224, 172, 140, 255]);
+    let red_eye = Rgba([200, 30, 35, 255]);
+    let mut img = RgbaImage::from_pixel(10, 10, skin);
+    for (x, y) in [(4, 4), (5, 4), (4, 5), (5, 5), (8, 8)] {
+        img.put_pixel(x, y, red_eye);
+    }
+    let mut p = processor(img);
+    p.remove_red_eye(2, 2, 6, 6).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    let redness = |pixel: &Rgba<u8>| pixel[0] as i32 - (pixel[1] as i32 + pixel[2] as i32) / 2;
+    for (x, y) in [(4, 4), (5, 4), (4, 5), (5, 5)] {
+        assert!(redness(out.get_pixel(x, y)) <= 1, "pupil at ({}, {}) is still {:?}", x, y, out.get_pixel(x, y));
+    }
+    // Outside the region the red pixel is left alone, and skin inside it is unchanged
+    assert_eq!(*out.get_pixel(8, 8), red_eye);
+    assert_eq!(*out.get_pixel(3, 3), skin);
+    assert_eq!(*out.get_pixel(0, 0), skin);
+
+    assert_eq!(operation_error(p.remove_red_eye(u32::MAX, 0, 2, 2)).0, "remove_red_eye");
+}
+
+#[test]
+fn sharpness_drops_after_blurring() {
+    let sharp = processor(checkerboard(32, 32, 2));
+    let mut blurred = processor(checkerboard(32, 32, 2));
+    blurred.blur(2.0).unwrap();
+    assert!(sharp.sharpness() > 10.0 * blurred.sharpness(), "{} vs {}", sharp.sharpness(), blurred.sharpness());
+    assert_eq!(processor(RgbaImage::from_pixel(8, 8, Rgba([90, 90, 90, 255]))).sharpness(), 0.0);
+    assert_eq!(processor(gradient(2, 8)).sharpness(), 0.0);
+}
+
+#[test]
+fn apply_masked_only_affects_the_white_half() {
+    let original = checkerboard(16, 8, 1);
+    let mask = DynamicImage::ImageLuma8(image::GrayImage::from_fn(16, 8, |x, _| image::Luma([if x < 8 { 255 } else { 0 }])));
+    let mut p = processor(original.clone());
+    p.apply_masked(&mask, |copy| copy.blur(1.5).map(|_| ())).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    for (x, y, pixel) in out.enumerate_pixels() {
+        if x >= 8 {
+            assert_eq!(pixel, original.get_pixel(x, y), "masked-out pixel ({}, {}) changed", x, y);
+        } else if x < 6 {
+            assert!(pixel[0].abs_diff(128) < 40, "pixel ({}, {}) wasn't blurred: {:?}", x, y, pixel);
+        }
+    }
+
+    let wrong_size = DynamicImage::new_luma8(15, 8);
+    assert_eq!(operation_error(p.apply_masked(&wrong_size, |_| Ok(()))).0, "apply_masked");
+    let shrink = |copy: &mut ImageProcessor| copy.crop(0, 0, 4, 4).map(|_| ());
+    assert_eq!(operation_error(p.apply_masked(&mask, shrink)).0, "apply_masked");
+}
+
+#[test]
+fn normalize_stretches_each_channel_separately() {
+    let img = RgbaImage::from_fn(51, 1, |x, _| Rgba([(x * 5) as u8, 100 + x as u8, 77, 255]));
+    let mut p = processor(img);
+    p.normalize().unwrap();
+    let out = p.get_image().to_rgba8();
+    let greens: Vec<u8> = out.pixels().map(|pixel| pixel[1]).collect();
+    assert_eq!((greens[0], greens[50]), (0, 255));
+    assert!(greens.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(out.get_pixel(25, 0)[1], 128);
+    // Red already spans 0..=250 so it is stretched only slightly; flat blue is untouched
+    assert_eq!((out.get_pixel(0, 0)[0], out.get_pixel(50, 0)[0]), (0, 255));
+    assert!(out.pixels().all(|pixel| pixel[2] == 77 && pixel[3] == 255));
+}
+
+#[test]
+fn best_png_compression_is_smaller_and_lossless() {
+    let img = gradient(128, 96);
+    let p = processor(img.clone());
+    let fast = temp_path("png_fast.png");
+    let best = temp_path("png_best.png");
+    p.save_png_optimized(&fast, PngCompression::Fast).unwrap();
+    p.save_png_optimized(&best, PngCompression::Best).unwrap();
+
+    let (fast_size, best_size) = (fs::metadata(&fast).unwrap().len(), fs::metadata(&best).unwrap().len());
+    let (fast_pixels, best_pixels) = (image::open(&fast).unwrap(), image::open(&best).unwrap());
+    fs::remove_file(&fast).unwrap();
+    fs::remove_file(&best).unwrap();
+    assert!(best_size < fast_size, "best {} bytes vs fast {} bytes", best_size, fast_size);
+    assert_eq!(fast_pixels.to_rgba8(), img);
+    assert_eq!(best_pixels.to_rgba8(), img);
+}
+
+#[test]
+fn rotate_about_a_pivot_lands_pixels_at_the_turned_position() {
+    let img = gradient(8, 8);
+    let fill = Rgba([0, 0, 255, 255]);
+    let mut p = processor(img.clone());
+    p.rotate_about(90.0, (3.0, 3.0), fill).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(out.dimensions(), (8, 8));
+
+    // A clockwise quarter turn about (3, 3) sends (x, y) to (6 - y, x)
+    for (x, y) in [(3, 3), (5, 1), (6, 3), (2, 6), (4, 2)] {
+        let (moved, source) = (out.get_pixel(6 - y, x), img.get_pixel(x, y));
+        assert!((0..4).all(|c| moved[c].abs_diff(source[c]) <= 1), "({}, {}): {:?} vs {:?}", x, y, moved, source);
+    }
+    // The right column came from the row above the image, so it is filled
+    assert!((0..8).all(|y| *out.get_pixel(7, y) == fill));
+}
+
+#[test]
+fn despeckle_removes_specks_but_keeps_the_main_shape() {
+    let white = Rgba([255, 255, 255, 255]);
+    let mut img = RgbaImage::from_pixel(20, 20, Rgba([0, 0, 0, 255]));
+    for y in 4..12 {
+        for x in 4..12 {
+            img.put_pixel(x, y, white);
+        }
+    }
+    let specks = [(16, 2), (2, 17), (3, 17), (17, 16), (18, 17), (17, 17)];
+    for (x, y) in specks {
+        img.put_pixel(x, y, white);
+    }
+    let mut p = processor(img.clone());
+    p.despeckle(4).unwrap();
+    let out = p.get_image().to_rgba8();
+    for (x, y) in specks {
+        assert_eq!(*out.get_pixel(x, y), Rgba([0, 0, 0, 255]), "speck at ({}, {}) survived", x, y);
+    }
+    let specks_cleared = |x: u32, y: u32| specks.contains(&(x, y));
+    assert!(out.enumerate_pixels().all(|(x, y, pixel)| specks_cleared(x, y) || pixel == img.get_pixel(x, y)));
+}
+
+#[test]
+fn presets_shift_color_temperature_deterministically() {
+    let gray = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+    let warmth = |preset: Preset| {
+        let mut p = processor(gray.clone());
+        p.apply_preset(preset).unwrap();
+        let means = channel_means(&p.get_image().to_rgba8());
+        (means[0] - means[2], p.get_image().to_rgba8())
+    };
+    let (warm, warm_image) = warmth(Preset::WarmFilm);
+    let (cool, _) = warmth(Preset::CoolCinematic);
+    assert!(warm > 5.0, "warm film shifted red-blue by {}", warm);
+    assert!(cool < -5.0, "cool cinematic shifted red-blue by {}", cool);
+    assert_eq!(warmth(Preset::WarmFilm).1, warm_image);
+
+    // Vignetting darkens the corners relative to the center
+    let mut vintage = processor(gray.clone());
+    vintage.apply_preset(Preset::Vintage).unwrap();
+    let out = vintage.get_image().to_rgba8();
+    assert!(luminance(out.get_pixel(0, 0)) < luminance(out.get_pixel(8, 8)));
+}
+
+#[test]
+fn image_dimensions_reads_only_the_header() {
+    let path = temp_path("dimensions.png");
+    processor(gradient(37, 21)).save(&path).unwrap();
+    assert_eq!(image_dimensions(&path).unwrap(), ImageProcessor::new(&path).unwrap().dimensions());
+
+    // With the pixel data cut off a full decode fails, but the header is still readable
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..64]).unwrap();
+    assert!(ImageProcessor::new(&path).is_err());
+    assert_eq!(image_dimensions(&path).unwrap(), (37, 21));
+    fs::remove_file(&path).unwrap();
+
+    assert!(matches!(image_dimensions(&temp_path("missing.png")), Err(ImageError::LoadError(_))));
+}
+
+#[test]
+fn adjust_channels_scales_only_the_chosen_channel() {
+    let img = gradient(9, 9);
+    let mut unchanged = processor(img.clone());
+    unchanged.adjust_channels(1.0, 1.0, 1.0).unwrap();
+    assert_eq!(unchanged.get_image().to_rgba8(), img);
+
+    let mut p = processor(img.clone());
+    p.adjust_channels(1.5, 1.0, 1.0).unwrap();
+    for (before, after) in img.pixels().zip(p.get_image().to_rgba8().pixels()) {
+        assert_eq!(after[0], (before[0] as f32 * 1.5).round().min(255.0) as u8);
+        assert_eq!((after[1], after[2], after[3]), (before[1], before[2], before[3]));
+    }
+}
+
+#[test]
+fn cartoonize_gives_flat_regions_with_dark_outlines() {
+    // Two softly shaded halves meeting at a hard edge between x = 11 and x = 12
+    let img = RgbaImage::from_fn(24, 12, |x, y| {
+        if x < 12 {
+            Rgba([240 - y as u8, 200, 60, 255])
+        } else {
+            Rgba([30, 60 + y as u8, 120, 255])
+        }
+    });
+    let mut p = processor(img);
+    p.cartoonize(4, 40).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    let levels = [0, 85, 170, 255];
+    assert!(out.pixels().all(|pixel| (0..3).all(|c| levels.contains(&pixel[c]))));
+    for y in 0..12 {
+        assert_eq!(*out.get_pixel(11, y), Rgba([0, 0, 0, 255]), "no outline at (11, {})", y);
+        assert_eq!(*out.get_pixel(12, y), Rgba([0, 0, 0, 255]), "no outline at (12, {})", y);
+        assert_eq!(*out.get_pixel(4, y), Rgba([255, 170, 85, 255]));
+        assert_eq!(*out.get_pixel(19, y), Rgba([0, 85, 85, 255]));
+    }
+    assert_eq!(operation_error(p.cartoonize(1, 40)).0, "cartoonize");
+}
+
+#[test]
+fn apply_in_polygon_grays_only_inside_the_triangle() {
+    let img = gradient(20, 20);
+    let mut p = processor(img.clone());
+    p.apply_in_polygon(&[(2, 2), (17, 2), (2, 17)], |copy| copy.grayscale().map(|_| ())).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    for (x, y) in [(4, 4), (8, 5), (5, 9)] {
+        let pixel = out.get_pixel(x, y);
+        assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2], "({}, {}) kept color {:?}", x, y, pixel);
+    }
+    // Pixels clear of the triangle and its anti-aliased edge keep their color
+    for (x, y, pixel) in out.enumerate_pixels() {
+        if x + y > 21 || x == 0 || y == 0 {
+            assert_eq!(pixel, img.get_pixel(x, y), "({}, {}) changed", x, y);
+        }
+    }
+    assert_eq!(operation_error(p.apply_in_polygon(&[(0, 0), (5, 5), (0, 0)], |_| Ok(()))).0, "apply_in_polygon");
+}
+
+#[test]
+fn adjust_value_keeps_hue_where_brightness_clips() {
+    let orange = RgbaImage::from_pixel(2, 2, Rgba([200, 100, 50, 255]));
+    let mut brightness = processor(orange.clone());
+    brightness.adjust_brightness(1.5).unwrap();
+    let mut value = processor(orange.clone());
+    value.adjust_value(1.5).unwrap();
+
+    assert_eq!(*brightness.get_image().to_rgba8().get_pixel(0, 0), Rgba([255, 150, 75, 255]));
+    // Value stops at full, keeping the 4:2:1 channel ratios of the original hue
+    let pixel = *value.get_image().to_rgba8().get_pixel(0, 0);
+    assert_eq!(pixel[0], 255);
+    assert!(pixel[1].abs_diff(128) <= 1 && pixel[2].abs_diff(64) <= 1, "{:?}", pixel);
+
+    let desaturated = |model: ColorModel| {
+        let mut p = processor(orange.clone());
+        p.adjust_saturation(0.5, model).unwrap();
+        *p.get_image().to_rgba8().get_pixel(0, 0)
+    };
+    let (hsl, hsv) = (desaturated(ColorModel::Hsl), desaturated(ColorModel::Hsv));
+    assert_ne!(hsl, hsv);
+    // HSV keeps the brightest channel; HSL keeps the midpoint of brightest and darkest
+    assert_eq!(hsv[0], 200);
+    assert!((hsl[0] as i32 + hsl[2] as i32 - 250).abs() <= 1, "{:?}", hsl);
+}
+
+#[test]
+fn cmyk_jpegs_load_with_correct_colors() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let reference = image::open(fixtures.join("cmyk_reference.png")).unwrap();
+    let cases = [
+        ("cmyk_adobe.jpg", jpeg::CmykEncoding::AdobeInverted),
+        ("cmyk_ycck.jpg", jpeg::CmykEncoding::Ycck),
+        ("cmyk_plain.jpg", jpeg::CmykEncoding::Plain),
+    ];
+    for (name, encoding) in cases {
+        let path = fixtures.join(name);
+        let file = File::open(&path).unwrap();
+        assert_eq!(jpeg::cmyk_encoding(BufReader::new(file)).unwrap(), Some(encoding), "{}", name);
+
+        let p = ImageProcessor::new(path.to_str().unwrap()).unwrap();
+        assert!(p.is_cmyk_source(), "{}", name);
+        crate::testing::assert_images_eq(p.get_image(), &reference, 6);
+    }
+
+    // Without an Adobe marker the inks aren't inverted, which a plain decode gets backwards
+    let naive = image::open(fixtures.join("cmyk_plain.jpg")).unwrap().to_rgb8();
+    assert!(naive.get_pixel(0, 0)[0] < 16, "white paper decoded as {:?}", naive.get_pixel(0, 0));
+
+    let rgb_path = temp_path("not_cmyk.jpg");
+    DynamicImage::ImageRgba8(gradient(16, 16)).to_rgb8().save(&rgb_path).unwrap();
+    let rgb = ImageProcessor::new(&rgb_path).unwrap();
+    fs::remove_file(&rgb_path).unwrap();
+    assert!(!rgb.is_cmyk_source());
+}
+
+#[test]
+fn adjust_hue_range_shifts_blues_and_leaves_reds() {
+    let colors = [Rgba([40, 80, 220, 255]), Rgba([220, 40, 40, 255]), Rgba([120, 120, 120, 255])];
+    let img = RgbaImage::from_fn(3, 1, |x, _| colors[x as usize]);
+    let mut p = processor(img);
+    p.adjust_hue_range(225.0, 60.0, -40.0, 1.0).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    assert_eq!(*out.get_pixel(1, 0), colors[1]);
+    assert_eq!(*out.get_pixel(2, 0), colors[2]);
+    // Blue moved toward teal: more green, no more red
+    let shifted = out.get_pixel(0, 0);
+    assert!(shifted[1] > 120 && shifted[0] <= 40, "{:?}", shifted);
+    assert_eq!(operation_error(p.adjust_hue_range(225.0, 0.0, 10.0, 1.0)).0, "adjust_hue_range");
+}
+
+#[test]
+fn failed_atomic_save_leaves_no_partial_file() {
+    // image's JPEG encoder rejects RGBA, so this encode fails
+    let p = processor(gradient(8, 8));
+    let path = temp_path("atomic.jpg");
+    assert_eq!(operation_error(p.save_atomic(&path)).0, "save_atomic");
+    assert!(!Path::new(&path).exists());
+
+    fs::write(&path, b"previous contents").unwrap();
+    assert!(p.save_atomic(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"previous contents");
+    fs::remove_file(&path).unwrap();
+
+    let file_name = Path::new(&path).file_name().unwrap().to_string_lossy().into_owned();
+    let leftovers = fs::read_dir(std::env::temp_dir())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().contains(&file_name))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    let png = temp_path("atomic.png");
+    p.save_atomic(&png).unwrap();
+    assert_eq!(image::open(&png).unwrap().to_rgba8(), gradient(8, 8));
+    fs::remove_file(&png).unwrap();
+}
+
+#[test]
+fn mipmaps_halve_down_to_the_average_color() {
+    let img = RgbaImage::from_fn(4, 4, |x, y| Rgba([(x * 60) as u8, (y * 40) as u8, 200, 255]));
+    let levels = processor(img.clone()).generate_mipmaps();
+    let sizes: Vec<(u32, u32)> = levels.iter().map(|level| level.dimensions()).collect();
+    assert_eq!(sizes, [(4, 4), (2, 2), (1, 1)]);
+    assert_eq!(levels[0].to_rgba8(), img);
+    // Red averages 90 and green 60 over the image
+    assert_eq!(*levels[2].to_rgba8().get_pixel(0, 0), Rgba([90, 60, 200, 255]));
+
+    let wide: Vec<(u32, u32)> = processor(gradient(5, 2)).generate_mipmaps().iter().map(|l| l.dimensions()).collect();
+    assert_eq!(wide, [(5, 2), (2, 1), (1, 1)]);
+}
+
+#[test]
+fn blur_fast_is_close_to_blur() {
+    let img = checkerboard(384, 384, 24);
+    let mut exact = processor(img.clone());
+    exact.blur(20.0).unwrap();
+    let mut fast = processor(img);
+    fast.blur_fast(20.0).unwrap();
+
+    let (exact, fast) = (exact.get_image().to_rgba8(), fast.get_image().to_rgba8());
+    assert_eq!(exact.dimensions(), fast.dimensions());
+    let diffs: Vec<u8> = exact.pixels().zip(fast.pixels()).flat_map(|(a, b)| (0..3).map(move |c| a[c].abs_diff(b[c]))).collect();
+    let mean = diffs.iter().map(|&d| d as f64).sum::<f64>() / diffs.len() as f64;
+    let max = *diffs.iter().max().unwrap();
+    assert!(mean < 1.0 && max <= 16, "mean difference {}, max {}", mean, max);
+
+    for sigma in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+        assert_eq!(operation_error(processor(gradient(4, 4)).blur_fast(sigma)).0, "blur_fast");
+    }
+}
+
+#[test]
+#[ignore = "timing benchmark, run with --ignored"]
+fn blur_fast_outpaces_blur() {
+    let img = checkerboard(384, 384, 24);
+    let exact_time = best_time(3, || {
+        processor(img.clone()).blur(20.0).unwrap();
+    });
+    let fast_time = best_time(3, || {
+        processor(img.clone()).blur_fast(20.0).unwrap();
+    });
+    assert!(fast_time * 2 < exact_time, "blur_fast took {:?} vs {:?}", fast_time, exact_time);
+}
+
+#[test]
+fn render_histogram_of_a_flat_color_has_one_spike_per_channel() {
+    let p = processor(RgbaImage::from_pixel(10, 10, Rgba([200, 50, 50, 255])));
+    let rendered = p.render_histogram(256, 64).to_rgba8();
+    assert_eq!(rendered.dimensions(), (256, 64));
+    for (x, y, pixel) in rendered.enumerate_pixels() {
+        let expected = match x {
+            200 => Rgba([255, 0, 0, 255]),
+            50 => Rgba([0, 255, 255, 255]),
+            _ => Rgba([0, 0, 0, 255]),
+        };
+        assert_eq!(*pixel, expected, "({}, {})", x, y);
+    }
+
+    // Narrower renders merge bins: 200 of 256 lands in column 100 of 128
+    let narrow = p.render_histogram(128, 16).to_rgba8();
+    assert_eq!(narrow.dimensions(), (128, 16));
+    assert_eq!(*narrow.get_pixel(100, 0), Rgba([255, 0, 0, 255]));
+    assert_eq!(*narrow.get_pixel(25, 0), Rgba([0, 255, 255, 255]));
+}
+
+#[test]
+fn straighten_levels_a_thirty_degree_line() {
+    let (sin, cos) = 30f32.to_radians().sin_cos();
+    let on_line = |x: u32, y: u32| {
+        let (dx, dy) = (x as f32 - 60.0, y as f32 - 60.0);
+        (dx * sin - dy * cos).abs() <= 1.5 && (dx * cos + dy * sin).abs() <= 45.0
+    };
+    let img = RgbaImage::from_fn(120, 120, |x, y| if on_line(x, y) { Rgba([255; 4]) } else { Rgba([0, 0, 0, 255]) });
+    let mut p = processor(img);
+    p.straighten((60.0 - 40.0 * cos, 60.0 - 40.0 * sin), (60.0 + 40.0 * cos, 60.0 + 40.0 * sin)).unwrap();
+
+    // Compare the centroids of the line's left and right halves
+    let out = p.get_image().to_rgba8();
+    let mut halves = [(0.0f32, 0.0f32, 0.0f32); 2];
+    for (x, y, pixel) in out.enumerate_pixels() {
+        if pixel[3] == 255 && luminance(pixel) > 128.0 {
+            let half = &mut halves[(x >= 60) as usize];
+            *half = (half.0 + x as f32, half.1 + y as f32, half.2 + 1.0);
+        }
+    }
+    let [(lx, ly, ln), (rx, ry, rn)] = halves;
+    let angle = (ry / rn - ly / ln).atan2(rx / rn - lx / ln).to_degrees();
+    assert!(angle.abs() < 1.0, "line is still {} degrees off level", angle);
+    assert_eq!(operation_error(p.straighten((3.0, 4.0), (3.0, 4.0))).0, "straighten");
+}
+
+#[test]
+fn flood_fill_stays_inside_the_enclosed_region() {
+    let (white, black, red, blue) =
+        (Rgba([255, 255, 255, 255]), Rgba([0, 0, 0, 255]), Rgba([200, 30, 30, 255]), Rgba([0, 0, 255, 255]));
+    // A black ring around a red square, with a second red square outside the ring
+    let img = RgbaImage::from_fn(20, 12, |x, y| match (x, y) {
+        (1..=8, 1..=10) if x == 1 || x == 8 || y == 1 || y == 10 => black,
+        (2..=7, 2..=9) => red,
+        (12..=17, 3..=8) => red,
+        _ => white,
+    });
+    let mut p = processor(img.clone());
+    p.flood_fill(4, 5, blue, 10).unwrap();
+    let out = p.get_image().to_rgba8();
+    for (x, y, pixel) in out.enumerate_pixels() {
+        let expected = if (2..=7).contains(&x) && (2..=9).contains(&y) { blue } else { *img.get_pixel(x, y) };
+        assert_eq!(*pixel, expected, "({}, {})", x, y);
+    }
+
+    // A large region doesn't overflow the stack, and the seed must be inside the image
+    let mut large = processor(RgbaImage::from_pixel(600, 600, white));
+    large.flood_fill(599, 0, red, 0).unwrap();
+    assert!(large.get_image().to_rgba8().pixels().all(|pixel| *pixel == red));
+    assert_eq!(operation_error(large.flood_fill(600, 0, red, 0)).0, "flood_fill");
+}
+
+#[test]
+fn despill_pulls_excess_green_toward_red_and_blue() {
+    let img = RgbaImage::from_fn(3, 1, |x, _| [Rgba([120, 200, 100, 255]), Rgba([180, 90, 60, 255]), Rgba([60, 80, 220, 255])][x as usize]);
+    let mut half = processor(img.clone());
+    half.despill(KeyColor::Green, 0.5).unwrap();
+    let out = half.get_image().to_rgba8();
+    // Green exceeded the larger of red and blue (120) by 80; half of that is removed
+    assert_eq!(*out.get_pixel(0, 0), Rgba([120, 160, 100, 255]));
+    assert_eq!(*out.get_pixel(1, 0), *img.get_pixel(1, 0));
+    assert_eq!(*out.get_pixel(2, 0), *img.get_pixel(2, 0));
+
+    let mut full = processor(img.clone());
+    full.despill(KeyColor::Green, 1.0).unwrap();
+    assert_eq!(*full.get_image().to_rgba8().get_pixel(0, 0), Rgba([120, 120, 100, 255]));
+
+    let mut blue = processor(img);
+    blue.despill(KeyColor::Blue, 1.0).unwrap();
+    let out = blue.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(0, 0), Rgba([120, 200, 100, 255]));
+    assert_eq!(*out.get_pixel(2, 0), Rgba([60, 80, 80, 255]));
+}
+
+#[test]
+fn open_frames_reads_every_gif_frame() {
+    let gif = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/animated.gif");
+    let gif = gif.to_str().unwrap();
+    let frames = ImageProcessor::open_frames(gif).unwrap();
+    assert_eq!(ImageProcessor::frame_count(gif).unwrap(), 3);
+    assert_eq!(frames.len(), 3);
+    // Frame i has a colored band at x = 4i..4i + 4 on white
+    let colors = [Rgba([255, 0, 0, 255]), Rgba([0, 255, 0, 255]), Rgba([0, 0, 255, 255])];
+    for (i, frame) in frames.iter().enumerate() {
+        let frame = frame.to_rgba8();
+        assert_eq!(frame.dimensions(), (12, 8));
+        assert_eq!(*frame.get_pixel(4 * i as u32 + 1, 3), colors[i]);
+        assert_eq!(*frame.get_pixel((4 * i as u32 + 6) % 12, 3), Rgba([255, 255, 255, 255]));
+    }
+
+    let still = temp_path("still.png");
+    processor(gradient(5, 4)).save(&still).unwrap();
+    let frames = ImageProcessor::open_frames(&still).unwrap();
+    fs::remove_file(&still).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].dimensions(), (5, 4));
+    assert!(matches!(ImageProcessor::frame_count(&temp_path("missing.gif")), Err(ImageError::LoadError(_))));
+}
+
+#[test]
+fn sample_average_matches_the_linear_gradient_mean() {
+    let img = RgbaImage::from_fn(20, 20, |x, y| Rgba([(x * 10) as u8, (y * 5) as u8, 70, 255]));
+    let p = processor(img);
+    // A square centred on a linear ramp averages to the centre value
+    assert_eq!(p.sample_average(10, 12, 2).unwrap(), Rgba([100, 60, 70, 255]));
+    assert_eq!(p.sample_average(10, 12, 0).unwrap(), Rgba([100, 60, 70, 255]));
+    // Clipped at the corner to x, y in 0..=2, whose means are 1
+    assert_eq!(p.sample_average(0, 0, 2).unwrap(), Rgba([10, 5, 70, 255]));
+    // Clipped at the far edge to 17..=19
+    assert_eq!(p.sample_average(19, 19, 2).unwrap(), Rgba([180, 90, 70, 255]));
+    assert_eq!(operation_error(p.sample_average(20, 0, 1)).0, "sample_average");
+}
+
+#[test]
+fn resize_streaming_matches_resize_area_with_bounded_memory() {
+    let source = RgbaImage::from_fn(1024, 1024, |x, y| Rgba([(x / 4) as u8, (y / 4) as u8, ((x + y) / 8) as u8, 255]));
+    let source_bytes = source.as_raw().len();
+    let mut streamed = processor(source.clone());
+    let mut regular = processor(source);
+    assert!(streamed.estimated_memory_bytes() >= source_bytes);
+
+    let (result, streaming_peak, _) = measure_allocations(|| streamed.resize_streaming(64, 48).map(|_| ()));
+    result.unwrap();
+    let (result, regular_peak, _) = measure_allocations(|| regular.resize_area(64, 48).map(|_| ()));
+    result.unwrap();
+    crate::testing::assert_images_eq(streamed.get_image(), regular.get_image(), 1);
+    // Only a strip of source rows is held at once, never a full-size intermediate
+    assert!(streaming_peak < source_bytes / 2, "streaming resize peaked at {} bytes", streaming_peak);
+    assert!(streaming_peak * 2 < regular_peak, "{} bytes streaming vs {} regular", streaming_peak, regular_peak);
+}
+
+#[test]
+fn color_matrix_identity_and_grayscale() {
+    let img = gradient(9, 7);
+    let mut identity = processor(img.clone());
+    identity.color_matrix([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]], [0.0; 3]).unwrap();
+    assert_eq!(identity.get_image().to_rgba8(), img);
+
+    let row = [0.2126, 0.7152, 0.0722];
+    let mut gray = processor(img.clone());
+    gray.color_matrix([row; 3], [0.0; 3]).unwrap();
+    for (pixel, before) in gray.get_image().to_rgba8().pixels().zip(img.pixels()) {
+        assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2], "{:?}", pixel);
+        assert_eq!(pixel[0], luminance(before).round() as u8);
+    }
+
+    // Offsets are added after the multiply and clamped
+    let mut lifted = processor(img.clone());
+    lifted.color_matrix([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]], [300.0, -300.0, 10.0]).unwrap();
+    assert!(lifted.get_image().to_rgba8().pixels().all(|p| p[0] == 255 && p[1] == 0 && p[2] == 106));
+
+    // sepia is the sepia matrix applied through the same path
+    let mut sepia = processor(img.clone());
+    sepia.sepia().unwrap();
+    let mut via_matrix = processor(img);
+    via_matrix.color_matrix(SEPIA_MATRIX, [0.0; 3]).unwrap();
+    assert_eq!(sepia.get_image().to_rgba8(), via_matrix.get_image().to_rgba8());
+}
+
+#[test]
+fn rgb_shift_moves_only_the_offset_channel() {
+    let img = gradient(10, 6);
+    let mut unchanged = processor(img.clone());
+    unchanged.rgb_shift((0, 0), (0, 0), (0, 0)).unwrap();
+    assert_eq!(unchanged.get_image().to_rgba8(), img);
+
+    let mut p = processor(img.clone());
+    p.rgb_shift((2, 0), (0, 0), (0, 0)).unwrap();
+    let out = p.get_image().to_rgba8();
+    for (x, y, pixel) in out.enumerate_pixels() {
+        // Red comes from two pixels to the left, clamped to the first column
+        assert_eq!(pixel[0], img.get_pixel(x.saturating_sub(2), y)[0], "({}, {})", x, y);
+        let before = img.get_pixel(x, y);
+        assert_eq!((pixel[1], pixel[2], pixel[3]), (before[1], before[2], before[3]));
+    }
+}
+
+#[test]
+fn split_tone_tints_shadows_and_highlights_separately() {
+    let grays = [40u8, 200, 230];
+    let img = RgbaImage::from_fn(3, 1, |x, _| {
+        let v = grays[x as usize];
+        Rgba([v, v, v, 255])
+    });
+    let (teal, orange) = (Rgba([0, 128, 128, 255]), Rgba([255, 160, 0, 255]));
+    let mut p = processor(img.clone());
+    p.split_tone(teal, orange, 0.0).unwrap();
+    let out = p.get_image().to_rgba8();
+    let dark = out.get_pixel(0, 0);
+    assert!(dark[2] > dark[0] + 20 && dark[1] > dark[0] + 20, "shadow {:?}", dark);
+    let bright = out.get_pixel(2, 0);
+    assert!(bright[0] > bright[2] + 20, "highlight {:?}", bright);
+
+    // Pushing the balance up moves the midpoint above 200, so that gray gets the shadow tint
+    let mut shifted = processor(img);
+    shifted.split_tone(teal, orange, 0.8).unwrap();
+    let light = *shifted.get_image().to_rgba8().get_pixel(1, 0);
+    assert!(light[2] > light[0], "{:?}", light);
+    assert!(out.get_pixel(1, 0)[0] > out.get_pixel(1, 0)[2]);
+    assert_eq!(operation_error(p.split_tone(teal, orange, 1.5)).0, "split_tone");
+}
+
+#[test]
+fn snapping_to_multiples_resizes_or_pads() {
+    let img = gradient(100, 100);
+    let mut resized = processor(img.clone());
+    resized.resize_to_multiple(32, ResizeFilter::Lanczos3).unwrap();
+    assert_eq!(resized.dimensions(), (96, 96));
+
+    let mut padded = processor(img.clone());
+    padded.pad_to_multiple(32).unwrap();
+    assert_eq!(padded.dimensions(), (128, 128));
+    let out = padded.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(99, 99), *img.get_pixel(99, 99));
+    assert_eq!(*out.get_pixel(100, 5), Rgba([0, 0, 0, 0]));
+    assert_eq!(*out.get_pixel(5, 127), Rgba([0, 0, 0, 0]));
+
+    // Already a multiple: padding changes nothing, and tiny images snap up to one multiple
+    let mut exact = processor(gradient(64, 32));
+    exact.pad_to_multiple(32).unwrap();
+    assert_eq!(exact.get_image().to_rgba8(), gradient(64, 32));
+    let mut tiny = processor(gradient(5, 5));
+    tiny.resize_to_multiple(32, ResizeFilter::Triangle).unwrap();
+    assert_eq!(tiny.dimensions(), (32, 32));
+    assert_eq!(operation_error(tiny.resize_to_multiple(0, ResizeFilter::Triangle)).0, "resize_to_multiple");
+    assert_eq!(operation_error(tiny.pad_to_multiple(0)).0, "pad_to_multiple");
+}
+
+#[test]
+fn normalized_tensor_is_channel_first() {
+    let img = RgbaImage::from_fn(2, 2, |x, y| Rgba([(x * 100 + y * 10) as u8, 51, 255, 7]));
+    let mean = [0.5, 0.1, 0.0];
+    let std = [0.5, 0.2, 2.0];
+    let tensor = processor(img).to_normalized_tensor(mean, std);
+    assert_eq!(tensor.len(), 3 * 4);
+
+    let expected_red = [0.0f32, 100.0, 10.0, 110.0].map(|v| (v / 255.0 - 0.5) / 0.5);
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+    // Red plane row by row, then green, then blue; alpha is dropped
+    assert!(tensor[..4].iter().zip(expected_red).all(|(&a, b)| close(a, b)), "{:?}", &tensor[..4]);
+    assert!(tensor[4..8].iter().all(|&v| close(v, (0.2 - 0.1) / 0.2)), "{:?}", &tensor[4..8]);
+    assert!(tensor[8..].iter().all(|&v| close(v, 0.5)), "{:?}", &tensor[8..]);
+}
+
+#[test]
+fn nine_patch_keeps_corners_and_stretches_edges() {
+    // 6x6 patch: 2-pixel red corners, green edges, blue center
+    let (red, green, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 255, 0, 255]), Rgba([0, 0, 255, 255]));
+    let region = |v: u32| if v < 2 { 0 } else if v < 4 { 1 } else { 2 };
+    let patch = RgbaImage::from_fn(6, 6, |x, y| match (region(x), region(y)) {
+        (1, 1) => blue,
+        (1, _) | (_, 1) => green,
+        _ => red,
+    });
+    let mut p = processor(RgbaImage::from_pixel(20, 16, Rgba([0, 0, 0, 255])));
+    p.nine_patch(&DynamicImage::ImageRgba8(patch), (2, 2, 2, 2), (20, 16)).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    for (x, y, pixel) in out.enumerate_pixels() {
+        let edge_x = !(2..18).contains(&x);
+        let edge_y = !(2..14).contains(&y);
+        let expected = match (edge_x, edge_y) {
+            (true, true) => red,
+            (false, false) => blue,
+            _ => green,
+        };
+        assert_eq!(*pixel, expected, "({}, {})", x, y);
+    }
+
+    let patch = DynamicImage::ImageRgba8(RgbaImage::new(6, 6));
+    assert_eq!(operation_error(p.nine_patch(&patch, (4, 0, 4, 0), (20, 16))).0, "nine_patch");
+    assert_eq!(operation_error(p.nine_patch(&patch, (u32::MAX, 0, 2, 0), (20, 16))).0, "nine_patch");
+    assert_eq!(operation_error(p.nine_patch(&patch, (2, 2, 2, 2), (21, 16))).0, "nine_patch");
+}
+
+#[test]
+fn side_by_side_lays_out_both_images_with_labels() {
+    let font = test_font();
+    let left = RgbaImage::from_pixel(60, 20, Rgba([255, 0, 0, 255]));
+    let right = RgbaImage::from_pixel(80, 30, Rgba([0, 0, 255, 255]));
+    let out = processor(left)
+        .side_by_side(&DynamicImage::ImageRgba8(right), ("before", "after"), &font)
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(out.width(), 60 + COMPARISON_DIVIDER_WIDTH + 80);
+    assert!(out.height() > 30 + 2 * CAPTION_PADDING);
+
+    // Top-aligned, with the shorter image padded white below
+    assert_eq!(*out.get_pixel(10, 0), Rgba([255, 0, 0, 255]));
+    assert_eq!(*out.get_pixel(10, 25), Rgba([255, 255, 255, 255]));
+    assert_eq!(*out.get_pixel(70, 29), Rgba([0, 0, 255, 255]));
+    assert_eq!(*out.get_pixel(61, out.height() - 1), Rgba([128, 128, 128, 255]));
+
+    // Dark label ink appears below the images, under each column
+    let is_ink = |p: &Rgba<u8>| luminance(p) < 100.0;
+    let inked = |range: std::ops::Range<u32>| range.into_iter().any(|x| (30..out.height()).any(|y| is_ink(out.get_pixel(x, y))));
+    assert!(inked(0..60));
+    assert!(inked(64..144));
+}
+
+#[test]
+fn antialiased_shapes_have_partial_coverage_edges() {
+    let black = RgbaImage::from_pixel(24, 24, Rgba([0, 0, 0, 255]));
+    let is_partial = |p: &Rgba<u8>| p[0] > 0 && p[0] < 255;
+
+    let mut hard = black.clone();
+    imageproc::drawing::draw_line_segment_mut(&mut hard, (1.0, 2.0), (22.0, 11.0), Rgba([255, 255, 255, 255]));
+    assert!(!hard.pixels().any(is_partial));
+
+    let mut line = processor(black.clone());
+    line.draw_line_aa((1, 2), (22, 11), Rgba([255, 255, 255, 255])).unwrap();
+    let out = line.get_image().to_rgba8();
+    assert!(out.pixels().filter(|p| is_partial(p)).count() >= 10);
+    assert_eq!(*out.get_pixel(1, 2), Rgba([255, 255, 255, 255]));
+    assert_eq!(*out.get_pixel(20, 2), Rgba([0, 0, 0, 255]));
+
+    let mut circle = processor(black);
+    circle.draw_circle_aa((12, 12), 8, Rgba([255, 255, 255, 255]), true).unwrap();
+    let out = circle.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(12, 12), Rgba([255, 255, 255, 255]));
+    assert_eq!(*out.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+    // On the diagonal the rim falls between pixel centres
+    assert!((0..24).any(|i| is_partial(out.get_pixel(i, i))));
+}
+
+#[test]
+fn draw_circle_filled_and_outline() {
+    let white = Rgba([255, 255, 255, 255]);
+    let black = RgbaImage::from_pixel(21, 21, Rgba([0, 0, 0, 255]));
+
+    let mut filled = processor(black.clone());
+    filled.draw_circle((10, 10), 6, white, true).unwrap();
+    let out = filled.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(10, 10), white);
+    assert_eq!(*out.get_pixel(16, 10), white);
+    assert_eq!(*out.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+
+    let mut outline = processor(black.clone());
+    outline.draw_circle((10, 10), 6, white, false).unwrap();
+    let out = outline.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(10, 10), Rgba([0, 0, 0, 255]));
+    assert_eq!(*out.get_pixel(16, 10), white);
+    assert_eq!(*out.get_pixel(10, 4), white);
+
+    // Half-transparent colors blend, and circles past the edge are clipped
+    let mut clipped = processor(black);
+    clipped.draw_circle((0, 0), 30, Rgba([255, 255, 255, 128]), true).unwrap();
+    assert_eq!(*clipped.get_image().to_rgba8().get_pixel(5, 5), Rgba([128, 128, 128, 255]));
+    assert_eq!(operation_error(clipped.draw_circle((0, 0), -1, white, true)).0, "draw_circle");
+}
+
+#[test]
+fn inpaint_fills_a_masked_square_from_its_surroundings() {
+    let img = gradient(40, 40);
+    let hole = |x: u32, y: u32| (15..25).contains(&x) && (15..25).contains(&y);
+    let mask = DynamicImage::ImageLuma8(image::GrayImage::from_fn(40, 40, |x, y| Luma([if hole(x, y) { 255 } else { 0 }])));
+    let mut damaged = img.clone();
+    for (x, y, pixel) in damaged.enumerate_pixels_mut() {
+        if hole(x, y) {
+            *pixel = Rgba([0, 0, 0, 0]);
+        }
+    }
+
+    let mut p = processor(damaged.clone());
+    p.inpaint(&mask, 3).unwrap();
+    let out = p.get_image().to_rgba8();
+    for (x, y, pixel) in out.enumerate_pixels() {
+        if hole(x, y) {
+            // The smooth ramp is continued into the hole rather than left blank
+            let original = img.get_pixel(x, y);
+            assert_eq!(pixel[3], 255, "({}, {}) left blank", x, y);
+            assert!((0..3).all(|c| pixel[c].abs_diff(original[c]) <= 20), "({}, {}): {:?} vs {:?}", x, y, pixel, original);
+        } else {
+            assert_eq!(pixel, damaged.get_pixel(x, y));
+        }
+    }
+
+    assert_eq!(operation_error(p.inpaint(&DynamicImage::new_luma8(39, 40), 3)).0, "inpaint");
+    assert_eq!(operation_error(p.inpaint(&mask, 0)).0, "inpaint");
+}
+
+#[test]
+fn inpaint_fills_a_large_hole_in_distance_order() {
+    // A hole much wider than the radius is still filled completely, outside in
+    let size = 160;
+    let hole = |x: u32, y: u32| (8..size - 8).contains(&x) && (8..size - 8).contains(&y);
+    let color = Rgba([90, 140, 200, 255]);
+    let img = RgbaImage::from_fn(size, size, |x, y| if hole(x, y) { Rgba([0, 0, 0, 0]) } else { color });
+    let mask = DynamicImage::ImageLuma8(image::GrayImage::from_fn(size, size, |x, y| {
+        Luma([if hole(x, y) { 255 } else { 0 }])
+    }));
+    let mut p = processor(img);
+    p.inpaint(&mask, 2).unwrap();
+    assert!(p.get_image().to_rgba8().pixels().all(|pixel| *pixel == color));
+}
+
+#[test]
+fn selective_desaturate_keeps_red_and_grays_the_blue_background() {
+    let (red, blue) = (Rgba([210, 30, 40, 255]), Rgba([40, 70, 200, 255]));
+    let img = RgbaImage::from_fn(12, 12, |x, y| if (4..8).contains(&x) && (4..8).contains(&y) { red } else { blue });
+    let mut p = processor(img);
+    p.selective_desaturate(0.0, 60.0).unwrap();
+    let out = p.get_image().to_rgba8();
+
+    assert_eq!(*out.get_pixel(5, 5), red);
+    let background = out.get_pixel(0, 0);
+    assert!(background[0] == background[1] && background[1] == background[2], "{:?}", background);
+    assert_eq!(background[0], luminance(&blue).round() as u8);
+
+    // Hues between the kept range and its edge keep part of their color
+    let amber = Rgba([220, 180, 40, 255]);
+    let mut edge = processor(RgbaImage::from_pixel(1, 1, amber));
+    edge.selective_desaturate(0.0, 60.0).unwrap();
+    let faded = *edge.get_image().to_rgba8().get_pixel(0, 0);
+    assert!(faded != amber && faded[0] > faded[2] + 20, "{:?}", faded);
+    assert_eq!(operation_error(p.selective_desaturate(0.0, 0.0)).0, "selective_desaturate");
+}
+
+#[test]
+fn porter_duff_src_in_and_xor_match_hand_computed_values() {
+    let clear = Rgba([0, 0, 0, 0]);
+    let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+
+    // SrcIn keeps the source color, limited to the destination's alpha
+    let dst = RgbaImage::from_fn(3, 1, |x, _| [Rgba([255, 0, 0, 128]), red, clear][x as usize]);
+    let mut p = processor(dst);
+    p.composite(&DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 1, blue)), 0, 0, PorterDuff::SrcIn).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(0, 0), Rgba([0, 0, 255, 128]));
+    assert_eq!(*out.get_pixel(1, 0), blue);
+    assert_eq!(*out.get_pixel(2, 0), clear);
+
+    // Xor keeps each image only where the other is absent: source in the left column,
+    // destination in the bottom row, nothing where both are opaque
+    let dst = RgbaImage::from_fn(3, 2, |_, y| if y == 1 { red } else { clear });
+    let src = RgbaImage::from_fn(3, 2, |x, _| match x {
+        0 => blue,
+        1 => clear,
+        _ => Rgba([0, 0, 255, 128]),
+    });
+    let mut p = processor(dst);
+    p.composite(&DynamicImage::ImageRgba8(src), 0, 0, PorterDuff::Xor).unwrap();
+    let out = p.get_image().to_rgba8();
+    assert_eq!(*out.get_pixel(0, 0), blue);
+    assert_eq!(*out.get_pixel(1, 0), clear);
+    assert_eq!(*out.get_pixel(0, 1), clear);
+    assert_eq!(*out.get_pixel(1, 1), red);
+    // Half-transparent source over opaque red: (1 - 128/255) of the red remains
+    assert_eq!(*out.get_pixel(2, 1), Rgba([255, 0, 0, 127]));
+    assert_eq!(*out.get_pixel(2, 0), Rgba([0, 0, 255, 128]));
+
+    let small = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+    assert_eq!(operation_error(p.composite(&small, u32::MAX, 0, PorterDuff::SrcOver)).0, "composite");
+    assert_eq!(operation_error(p.composite(&small, 2, 0, PorterDuff::SrcOver)).0, "composite");
+}
+
+#[test]
+fn frequency_separation_recombines_to_the_original() {
+    // A gradient with fine texture that stays within the detail layer's +/-127 range
+    let img = RgbaImage::from_fn(32, 24, |x, y| {
+        let base = gradient(32, 24).get_pixel(x, y).0;
+        let texture = ((x * 7 + y * 3) % 5) as u8 * 12;
+        Rgba([base[0] / 2 + texture, base[1] / 2 + 60, base[2] + texture, 255])
+    });
+    let (low, high) = processor(img.clone()).frequency_separation(3.0);
+    assert_ne!(low.to_rgba8(), img);
+    let high_rgba = high.to_rgba8();
+    let mean_detail = high_rgba.pixels().map(|p| p[1] as f64).sum::<f64>() / (32.0 * 24.0);
+    assert!((mean_detail - 128.0).abs() < 4.0, "detail layer centred on {}", mean_detail);
+
+    let merged = ImageProcessor::recombine(&low, &high).unwrap();
+    crate::testing::assert_images_eq(&merged, &DynamicImage::ImageRgba8(img), 1);
+
+    let smaller = DynamicImage::new_rgba8(31, 24);
+    assert_eq!(operation_error(ImageProcessor::recombine(&low, &smaller)).0, "recombine");
+}
+
+/// Reference transpose for the blocked one: one element at a time, in source order
+fn naive_transpose<T: Copy + Default>(src: &[T], width: usize, height: usize) -> Vec<T> {
+    let mut out = vec![T::default(); src.len()];
+    for (i, value) in src.iter().enumerate() {
+        out[(i % width) * height + i / width] = *value;
+    }
+    out
+}
+
+#[test]
+fn blocked_transpose_matches_a_naive_loop() {
+    // Sizes that are not multiples of the tile exercise the partial edge tiles
+    for (width, height) in [(1, 1), (7, 3), (33, 65), (100, 1), (2048, 2048)] {
+        let src: Vec<u32> = (0..(width * height) as u32).collect();
+        assert_eq!(crate::transpose(&src, width, height), naive_transpose(&src, width, height), "{}x{}", width, height);
+    }
+}
+
+#[test]
+#[ignore = "timing benchmark, run with --ignored"]
+fn blocked_transpose_outpaces_a_naive_loop() {
+    let (width, height) = (2048, 2048);
+    let src: Vec<[u8; 4]> = (0..width * height).map(|i| (i as u32).to_le_bytes()).collect();
+    let blocked = best_time(5, || {
+        std::hint::black_box(crate::transpose(&src, width, height));
+    });
+    let simple = best_time(5, || {
+        std::hint::black_box(naive_transpose(&src, width, height));
+    });
+    assert!(blocked * 5 < simple * 4, "blocked {:?} vs naive {:?}", blocked, simple);
+}
+
+#[test]
+fn alpha_mask_round_trips_through_a_cleared_alpha_channel() {
+    let img = RgbaImage::from_fn(16, 12, |x, y| Rgba([x as u8 * 10, y as u8 * 20, 90, (x * 16 + y) as u8]));
+    let mut proc = processor(img.clone());
+    let mask = proc.alpha_mask().unwrap();
+    assert_eq!(mask.color(), image::ColorType::L8);
+    assert_eq!(mask.as_luma8().unwrap().get_pixel(5, 3)[0], 83);
+
+    let mut cleared = img.clone();
+    cleared.pixels_mut().for_each(|p| p[3] = 0);
+    proc.image = DynamicImage::ImageRgba8(cleared);
+    proc.apply_alpha_mask(&mask).unwrap();
+    assert_eq!(proc.image.to_rgba8(), img);
+
+    let wrong_size = DynamicImage::new_luma8(15, 12);
+    assert_eq!(operation_error(proc.apply_alpha_mask(&wrong_size)).0, "apply_alpha_mask");
+}
+
+#[test]
+fn save_png16_keeps_more_than_256_levels_from_a_float_gradient() {
+    let gradient = image::Rgb32FImage::from_fn(2048, 2, |x, _| {
+        let v = x as f32 / 2047.0;
+        image::Rgb([v, v * 0.5, 1.0 - v])
+    });
+    let path = temp_path("png16_gradient.png");
+    let proc = ImageProcessor { image: DynamicImage::ImageRgb32F(gradient), ..processor(RgbaImage::new(1, 1)) };
+    proc.save_png16(&path).unwrap();
+
+    let decoded = image::open(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(decoded.color(), image::ColorType::Rgb16);
+    let levels: std::collections::HashSet<u16> = decoded.as_rgb16().unwrap().pixels().map(|p| p[0]).collect();
+    assert!(levels.len() > 256, "only {} distinct levels", levels.len());
+}
+
+#[test]
+fn grid_and_thirds_lines_land_on_expected_coordinates_only() {
+    let background = Rgba([40, 80, 120, 255]);
+    let img = RgbaImage::from_pixel(30, 21, background);
+
+    let mut proc = processor(img.clone());
+    proc.draw_grid(10, Rgba([255, 255, 255, 255])).unwrap();
+    let grid = proc.image.to_rgba8();
+    for (x, y, pixel) in grid.enumerate_pixels() {
+        let on_line = x % 10 == 0 || y % 10 == 0;
+        let expected = if on_line { Rgba([255, 255, 255, 255]) } else { background };
+        assert_eq!(*pixel, expected, "({}, {})", x, y);
+    }
+
+    // Half-transparent lines blend rather than replace
+    let mut proc = processor(img.clone());
+    proc.draw_grid(10, Rgba([240, 80, 120, 128])).unwrap();
+    let blended = proc.image.to_rgba8();
+    assert!((138..=142).contains(&blended.get_pixel(10, 5)[0]));
+    assert_eq!(*blended.get_pixel(5, 5), background);
+    assert_eq!(operation_error(processor(img.clone()).draw_grid(0, background)).0, "draw_grid");
+
+    let mut proc = processor(img.clone());
+    proc.draw_thirds(Rgba([0, 0, 0, 255])).unwrap();
+    let thirds = proc.image.to_rgba8();
+    for (x, y, pixel) in thirds.enumerate_pixels() {
+        let on_line = x == 10 || x == 20 || y == 7 || y == 14;
+        assert_eq!(*pixel == Rgba([0, 0, 0, 255]), on_line, "({}, {})", x, y);
+        if !on_line {
+            assert_eq!(*pixel, background);
+        }
+    }
+}
+
+#[test]
+fn correct_vignette_undoes_a_matching_vignette() {
+    let img = RgbaImage::from_fn(40, 30, |x, y| Rgba([60 + x as u8 * 3, 90 + y as u8 * 2, 150, 255]));
+    let mut proc = processor(img.clone());
+    proc.vignette(0.5).unwrap();
+    let darkened = proc.image.to_rgba8();
+    assert!(darkened.get_pixel(0, 0)[0] < img.get_pixel(0, 0)[0] * 2 / 3);
+
+    proc.correct_vignette(0.5).unwrap();
+    // Rounding in the darkened image is amplified by at most 1 / gain = 2 at the corners
+    crate::testing::assert_images_eq(&proc.image, &DynamicImage::ImageRgba8(img.clone()), 2);
+    let center = proc.image.to_rgba8().get_pixel(20, 15).0;
+    assert_eq!(center, img.get_pixel(20, 15).0);
+
+    for strength in [-0.1, 1.0] {
+        assert_eq!(operation_error(processor(img.clone()).correct_vignette(strength)).0, "correct_vignette");
+    }
+}
+
+#[test]
+fn resize_leaves_no_dark_halo_around_transparent_edges() {
+    // White opaque disc on transparent black, so straight-alpha filtering would mix in black
+    let img = RgbaImage::from_fn(64, 64, |x, y| {
+        let (dx, dy) = (x as f32 - 31.5, y as f32 - 31.5);
+        if dx * dx + dy * dy < 20.0 * 20.0 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 0]) }
+    });
+    for filter in [ResizeFilter::Triangle, ResizeFilter::CatmullRom, ResizeFilter::Lanczos3] {
+        let mut proc = processor(img.clone());
+        proc.resize(16, 16, filter).unwrap();
+        let small = proc.image.to_rgba8();
+        let edges: Vec<_> = small.pixels().filter(|p| p[3] > 0 && p[3] < 255).collect();
+        assert!(!edges.is_empty(), "{:?} produced no partially transparent edge", filter);
+        for pixel in small.pixels().filter(|p| p[3] > 0) {
+            assert!(pixel[0] >= 254 && pixel[1] >= 254 && pixel[2] >= 254, "{:?} halo pixel {:?}", filter, pixel);
+        }
+    }
+}
+
+#[test]
+fn sample_line_rises_monotonically_across_a_gradient() {
+    let img = RgbaImage::from_fn(64, 8, |x, _| Rgba([x as u8 * 4, 255 - x as u8 * 4, 0, 255]));
+    let proc = processor(img);
+    let profile = proc.sample_line((0.0, 4.0), (63.0, 4.0), 20);
+    assert_eq!(profile.len(), 20);
+    assert_eq!(profile[0][0], 0);
+    assert_eq!(profile[19][0], 252);
+    for pair in profile.windows(2) {
+        assert!(pair[1][0] > pair[0][0], "red fell from {:?} to {:?}", pair[0], pair[1]);
+        assert!(pair[1][1] < pair[0][1], "green rose from {:?} to {:?}", pair[0], pair[1]);
+    }
+
+    // Between pixel centers the value is interpolated, not snapped
+    assert_eq!(proc.sample_line((10.5, 2.0), (10.5, 2.0), 1), vec![Rgba([42, 213, 0, 255])]);
+    // Endpoints beyond the image clamp to the edge
+    assert_eq!(proc.sample_line((-5.0, 4.0), (70.0, 4.0), 2)[1][0], 252);
+}
+
+#[test]
+fn nearest_rotation_keeps_only_the_original_colors() {
+    let palette = [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]), Rgba([0, 0, 0, 0])];
+    let img = RgbaImage::from_fn(32, 32, |x, y| palette[((x / 4 + y / 4) % 2) as usize]);
+    let mut nearest = processor(img.clone());
+    nearest.rotate_with(30.0, RotateInterpolation::Nearest).unwrap();
+    let rotated = nearest.image.to_rgba8();
+    assert!(rotated.pixels().all(|p| palette.contains(p)));
+    assert!(rotated.pixels().any(|p| *p == palette[0]) && rotated.pixels().any(|p| *p == palette[1]));
+
+    let mut bilinear = processor(img);
+    bilinear.rotate_with(30.0, RotateInterpolation::Bilinear).unwrap();
+    assert!(bilinear.image.to_rgba8().pixels().any(|p| !palette.contains(p)));
+}
+
+#[test]
+fn rotate_with_fill_paints_exposed_corners() {
+    let img = RgbaImage::from_pixel(40, 30, Rgba([30, 90, 150, 255]));
+    let white = Rgba([255, 255, 255, 255]);
+    let mut filled = processor(img.clone());
+    filled.rotate_with_fill(45.0, white).unwrap();
+    let rotated = filled.image.to_rgba8();
+    for (x, y) in [(0, 0), (39, 0), (0, 29), (39, 29)] {
+        assert_eq!(*rotated.get_pixel(x, y), white, "corner ({}, {})", x, y);
+    }
+    assert_eq!(*rotated.get_pixel(20, 15), Rgba([30, 90, 150, 255]));
+    assert!(rotated.pixels().all(|p| p[3] == 255));
+
+    let mut plain = processor(img);
+    plain.rotate(45.0).unwrap();
+    assert_eq!(*plain.image.to_rgba8().get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+}
+
+#[test]
+fn rotate_hue_matrix_tracks_hsl_hue_rotation() {
+    // Muted colors, where the luminance-preserving matrix and HSL rotation agree closely
+    let img = RgbaImage::from_fn(64, 64, |x, y| Rgba([110 + x as u8, 120 + y as u8, 140 - (x / 2) as u8, 255]));
+    for degrees in [30.0, 90.0, 180.0, 270.0] {
+        let mut matrix = processor(img.clone());
+        matrix.rotate_hue_matrix(degrees).unwrap();
+        let mut hsl = processor(img.clone());
+        hsl.adjust_hue(degrees).unwrap();
+        let (a, b) = (matrix.image.to_rgba8(), hsl.image.to_rgba8());
+        let mean_diff = a.as_raw().iter().zip(b.as_raw()).map(|(&p, &q)| p.abs_diff(q) as f64).sum::<f64>()
+            / a.as_raw().len() as f64;
+        assert!(mean_diff < 6.0, "{} degrees: mean difference {}", degrees, mean_diff);
+    }
+    let mut identity = processor(img.clone());
+    identity.rotate_hue_matrix(360.0).unwrap();
+    crate::testing::assert_images_eq(&identity.image, &DynamicImage::ImageRgba8(img.clone()), 1);
+}
+
+#[test]
+#[ignore = "timing benchmark, run with --ignored"]
+fn rotate_hue_matrix_outpaces_hsl_hue_rotation() {
+    let large = gradient(512, 512);
+    let matrix_time = best_time(5, || {
+        processor(large.clone()).rotate_hue_matrix(120.0).unwrap();
+    });
+    let hsl_time = best_time(5, || {
+        processor(large.clone()).adjust_hue(120.0).unwrap();
+    });
+    assert!(matrix_time * 5 < hsl_time * 4, "matrix {:?} vs HSL {:?}", matrix_time, hsl_time);
+}
+
+#[test]
+fn soft_clip_highlights_leaves_the_knee_and_rolls_off_above_it() {
+    let img = RgbaImage::from_fn(256, 1, |x, _| Rgba([x as u8, x as u8, x as u8, 255]));
+    let mut proc = processor(img);
+    proc.soft_clip_highlights(0.75).unwrap();
+    let curve: Vec<u8> = proc.image.to_rgba8().pixels().map(|p| p[0]).collect();
+
+    let knee = (0.75f32 * 255.0) as usize;
+    assert!((0..=knee).all(|v| curve[v] as usize == v));
+    // Still rising all the way up, so nothing is clipped flat
+    assert!(curve[knee..].windows(2).all(|pair| pair[1] >= pair[0]));
+    assert!(curve[knee..].iter().zip(knee..).all(|(&out, v)| out as usize <= v));
+    assert!(curve[255] < 250 && curve[255] > 230, "white maps to {}", curve[255]);
+    let plateau = curve[knee..].iter().filter(|&&v| v == curve[255]).count();
+    assert!(plateau <= 6, "{} inputs collapse onto the top value", plateau);
+
+    for knee in [-0.1, 1.0] {
+        assert_eq!(operation_error(processor(RgbaImage::new(1, 1)).soft_clip_highlights(knee)).0, "soft_clip_highlights");
+    }
+}
+
+#[test]
+fn perceptual_hash_survives_jpeg_but_separates_unrelated_images() {
+    let original = RgbaImage::from_fn(96, 64, |x, y| {
+        let (dx, dy) = (x as f32 - 30.0, y as f32 - 34.0);
+        let disc = if dx * dx + dy * dy < 400.0 { 120 } else { 0 };
+        Rgba([(x * 2) as u8 + disc, (y * 3) as u8, 200 - disc, 255])
+    });
+    let mut encoded = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, 70)
+        .encode_image(&DynamicImage::ImageRgba8(original.clone()).to_rgb8())
+        .unwrap();
+    let recompressed = image::load_from_memory(&encoded).unwrap().to_rgba8();
+    assert_ne!(recompressed, original);
+
+    let hash = processor(original).perceptual_hash();
+    let near = crate::hamming_distance(hash, processor(recompressed).perceptual_hash());
+    let unrelated = crate::hamming_distance(hash, processor(checkerboard(96, 64, 6)).perceptual_hash());
+    assert!(near <= 4, "re-encode moved {} bits", near);
+    assert!(unrelated >= 20, "unrelated image only {} bits away", unrelated);
+    assert_eq!(crate::hamming_distance(hash, hash), 0);
+    // Bit 0 is the DC term; of the 63 distinct AC terms exactly 31 lie above their median
+    assert_eq!((hash >> 1).count_ones(), 31);
+}
+
+#[test]
+fn crop_to_alpha_trims_transparent_padding() {
+    let mut img = RgbaImage::new(20, 16);
+    for (x, y) in [(5, 3), (12, 9), (8, 6)] {
+        img.put_pixel(x, y, Rgba([200, 40, 40, 255]));
+    }
+    // Faint fringe below the threshold doesn't widen the box
+    img.put_pixel(1, 14, Rgba([200, 40, 40, 10]));
+    let mut proc = processor(img.clone());
+    proc.crop_to_alpha(16).unwrap();
+    let trimmed = proc.image.to_rgba8();
+    assert_eq!(trimmed.dimensions(), (8, 7));
+    assert_eq!(*trimmed.get_pixel(0, 0), Rgba([200, 40, 40, 255]));
+    assert_eq!(*trimmed.get_pixel(7, 6), Rgba([200, 40, 40, 255]));
+    assert_eq!(
+        proc.operation_log().operations(),
+        [Operation::Crop { x: 5, y: 3, width: 8, height: 7 }]
+    );
+
+    assert_eq!(operation_error(processor(RgbaImage::new(4, 4)).crop_to_alpha(0)).0, "crop_to_alpha");
+    let mut opaque = processor(img);
+    opaque.image = DynamicImage::ImageRgb8(opaque.image.to_rgb8());
+    assert!(matches!(opaque.crop_to_alpha(0), Err(ImageError::UnsupportedColorType { .. })));
+}
+
+#[test]
+fn in_place_ops_on_rgba8_match_converted_results_without_reallocating() {
+    let img = gradient(256, 256);
+    let rgb = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img.clone()).to_rgb8());
+
+    let mut converted = ImageProcessor::from_dynamic_image(rgb);
+    converted.invert().unwrap().adjust_brightness(1.2).unwrap().adjust_contrast(0.8).unwrap();
+    let mut in_place = processor(img);
+    let buffer = in_place.get_image().as_rgba8().unwrap().as_ptr();
+
+    let (result, peak, _) = measure_allocations(|| {
+        for _ in 0..5 {
+            in_place.invert()?.invert()?;
+        }
+        in_place.invert()?.adjust_brightness(1.2)?.adjust_contrast(0.8).map(|_| ())
+    });
+    result.unwrap();
+    assert_eq!(in_place.get_image(), converted.get_image());
+    // Same buffer throughout, and nothing close to another 256 KB copy was allocated
+    assert_eq!(in_place.get_image().as_rgba8().unwrap().as_ptr(), buffer);
+    assert!(peak < 16 * 1024, "in-place chain allocated {} bytes", peak);
+}
+
+#[test]
+fn draw_text_fit_fills_a_wide_box_and_stays_inside_it() {
+    let font = test_font();
+    let background = Rgba([255, 255, 255, 255]);
+    let (bx, by, bw, bh) = (10, 20, 200, 90);
+    let mut proc = processor(RgbaImage::from_pixel(220, 130, background));
+    proc.draw_text_fit("Hello", (bx, by, bw, bh), &font, Rgba([0, 0, 0, 255])).unwrap();
+    let img = proc.image.to_rgba8();
+
+    let ink: Vec<(u32, u32)> = img.enumerate_pixels().filter(|(_, _, p)| p[0] < 128).map(|(x, y, _)| (x, y)).collect();
+    let min_x = ink.iter().map(|p| p.0).min().unwrap();
+    let max_x = ink.iter().map(|p| p.0).max().unwrap();
+    assert!(max_x - min_x + 1 >= bw * 85 / 100, "glyphs span {}..={} of a {} wide box", min_x, max_x, bw);
+    assert!(img.enumerate_pixels().all(|(x, y, p)| {
+        *p == background || ((bx..bx + bw).contains(&x) && (by..by + bh).contains(&y))
+    }));
+
+    for rect in [(u32::MAX, 0, 2, 2), (0, 4, 2, u32::MAX), (30, 0, 200, 10)] {
+        assert_eq!(operation_error(proc.draw_text_fit("Hi", rect, &font, background)).0, "draw_text_fit");
+    }
+}
+
+#[test]
+fn blurhash_placeholder_keeps_the_average_color() {
+    let img = RgbaImage::from_fn(48, 32, |x, y| Rgba([200 - x as u8 * 2, 60 + y as u8 * 3, 110, 255]));
+    let hash = processor(img.clone()).blurhash(4, 3);
+    assert_eq!(hash.len(), 4 + 2 * 4 * 3);
+
+    let placeholder = ImageProcessor::from_blurhash(&hash, 24, 16).unwrap();
+    assert_eq!(placeholder.dimensions(), (24, 16));
+    let (original, decoded) = (channel_means(&img), channel_means(&placeholder.image.to_rgba8()));
+    for c in 0..3 {
+        assert!((original[c] - decoded[c]).abs() < 6.0, "channel {}: {} vs {}", c, original[c], decoded[c]);
+    }
+
+    for (width, height) in [(0, 0), (0, 16), (24, 0)] {
+        assert_eq!(operation_error(ImageProcessor::from_blurhash(&hash, width, height)).0, "from_blurhash");
+    }
+    assert_eq!(operation_error(ImageProcessor::from_blurhash(&hash[..hash.len() - 2], 8, 8)).0, "from_blurhash");
+}
+
+#[test]
+fn adjust_gamma_rgb_moves_only_the_raised_channel() {
+    let img = RgbaImage::from_fn(16, 16, |x, y| {
+        let v = (x * 16 + y) as u8;
+        Rgba([v, 255 - v, 128, 255])
+    });
+    let mut proc = processor(img.clone());
+    proc.adjust_gamma_rgb(1.0, 1.0, 2.0).unwrap();
+    let adjusted = proc.image.to_rgba8();
+    for (before, after) in img.pixels().zip(adjusted.pixels()) {
+        assert_eq!((before[0], before[1], before[3]), (after[0], after[1], after[3]));
+    }
+    // 255 * (128 / 255)^(1 / 2)
+    assert_eq!(adjusted.get_pixel(0, 0)[2], 181);
+
+    let mut identity = processor(img.clone());
+    identity.adjust_gamma_rgb(1.0, 1.0, 1.0).unwrap();
+    assert_eq!(identity.image.to_rgba8(), img);
+
+    for gammas in [(0.0, 1.0, 1.0), (1.0, -2.0, 1.0), (1.0, 1.0, f32::NAN)] {
+        let result = processor(img.clone()).adjust_gamma_rgb(gammas.0, gammas.1, gammas.2).map(|_| ());
+        assert_eq!(operation_error(result).0, "adjust_gamma_rgb");
+    }
+}
+
+#[test]
+fn stacking_noisy_exposures_reduces_variance_and_rejects_trails() {
+    let clean = gradient(32, 32);
+    // Deterministic per-exposure noise of up to +/-20 around the clean image
+    let exposures: Vec<DynamicImage> = (0..7u32)
+        .map(|seed| {
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, y| {
+                let mut p = *clean.get_pixel(x, y);
+                let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ (seed + 1).wrapping_mul(83_492_791))
+                    .wrapping_mul(2_654_435_761);
+                for c in 0..3 {
+                    let noise = ((hash >> (8 * c)) & 0xff) as i32 * 40 / 255 - 20;
+                    p[c] = (p[c] as i32 + noise).clamp(0, 255) as u8;
+                }
+                p
+            }))
+        })
+        .collect();
+    let error_variance = |img: &DynamicImage| {
+        let img = img.to_rgba8();
+        img.as_raw().iter().zip(clean.as_raw()).map(|(&a, &b)| (a as f64 - b as f64).powi(2)).sum::<f64>()
+            / img.as_raw().len() as f64
+    };
+    let single = exposures.iter().map(error_variance).fold(f64::INFINITY, f64::min);
+    let averaged = error_variance(&ImageProcessor::stack_average(&exposures).unwrap());
+    let median = error_variance(&ImageProcessor::stack_median(&exposures).unwrap());
+    assert!(averaged < single / 3.0, "average {} vs best single {}", averaged, single);
+    assert!(median < single / 2.0, "median {} vs best single {}", median, single);
+
+    // A bright trail through two of the seven exposures survives averaging but not the median
+    let mut trailed = exposures.clone();
+    for img in &mut trailed[..2] {
+        let mut rgba = img.to_rgba8();
+        (0..32).for_each(|x| rgba.put_pixel(x, 10, Rgba([255, 255, 255, 255])));
+        *img = DynamicImage::ImageRgba8(rgba);
+    }
+    let trail_level = |img: DynamicImage| {
+        let img = img.to_rgba8();
+        (0..32).map(|x| img.get_pixel(x, 10)[2].abs_diff(clean.get_pixel(x, 10)[2]) as u32).max().unwrap()
+    };
+    assert!(trail_level(ImageProcessor::stack_average(&trailed).unwrap()) > 40);
+    assert!(trail_level(ImageProcessor::stack_median(&trailed).unwrap()) <= 20);
+
+    let mismatched = [exposures[0].clone(), DynamicImage::new_rgba8(31, 32)];
+    assert_eq!(operation_error(ImageProcessor::stack_average(&mismatched)).0, "stack_average");
+    assert_eq!(operation_error(ImageProcessor::stack_median(&[])).0, "stack_median");
+}
+
+#[test]
+fn kaleidoscope_with_four_segments_is_symmetric_under_quarter_turns() {
+    let source = gradient(33, 33);
+    let quarter_turn = |img: &RgbaImage| DynamicImage::ImageRgba8(image::imageops::rotate90(img));
+    assert_ne!(quarter_turn(&source).to_rgba8(), source);
+
+    let mut proc = processor(source.clone());
+    proc.kaleidoscope(4).unwrap();
+    let mirrored = proc.image.to_rgba8();
+    assert_eq!(mirrored.dimensions(), (33, 33));
+    crate::testing::assert_images_eq(&quarter_turn(&mirrored), &proc.image, 2);
+    // The source wedge itself, just right of the center along the x axis, is kept
+    assert_eq!(*mirrored.get_pixel(30, 16), *source.get_pixel(30, 16));
+
+    assert_eq!(operation_error(processor(source).kaleidoscope(0)).0, "kaleidoscope");
+}
+
+#[test]
+fn detect_background_picks_the_border_color() {
+    let border = Rgba([236, 238, 230, 255]);
+    let mut img = RgbaImage::from_fn(40, 30, |x, y| {
+        // Slight JPEG-like noise along the border still lands in one bucket
+        Rgba([border[0] - ((x + y) % 3) as u8, border[1], border[2], 255])
+    });
+    for y in 3..27 {
+        for x in 3..37 {
+            img.put_pixel(x, y, Rgba([20, 60, 180, 255]));
+        }
+    }
+    // A subject touching one edge doesn't outvote the rest of the border
+    for x in 10..20 {
+        img.put_pixel(x, 0, Rgba([20, 60, 180, 255]));
+    }
+    let detected = processor(img).detect_background();
+    assert!(detected.0.iter().zip(border.0).all(|(&a, b)| a.abs_diff(b) <= 2), "{:?}", detected);
+    assert_eq!(processor(RgbaImage::new(0, 0)).detect_background(), Rgba([0, 0, 0, 0]));
+}
+
+#[test]
+fn shadows_highlights_lifts_darks_recovers_brights_and_keeps_mid_gray() {
+    let dark = Rgba([30, 20, 15, 255]);
+    let bright = Rgba([250, 235, 220, 255]);
+    let gray = Rgba([128, 128, 128, 255]);
+    let img = RgbaImage::from_fn(3, 1, |x, _| [dark, bright, gray][x as usize]);
+    let mut proc = processor(img);
+    proc.shadows_highlights(0.5, 0.5).unwrap();
+    let out = proc.image.to_rgba8();
+
+    let (lifted, recovered, mid) = (out.get_pixel(0, 0), out.get_pixel(1, 0), out.get_pixel(2, 0));
+    assert!((0..3).all(|c| lifted[c] > dark[c]), "{:?}", lifted);
+    assert!((0..3).all(|c| recovered[c] < bright[c]), "{:?}", recovered);
+    assert!((0..3).all(|c| mid[c].abs_diff(128) <= 1), "{:?}", mid);
+    // Channels are scaled together, so the dark pixel keeps its warm cast
+    assert!(lifted[0] > lifted[1] && lifted[1] > lifted[2]);
+
+    assert_eq!(operation_error(processor(RgbaImage::new(1, 1)).shadows_highlights(1.5, 0.0)).0, "shadows_highlights");
+}
+
+#[test]
+fn alpha_operations_on_a_loaded_luma8_image_report_the_color_type() {
+    let path = temp_path("luma8_alpha_ops.png");
+    image::GrayImage::from_fn(6, 4, |x, y| Luma([(x * 40 + y) as u8])).save(&path).unwrap();
+    let mut proc = ImageProcessor::new(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(proc.get_image().color(), ColorType::L8);
+    let before = proc.get_image().clone();
+
+    assert!(matches!(
+        proc.alpha_mask(),
+        Err(ImageError::UnsupportedColorType { ref op, color_type: ColorType::L8 }) if op == "alpha_mask"
+    ));
+    let mask = DynamicImage::new_luma8(6, 4);
+    assert!(matches!(
+        proc.apply_alpha_mask(&mask),
+        Err(ImageError::UnsupportedColorType { ref op, color_type: ColorType::L8 }) if op == "apply_alpha_mask"
+    ));
+    assert!(matches!(proc.crop_to_alpha(0), Err(ImageError::UnsupportedColorType { color_type: ColorType::L8, .. })));
+    // Nothing was converted behind the caller's back
+    assert_eq!(proc.get_image(), &before);
+
+    proc.convert_to(ColorType::La8).unwrap();
+    assert!(proc.apply_alpha_mask(&mask).is_ok());
+    assert!(proc.alpha_mask().unwrap().to_luma8().pixels().all(|p| p[0] == 0));
+}